@@ -42,12 +42,15 @@ public class PlayerController : MonoBehaviour
         {
             var s1 = spell1.GetComponent<StackMachine>();
             s1.program = spells[mySpells[0]];
+            s1.bytecode = compiledSpells.GetValueOrDefault(mySpells[0]);
             s1.Recompile();
             var s2 = spell2.GetComponent<StackMachine>();
             s2.program = spells[mySpells[1]];
+            s2.bytecode = compiledSpells.GetValueOrDefault(mySpells[1]);
             s2.Recompile();
             var s3 = spell3.GetComponent<StackMachine>();
             s3.program = spells[mySpells[2]];
+            s3.bytecode = compiledSpells.GetValueOrDefault(mySpells[2]);
             s3.Recompile();
         }catch(Exception e)
         {
@@ -9,6 +9,79 @@ public ref struct CompileResult {
     public string error;
     public long error_start;
     public long error_end;
+    public IntPtr diagnostics;
+}
+
+[StructLayout(LayoutKind.Sequential)]
+public struct DiagnosticInfo {
+    public IntPtr message;
+    public long start;
+    public long end;
+    public long line;
+    public long column;
+    // 0 for errors, 1 for warnings
+    public long severity;
+}
+
+[StructLayout(LayoutKind.Sequential)]
+public struct HeapUsage {
+    // each object on the heap counts as a cell, as does each value in one
+    public long cells;
+    public long objects;
+    public long max_cells;
+    public long max_objects;
+}
+
+public enum DebugStep {
+    Into = 0,
+    Over = 1,
+    Out = 2
+}
+
+[StructLayout(LayoutKind.Sequential)]
+public struct VariableInfo {
+    public IntPtr name;
+    public IntPtr tpe;
+    public IntPtr value;
+}
+
+public enum TokenKind {
+    Keyword = 0,
+    Identifier = 1,
+    Type = 2,
+    Function = 3,
+    Field = 4,
+    Literal = 5,
+    Operator = 6,
+    Comment = 7,
+    Error = 8
+}
+
+[StructLayout(LayoutKind.Sequential)]
+public struct SemanticToken {
+    public long kind;
+    // byte offsets into the UTF-8 program, end is exclusive
+    public long start;
+    public long end;
+
+    public TokenKind Kind => (TokenKind) kind;
+}
+
+[StructLayout(LayoutKind.Sequential)]
+public struct SourceLocation {
+    // byte offsets into the UTF-8 program, end is exclusive
+    public long start;
+    public long end;
+    public IntPtr function;
+}
+
+[StructLayout(LayoutKind.Sequential)]
+public struct FrameInfo {
+    public long pc;
+    // byte offsets into the UTF-8 program, both -1 outside the spell's code
+    public long start;
+    public long end;
+    public IntPtr function;
 }
 
 //[StructLayout(LayoutKind.Sequential)]
@@ -35,6 +108,15 @@ public static class Compiler {
             out CompileResult res
     );
 
+    [DllImport(dllName)]
+    public static extern long compileresult_diagnostic_count(ref CompileResult res);
+
+    [DllImport(dllName)]
+    public static extern bool compileresult_get_diagnostic(ref CompileResult res, long index, out DiagnosticInfo info);
+
+    [DllImport(dllName)]
+    public static extern void free_compileresult(ref CompileResult res);
+
     [DllImport(dllName)]
     public static extern int run_to_syscall_or_n(long id, int max_instructions, ref int executed);
 
@@ -57,6 +139,168 @@ public static class Compiler {
     private static extern bool pop_int_array(long id, out /*ref?*/ IntPtr data, out ulong len);
 
 
+    [DllImport(dllName)]
+    private static extern IntPtr format_program(
+            [MarshalAs(UnmanagedType.LPStr)]
+            string program
+    );
+
+    [DllImport(dllName)]
+    private static extern void free_formatted(IntPtr formatted);
+
+    // the formatted spell, or null if it doesn't parse
+    public static string Format(string program) {
+        IntPtr formatted = format_program(program);
+        if (formatted == IntPtr.Zero) {
+            return null;
+        }
+        string output = Marshal.PtrToStringUTF8(formatted);
+        free_formatted(formatted);
+        return output;
+    }
+
+    [DllImport(dllName)]
+    private static extern void semantic_tokens(
+            [MarshalAs(UnmanagedType.LPStr)]
+            string program,
+            out IntPtr tokens,
+            out ulong length
+    );
+
+    [DllImport(dllName)]
+    private static extern void free_semantic_tokens(IntPtr tokens, ulong length);
+
+    // the tokens to highlight, in order, without punctuation
+    public static SemanticToken[] SemanticTokens(string program) {
+        semantic_tokens(program, out IntPtr tokens, out ulong length);
+        SemanticToken[] output = new SemanticToken[(int) length];
+        int size = Marshal.SizeOf<SemanticToken>();
+        for (int i = 0; i < output.Length; i++) {
+            output[i] = Marshal.PtrToStructure<SemanticToken>(tokens + i * size);
+        }
+        free_semantic_tokens(tokens, length);
+        return output;
+    }
+
+    [DllImport(dllName)]
+    private static extern bool save_bytecode(long id, out IntPtr data, out ulong length);
+
+    [DllImport(dllName)]
+    private static extern void free_bytecode(IntPtr data, ulong length);
+
+    [DllImport(dllName)]
+    private static extern long load_bytecode(IntPtr data, ulong length);
+
+    [DllImport(dllName)]
+    public static extern bool free_vm(long id);
+
+    // The compiled program of a VM, to be loaded again with LoadBytecode, or
+    // null if there's no such VM
+    public static byte[] SaveBytecode(long id) {
+        bool found = save_bytecode(id, out IntPtr data, out ulong length);
+        byte[] output = new byte[(int) length];
+        Marshal.Copy(data, output, 0, output.Length);
+        free_bytecode(data, length);
+        return found ? output : null;
+    }
+
+    // Spawns a VM running a program from SaveBytecode, returning its ID, or a
+    // negative number if the bytes can't be loaded.  Free it with free_vm.
+    public static long LoadBytecode(byte[] bytecode) {
+        unsafe {
+            fixed (byte* ptr = bytecode) {
+                return load_bytecode((IntPtr) ptr, (ulong) bytecode.Length);
+            }
+        }
+    }
+
+    [DllImport(dllName)]
+    private static extern bool source_location(long id, out SourceLocation location);
+
+    [DllImport(dllName)]
+    private static extern void free_source_location(ref SourceLocation location);
+
+    // Where in the spell a VM is, such as the statement that failed after
+    // run_to_syscall_or_n returns an error, as the byte range and the name of
+    // the function it's in ("" for the top level).  Null if it isn't known.
+    public static (long start, long end, string function)? GetSourceLocation(long id) {
+        if (!source_location(id, out SourceLocation location)) {
+            return null;
+        }
+        string function = Marshal.PtrToStringUTF8(location.function);
+        free_source_location(ref location);
+        return (location.start, location.end, function);
+    }
+
+    [DllImport(dllName)]
+    private static extern bool stack_trace(long id, out IntPtr frames, out ulong length);
+
+    [DllImport(dllName)]
+    private static extern void free_stack_trace(IntPtr frames, ulong length);
+
+    // The function calls in progress in a VM, innermost first, such as after
+    // run_to_syscall_or_n returns an error.  The function is "" for the top
+    // level, and start and end are -1 outside the spell's code.
+    public static (long pc, long start, long end, string function)[] StackTrace(long id) {
+        stack_trace(id, out IntPtr frames, out ulong length);
+        var output = new (long pc, long start, long end, string function)[(int) length];
+        int size = Marshal.SizeOf<FrameInfo>();
+        for (int i = 0; i < output.Length; i++) {
+            FrameInfo frame = Marshal.PtrToStructure<FrameInfo>(frames + i * size);
+            output[i] = (frame.pc, frame.start, frame.end, Marshal.PtrToStringUTF8(frame.function));
+        }
+        free_stack_trace(frames, length);
+        return output;
+    }
+
+    [DllImport(dllName)]
+    public static extern bool set_breakpoint(long id, long line);
+
+    [DllImport(dllName)]
+    public static extern bool clear_breakpoint(long id, long line);
+
+    [DllImport(dllName)]
+    private static extern bool debug_step(long id, int step);
+
+    // Makes run_to_syscall_or_n return -12 once the VM has run a step of the
+    // spell, rather than carrying on to a breakpoint
+    public static bool Step(long id, DebugStep step) {
+        return debug_step(id, (int) step);
+    }
+
+    // The line of the spell a VM is on, counting from 1, or -1 if it isn't known
+    [DllImport(dllName)]
+    public static extern long current_line(long id);
+
+    [DllImport(dllName)]
+    private static extern bool variables(long id, long frame, out IntPtr variables, out ulong length);
+
+    [DllImport(dllName)]
+    private static extern void free_variables(IntPtr variables, ulong length);
+
+    // The variables in scope in a frame of a paused VM's stack trace, 0 being
+    // the innermost, with their values written out as they would be in a spell
+    public static (string name, string type, string value)[] Variables(long id, long frame) {
+        variables(id, frame, out IntPtr items, out ulong length);
+        var output = new (string name, string type, string value)[(int) length];
+        int size = Marshal.SizeOf<VariableInfo>();
+        for (int i = 0; i < output.Length; i++) {
+            VariableInfo variable = Marshal.PtrToStructure<VariableInfo>(items + i * size);
+            output[i] = (Marshal.PtrToStringUTF8(variable.name), Marshal.PtrToStringUTF8(variable.tpe), Marshal.PtrToStringUTF8(variable.value));
+        }
+        free_variables(items, length);
+        return output;
+    }
+
+    // How much of its heap a VM is using, and its limits
+    [DllImport(dllName)]
+    public static extern bool heap_usage(long id, out HeapUsage usage);
+
+    // Allocating past the limits makes run_to_syscall_or_n return -7 once
+    // collecting the garbage can't make room
+    [DllImport(dllName)]
+    public static extern bool set_heap_limit(long id, long max_cells, long max_objects);
+
     public static void PushIntArray(long id, int[] items) {
         unsafe {
             fixed (int* ptr = items) {
@@ -5,6 +5,9 @@ using UnityEngine.SceneManagement;
 public class SpellSelectScript : MonoBehaviour
 {
     public static Dictionary<string, string> spells;
+    // the bytecode of every spell in the spellbook that compiles, so they don't
+    // have to be compiled again when a game starts
+    public static Dictionary<string, byte[]> compiledSpells = new();
     private readonly static string fireBallProgram =
         "while true {     var pos = get_click();     print('A');     var effect = spawn_effect(0);     if effect != -1 {         move_effect(pos[0], pos[1], effect);    } }";
     private readonly static string lightningProgram =
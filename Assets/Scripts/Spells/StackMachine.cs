@@ -11,6 +11,8 @@ public class StackMachine: MonoBehaviour {
     public SysCallManager manager;
     public bool halted = false;
     public string program;
+    // the program already compiled, loaded instead of compiling it if set
+    public byte[] bytecode;
     public LeyLineGen leyLineMap;
 
     public void Start()
@@ -24,6 +26,12 @@ public class StackMachine: MonoBehaviour {
     }
     public void Recompile()
     {
+        if (bytecode != null)
+        {
+            id = Compiler.LoadBytecode(bytecode);
+            if (id >= 0) return;
+            Debug.Log($"couldn't load the compiled spell ({id}), compiling it again");
+        }
         Compiler.compile(program, out CompileResult res);
         id = res.id;
         Debug.Log(res.error);
@@ -41,7 +49,17 @@ public class StackMachine: MonoBehaviour {
         {
             int syscall = Compiler.run_to_syscall_or_n(id, total_allowance - num_executed, ref num_executed);
             if (syscall != 7) Debug.Log($"syscall = {syscall}");
-            if (syscall < 0) return;
+            if (syscall == -12)
+            {
+                // paused by the debugger, the next turn carries on from here
+                Debug.Log($"spell paused at line {Compiler.current_line(id)}");
+                return;
+            }
+            if (syscall < 0)
+            {
+                LogFailure(syscall);
+                return;
+            }
 
             SyscallResult res = await SyscallHandler(syscall);
             switch (res) {
@@ -64,6 +82,30 @@ public class StackMachine: MonoBehaviour {
         }
     }
 
+    // Says where the spell stopped and how it got there, unless it just halted
+    private void LogFailure(int code)
+    {
+        if (code == -4) return;
+        byte[] bytes = System.Text.Encoding.UTF8.GetBytes(program);
+        var message = new System.Text.StringBuilder($"spell failed with {code}");
+        var trace = Compiler.StackTrace(id);
+        for (int i = 0; i < trace.Length; i++)
+        {
+            var (pc, start, end, function) = trace[i];
+            string where = function == "" ? "the top level" : function;
+            if (start < 0)
+            {
+                where += $", instruction {pc}";
+            }
+            else
+            {
+                where += $": {System.Text.Encoding.UTF8.GetString(bytes, (int)start, (int)(end - start))}";
+            }
+            message.Append($"\n    {(i == 0 ? "in" : "called from")} {where}");
+        }
+        Debug.Log(message.ToString());
+    }
+
     public async Task<SyscallResult> SyscallHandler(int code)
     {
         switch (code)
@@ -23,14 +23,32 @@ public class SaveHandler : MonoBehaviour
         {
             spells.Add(spellName.text, spellText.text);
         }
-        
+
+        byte[] bytecode = Compile();
+        if (bytecode != null)
+        {
+            compiledSpells[spellName.text] = bytecode;
+        }
+        else
+        {
+            compiledSpells.Remove(spellName.text);
+        }
     }
     
     public void OnCompile()
+    {
+        Compile();
+    }
+
+    // The spell's bytecode, or null if it doesn't compile
+    private byte[] Compile()
     {
         CompileResult res;
         Compiler.compile(spellText.text, out res);
         Debug.Log(res.error);
-        //compiledSpell=res.
+        // this also frees the VM, so the bytecode has to be saved first
+        byte[] bytecode = res.id == -1 ? null : Compiler.SaveBytecode(res.id);
+        Compiler.free_compileresult(ref res);
+        return bytecode;
     }
 }
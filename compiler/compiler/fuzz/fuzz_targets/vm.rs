@@ -0,0 +1,23 @@
+#![no_main]
+#![feature(box_patterns)]
+#![allow(unused)]
+
+// the compiler crate only builds as a cdylib and binaries, so the modules are pulled in directly
+#[path = "../../src/stack_machine.rs"]
+mod stack_machine;
+#[path = "../../src/parser.rs"]
+mod parser;
+#[path = "../../src/compiler.rs"]
+mod compiler;
+#[path = "../../src/diagnostics.rs"]
+mod diagnostics;
+#[path = "../../src/bytecode.rs"]
+mod bytecode;
+#[path = "../../src/world.rs"]
+mod world;
+#[path = "../../src/fuzz.rs"]
+mod fuzz;
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| fuzz::run(data));
@@ -0,0 +1,299 @@
+use std::{collections::{BTreeMap, HashMap}, fmt::{Display, Write}};
+
+use crate::{compiler::SourceMap, diagnostics, stack_machine::{self, Instruction, Tpe}};
+
+// the column the instruction numbers line up at
+const COMMENT_COLUMN: usize = 40;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssemblyError {
+    // 1-based
+    pub line: usize,
+    pub message: String
+}
+
+impl Display for AssemblyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// Where a jump or call goes
+fn target(ins: &Instruction) -> Option<usize> {
+    match ins {
+        Instruction::Brz(n) | Instruction::Brnz(n) | Instruction::Jmp(n) | Instruction::Call(n) => Some(*n),
+        _ => None
+    }
+}
+
+fn type_name(tpe: &Tpe) -> String {
+    match tpe {
+        Tpe::Int => "int".to_owned(),
+        Tpe::Double => "double".to_owned(),
+        Tpe::Array(inner) => format!("{}[]", type_name(inner)),
+        Tpe::Struct(fields) => format!("{{{}}}", fields.iter().map(type_name).collect::<Vec<_>>().join(", "))
+    }
+}
+
+/// Lists a program as assembly that `assemble` reads back, one instruction per line with its
+/// address in a comment.  Jumps and calls go to labels rather than addresses.  Given the source
+/// and its source map, functions are labelled by their signatures, and the line each statement
+/// starts on is shown above the instructions it compiled to
+pub fn disassemble(program: &[Instruction], source: Option<(&str, &SourceMap)>) -> String {
+    let mut labels = BTreeMap::new();
+    if let Some((_, map)) = source {
+        for (address, function) in &map.functions {
+            let args = function.args.iter().map(|x| x.1.to_string()).collect::<Vec<_>>();
+            labels.insert(*address, format!("{}({})", function.name, args.join(",")));
+        }
+    }
+    for address in program.iter().filter_map(target) {
+        labels.entry(address).or_insert_with(String::new);
+    }
+    for (count, label) in labels.values_mut().filter(|x| x.is_empty()).enumerate() {
+        *label = format!("L{count}");
+    }
+
+    let mut out = String::new();
+    let mut last_span = None;
+    for (i, ins) in program.iter().enumerate() {
+        if let Some((_, map)) = source && let Some((_, function)) = map.functions.iter().find(|x| x.0 == i) {
+            writeln!(out, "\n; {function}").unwrap();
+        }
+        if let Some(label) = labels.get(&i) {
+            writeln!(out, "{label}:").unwrap();
+        }
+        if let Some((src, map)) = source {
+            let span = map.span(i);
+            if span != last_span && let Some(span) = &span {
+                let (line, column) = diagnostics::line_col(src, span.start);
+                // the whole line, since a statement's span leaves out its keyword
+                let text = src[..span.start].rfind('\n').map_or(src, |x| &src[x + 1..]);
+                writeln!(out, "    ; {line}:{column}  {}", text.lines().next().unwrap_or_default().trim()).unwrap();
+            }
+            last_span = span;
+        }
+        let text = instruction_text(ins, &labels);
+        writeln!(out, "    {text:<width$}; {i}", width = COMMENT_COLUMN - 4).unwrap();
+    }
+    // a jump past the last instruction
+    if let Some(label) = labels.get(&program.len()) {
+        writeln!(out, "{label}:").unwrap();
+    }
+    // the blank line before the first function
+    out.strip_prefix('\n').map(str::to_owned).unwrap_or(out)
+}
+
+fn instruction_text(ins: &Instruction, labels: &BTreeMap<usize, String>) -> String {
+    let name = format!("{ins:?}");
+    let name = name.split('(').next().unwrap();
+    let operand = match ins {
+        Instruction::ImmediateInt(v) => v.to_string(),
+        Instruction::ImmediateDouble(v) => format!("{v:?}"),
+        Instruction::Pop(n) | Instruction::Copy(n) | Instruction::Set(n) | Instruction::GetS(n) | Instruction::SetS(n) => n.to_string(),
+        Instruction::Brz(n) | Instruction::Brnz(n) | Instruction::Jmp(n) | Instruction::Call(n) => labels[n].clone(),
+        Instruction::Syscall(syscall) => format!("{syscall:?}"),
+        Instruction::AllocA(tpe) | Instruction::AllocS(tpe) => type_name(tpe),
+        _ => return name.to_owned()
+    };
+    format!("{name} {operand}")
+}
+
+/// Reads assembly, as written by `disassemble`.  Each line is an optional `label:`, then an
+/// optional instruction, then an optional comment starting with `;`.  Instructions are named as
+/// in `Instruction`, followed by their operand if they have one: a number, a label (or an
+/// address), a syscall's name, or a type such as `int[]` or `{int, double}`
+pub fn assemble(src: &str) -> Result<Vec<Instruction>, AssemblyError> {
+    let mut labels = HashMap::new();
+    // the instructions, with the label each jump goes to to fill in once they're all known
+    let mut program: Vec<(Instruction, Option<&str>, usize)> = vec![];
+
+    for (i, line) in src.lines().enumerate() {
+        let line_number = i + 1;
+        let error = |message: String| AssemblyError { line: line_number, message };
+        let mut line = line.split(';').next().unwrap().trim();
+        if let Some((label, rest)) = line.split_once(':') {
+            let label = label.trim();
+            if label.is_empty() || label.contains(char::is_whitespace) || label.parse::<usize>().is_ok() {
+                return Err(error(format!("`{label}` can't be a label")));
+            }
+            if labels.insert(label, program.len()).is_some() {
+                return Err(error(format!("`{label}` is already a label")));
+            }
+            line = rest.trim();
+        }
+        if line.is_empty() {
+            continue;
+        }
+
+        let (name, operand) = line.split_once(char::is_whitespace).map_or((line, ""), |(a, b)| (a, b.trim()));
+        let number = || operand.parse::<usize>().map_err(|_| error(format!("{name} needs a number, not `{operand}`")));
+        use Instruction::*;
+        let mut label = None;
+        let ins = match name {
+            "ImmediateInt" => ImmediateInt(operand.parse().map_err(|_| error(format!("`{operand}` isn't an int")))?),
+            "ImmediateDouble" => ImmediateDouble(operand.parse().map_err(|_| error(format!("`{operand}` isn't a double")))?),
+            "Pop" => Pop(number()?),
+            "Copy" => Copy(number()?),
+            "Set" => Set(number()?),
+            "GetS" => GetS(number()?),
+            "SetS" => SetS(number()?),
+            "Brz" | "Brnz" | "Jmp" | "Call" => {
+                if operand.is_empty() {
+                    return Err(error(format!("{name} needs a label")));
+                }
+                let address = operand.parse().unwrap_or_else(|_| {
+                    label = Some(operand);
+                    0
+                });
+                match name {
+                    "Brz" => Brz(address),
+                    "Brnz" => Brnz(address),
+                    "Jmp" => Jmp(address),
+                    _ => Call(address)
+                }
+            }
+            "Syscall" => Syscall(*stack_machine::Syscall::ALL.iter().find(|x| format!("{x:?}") == operand).ok_or(error(format!("unknown syscall `{operand}`")))?),
+            "AllocA" | "AllocS" => {
+                let (tpe, rest) = parse_type(operand).ok_or(error(format!("`{operand}` isn't a type")))?;
+                if !rest.trim().is_empty() {
+                    return Err(error(format!("`{operand}` isn't a type")));
+                }
+                if name == "AllocA" { AllocA(tpe) } else { AllocS(tpe) }
+            }
+            _ => {
+                let ins = match name {
+                    "AddI" => AddI, "SubI" => SubI, "MulI" => MulI, "DivI" => DivI, "ModI" => ModI,
+                    "AndI" => AndI, "OrI" => OrI, "XorI" => XorI, "ShlI" => ShlI, "ShrI" => ShrI, "ShrlI" => ShrlI,
+                    "LtI" => LtI, "GeI" => GeI, "NotI" => NotI, "EqI" => EqI,
+                    "AddD" => AddD, "SubD" => SubD, "MulD" => MulD, "DivD" => DivD,
+                    "LtD" => LtD, "GeD" => GeD, "EqD" => EqD, "IsInf" => IsInf, "IsNaN" => IsNaN,
+                    "ConvID" => ConvID, "ConvDI" => ConvDI,
+                    "Return" => Return,
+                    "GetA" => GetA, "SetA" => SetA, "LenA" => LenA,
+                    _ => return Err(error(format!("unknown instruction `{name}`")))
+                };
+                if !operand.is_empty() {
+                    return Err(error(format!("{name} doesn't take an operand")));
+                }
+                ins
+            }
+        };
+        program.push((ins, label, line_number));
+    }
+
+    program.into_iter().map(|(ins, label, line)| {
+        let Some(label) = label else { return Ok(ins) };
+        let address = *labels.get(label).ok_or(AssemblyError { line, message: format!("no label `{label}`") })?;
+        Ok(match ins {
+            Instruction::Brz(_) => Instruction::Brz(address),
+            Instruction::Brnz(_) => Instruction::Brnz(address),
+            Instruction::Jmp(_) => Instruction::Jmp(address),
+            _ => Instruction::Call(address)
+        })
+    }).collect()
+}
+
+// a type at the start of the text, and what's left after it
+fn parse_type(text: &str) -> Option<(Tpe, &str)> {
+    let text = text.trim_start();
+    let (mut tpe, mut rest) = if let Some(rest) = text.strip_prefix("int") {
+        (Tpe::Int, rest)
+    } else if let Some(rest) = text.strip_prefix("double") {
+        (Tpe::Double, rest)
+    } else {
+        let mut rest = text.strip_prefix('{')?.trim_start();
+        let mut fields = vec![];
+        loop {
+            if let Some(after) = rest.strip_prefix('}') {
+                rest = after;
+                break;
+            }
+            if !fields.is_empty() {
+                rest = rest.strip_prefix(',')?;
+            }
+            let (field, after) = parse_type(rest)?;
+            fields.push(field);
+            rest = after.trim_start();
+        }
+        (Tpe::Struct(fields), rest)
+    };
+    while let Some(after) = rest.trim_start().strip_prefix("[]") {
+        tpe = Tpe::Array(Box::new(tpe));
+        rest = after;
+    }
+    Some((tpe, rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compiler::Compiler, parser, stack_machine::{ExecutionException, StackItem, Syscall, VM}};
+
+    #[test]
+    fn test_assemble() {
+        let program = assemble("
+            ; counts down from 3
+                ImmediateInt 3
+            loop: Copy 1        ; the counter
+                Brz done
+                ImmediateInt -1
+                AddI
+                Jmp loop
+            done:
+                AllocA {int, double[]}[]
+                Syscall Halt
+        ").unwrap();
+        assert_eq!(format!("{program:?}"), format!("{:?}", vec![
+            Instruction::ImmediateInt(3), Instruction::Copy(1), Instruction::Brz(6), Instruction::ImmediateInt(-1), Instruction::AddI, Instruction::Jmp(1),
+            Instruction::AllocA(Tpe::Array(Box::new(Tpe::Struct(vec![Tpe::Int, Tpe::Array(Box::new(Tpe::Double))])))), Instruction::Syscall(Syscall::Halt)
+        ]));
+
+        let error = |src| assemble(src).unwrap_err();
+        assert_eq!(error("Jmp nowhere"), AssemblyError { line: 1, message: "no label `nowhere`".to_owned() });
+        assert_eq!(error("AddI\nAddI 2").line, 2);
+        assert!(error("a:\na: Return").message.contains("already"));
+        assert!(error("Push 1").message.contains("unknown instruction"));
+        assert!(error("Syscall Explode").message.contains("syscall"));
+        assert!(error("AllocS {int").message.contains("type"));
+        assert!(error("Pop x").message.contains("number"));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let src = "struct P { q: int, r: double[] }\nfun f(p: P) -> int {\n    return p.q\n}\nvar p = new P\nwhile p.q < 3 { p.q = p.q + 1 }\nprintln(f(p) * 2)";
+        let mut compiler = Compiler::new();
+        compiler.compile_program(&parser::parse_program(src).unwrap()).unwrap();
+        let map = compiler.source_map(src);
+        let text = disassemble(&compiler.program, Some((src, &map)));
+        assert_eq!(format!("{:?}", assemble(&text).unwrap()), format!("{:?}", compiler.program));
+        assert_eq!(format!("{:?}", assemble(&disassemble(&compiler.program, None)).unwrap()), format!("{:?}", compiler.program));
+
+        assert!(text.contains("\n; f(p: P) -> int\nf(P):\n    ; 3:5  return p.q\n"));
+        assert!(text.contains("L0:\n    ; 6:7  while p.q < 3 { p.q = p.q + 1 }\n"));
+        assert!(text.contains("Call f(P)"));
+        assert!(text.contains("    ; 4:1  }\n"));
+    }
+
+    #[test]
+    fn test_run_assembled() {
+        let mut vm = VM::new(assemble("
+                ImmediateInt 0
+                Call double
+                Syscall Halt
+            double:
+                Copy 3
+                Copy 1
+                AddI
+                Set 2
+                Return
+        ").unwrap());
+        // the argument, then the return value and the return address above it
+        vm.stack.push(StackItem::Int(21));
+        let result = loop {
+            if let Err(e) = vm.tick() { break e; }
+        };
+        assert_eq!(result, ExecutionException::Halt);
+        assert_eq!(vm.stack, vec![StackItem::Int(21), StackItem::Int(42)]);
+    }
+}
@@ -0,0 +1,95 @@
+use std::time::{Duration, Instant};
+
+use crate::{assembly, stack_machine::VM};
+
+/// Small loops that each lean on one kind of instruction, in assembly, for timing the VM.  Each
+/// runs forever
+pub const MIXES: [(&str, &str); 5] = [
+    ("arithmetic", "
+            ImmediateInt 0
+        loop:
+            Copy 1
+            ImmediateInt 1
+            AddI
+            Set 1
+            Jmp loop
+    "),
+    ("array reads and writes", "
+            ImmediateInt 64
+            AllocA int
+            ImmediateInt 0          ; i
+        loop:
+            ; a[i & 63] = a[i & 63] + 1
+            Copy 1
+            ImmediateInt 63
+            AndI
+            Copy 3
+            GetA
+            ImmediateInt 1
+            AddI
+            Copy 2
+            ImmediateInt 63
+            AndI
+            Copy 4
+            SetA
+            ; i = i + 1
+            Copy 1
+            ImmediateInt 1
+            AddI
+            Set 1
+            Jmp loop
+    "),
+    ("struct fields", "
+            AllocS {int, int}
+        loop:
+            Copy 1
+            GetS 0
+            ImmediateInt 1
+            AddI
+            Copy 2
+            SetS 0
+            Jmp loop
+    "),
+    ("calls", "
+            ImmediateInt 0
+        loop:
+            Call increment
+            Jmp loop
+        increment:
+            Copy 2
+            ImmediateInt 1
+            AddI
+            Set 2
+            Return
+    "),
+    ("allocation", "
+        loop:
+            ImmediateInt 16
+            AllocA {int, double}
+            Pop 1
+            Jmp loop
+    ")
+];
+
+/// How long a mix takes to run the given number of instructions
+pub fn time_mix(src: &str, instructions: usize) -> Duration {
+    let program = assembly::assemble(src).expect("the mixes are valid assembly");
+    let mut vm = VM::new(program);
+    let start = Instant::now();
+    for _ in 0..instructions {
+        vm.tick_nohandle().expect("the mixes run forever");
+    }
+    start.elapsed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mixes() {
+        for (_, src) in MIXES {
+            time_mix(src, 10000);
+        }
+    }
+}
@@ -0,0 +1,489 @@
+use std::{collections::HashMap, fmt::Display};
+
+use crate::stack_machine::{self, Instruction, Tpe};
+
+/// The first bytes of every compiled spell
+pub const MAGIC: &[u8; 4] = b"SPBC";
+/// Bumped whenever the layout changes, older versions are rejected rather than misread
+pub const VERSION: u16 = 2;
+const HEADER_LEN: usize = 16;
+// how many arrays and structs deep a type can be nested, far more than any spell needs but few
+// enough that reading or allocating one can't overflow the stack
+const MAX_TYPE_DEPTH: usize = 64;
+
+// the lines around the base64 of the text form
+const TEXT_BEGIN: &str = "-----BEGIN SPELLCODE BYTECODE-----";
+const TEXT_END: &str = "-----END SPELLCODE BYTECODE-----";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BytecodeError {
+    NotBytecode,
+    UnsupportedVersion(u16),
+    // the contents don't match the checksum in the header, so they were corrupted or edited
+    BadChecksum,
+    // the file ended partway through something
+    Truncated,
+    UnknownOpcode { opcode: u8, offset: usize },
+    UnknownSyscall { syscall: u8, offset: usize },
+    UnknownType { tag: u8, offset: usize },
+    TypeTooDeep { offset: usize },
+    UnknownConstant { tag: u8, offset: usize },
+    // an instruction refers to a constant that isn't in the pool, or is the wrong kind
+    BadConstant { index: u32, offset: usize },
+    // the base64 of the text form
+    BadText,
+    // only when writing, an instruction's operand doesn't fit in the four bytes it gets
+    OperandTooLarge { operand: usize, index: usize }
+}
+
+impl Display for BytecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BytecodeError::NotBytecode => write!(f, "not a compiled spell"),
+            BytecodeError::UnsupportedVersion(version) => write!(f, "compiled with bytecode version {version}, but only version {VERSION} can be loaded"),
+            BytecodeError::BadChecksum => write!(f, "the compiled spell is corrupted"),
+            BytecodeError::Truncated => write!(f, "the compiled spell is cut short"),
+            BytecodeError::UnknownOpcode { opcode, offset } => write!(f, "unknown opcode {opcode} at byte {offset}"),
+            BytecodeError::UnknownSyscall { syscall, offset } => write!(f, "unknown syscall {syscall} at byte {offset}"),
+            BytecodeError::UnknownType { tag, offset } => write!(f, "unknown type tag {tag} at byte {offset}"),
+            BytecodeError::TypeTooDeep { offset } => write!(f, "type nested too deeply at byte {offset}"),
+            BytecodeError::UnknownConstant { tag, offset } => write!(f, "unknown constant tag {tag} at byte {offset}"),
+            BytecodeError::BadConstant { index, offset } => write!(f, "bad constant {index} at byte {offset}"),
+            BytecodeError::BadText => write!(f, "the compiled spell's text is garbled"),
+            BytecodeError::OperandTooLarge { operand, index } => write!(f, "instruction {index} has operand {operand}, too large to compile")
+        }
+    }
+}
+
+// an entry in the constant pool, doubles are kept as bits so they can be compared and hashed
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Constant {
+    Int(i32),
+    Double(u64),
+    Type(Tpe)
+}
+
+/// Encodes a program.  The header is the magic bytes, the version, two bytes of flags (always
+/// 0 for now), the length of the body and its CRC-32.  The body is the constant pool, with every
+/// distinct literal and type in the program, followed by the instructions, each an opcode and
+/// then its operand, which for literals and types is an index into the pool.  Numbers are
+/// little endian.  Fails if an operand doesn't fit in 32 bits
+pub fn write_program(program: &[Instruction]) -> Result<Vec<u8>, BytecodeError> {
+    let mut pool = Pool::default();
+    let mut code = vec![];
+    for (index, ins) in program.iter().enumerate() {
+        write_instruction(&mut code, &mut pool, ins).map_err(|operand| BytecodeError::OperandTooLarge { operand, index })?;
+    }
+
+    let mut body = (pool.constants.len() as u32).to_le_bytes().to_vec();
+    for constant in &pool.constants {
+        match constant {
+            Constant::Int(v) => {
+                body.push(0);
+                body.extend(v.to_le_bytes());
+            }
+            Constant::Double(v) => {
+                body.push(1);
+                body.extend(v.to_le_bytes());
+            }
+            Constant::Type(tpe) => {
+                body.push(2);
+                write_type(&mut body, tpe);
+            }
+        }
+    }
+    body.extend((program.len() as u32).to_le_bytes());
+    body.extend(code);
+
+    let mut out = MAGIC.to_vec();
+    out.extend(VERSION.to_le_bytes());
+    out.extend(0u16.to_le_bytes());
+    out.extend((body.len() as u32).to_le_bytes());
+    out.extend(crc32(&body).to_le_bytes());
+    out.extend(body);
+    Ok(out)
+}
+
+#[derive(Default)]
+struct Pool {
+    constants: Vec<Constant>,
+    indices: HashMap<Constant, u32>
+}
+
+impl Pool {
+    fn add(&mut self, constant: Constant) -> u32 {
+        *self.indices.entry(constant.clone()).or_insert_with(|| {
+            self.constants.push(constant);
+            self.constants.len() as u32 - 1
+        })
+    }
+}
+
+// fails with the operand if it's too large
+fn write_instruction(out: &mut Vec<u8>, pool: &mut Pool, ins: &Instruction) -> Result<(), usize> {
+    use Instruction::*;
+    let operand = |n: &usize| u32::try_from(*n).map(Some).map_err(|_| *n);
+    let (opcode, operand): (u8, Option<u32>) = match ins {
+        ImmediateInt(v) => (0, Some(pool.add(Constant::Int(*v)))),
+        ImmediateDouble(v) => (1, Some(pool.add(Constant::Double(v.to_bits())))),
+        Pop(n) => (2, operand(n)?),
+        Copy(n) => (3, operand(n)?),
+        Set(n) => (4, operand(n)?),
+        AddI => (5, None), SubI => (6, None), MulI => (7, None), DivI => (8, None), ModI => (9, None),
+        AndI => (10, None), OrI => (11, None), XorI => (12, None), ShlI => (13, None), ShrI => (14, None), ShrlI => (15, None),
+        LtI => (16, None), GeI => (17, None), NotI => (18, None), EqI => (19, None),
+        AddD => (20, None), SubD => (21, None), MulD => (22, None), DivD => (23, None),
+        LtD => (24, None), GeD => (25, None), EqD => (26, None), IsInf => (27, None), IsNaN => (28, None),
+        ConvID => (29, None), ConvDI => (30, None),
+        Brz(n) => (31, operand(n)?),
+        Brnz(n) => (32, operand(n)?),
+        Jmp(n) => (33, operand(n)?),
+        Call(n) => (34, operand(n)?),
+        Return => (35, None),
+        Syscall(syscall) => {
+            out.extend([36, *syscall as u8]);
+            return Ok(());
+        }
+        AllocA(tpe) => (37, Some(pool.add(Constant::Type(tpe.clone())))),
+        GetA => (38, None), SetA => (39, None), LenA => (40, None),
+        AllocS(tpe) => (41, Some(pool.add(Constant::Type(tpe.clone())))),
+        GetS(n) => (42, operand(n)?),
+        SetS(n) => (43, operand(n)?)
+    };
+    out.push(opcode);
+    if let Some(v) = operand {
+        out.extend(v.to_le_bytes());
+    }
+    Ok(())
+}
+
+fn write_type(out: &mut Vec<u8>, tpe: &Tpe) {
+    match tpe {
+        Tpe::Int => out.push(0),
+        Tpe::Double => out.push(1),
+        Tpe::Array(inner) => {
+            out.push(2);
+            write_type(out, inner);
+        }
+        Tpe::Struct(fields) => {
+            out.push(3);
+            out.extend((fields.len() as u32).to_le_bytes());
+            for field in fields {
+                write_type(out, field);
+            }
+        }
+    }
+}
+
+/// The CRC-32 used by zip and PNG
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/// Encodes a program like `write_program`, as base64 between two marker lines so it can be
+/// kept anywhere text can, such as the spellbook or a save file
+#[allow(unused)]
+pub fn write_text(program: &[Instruction]) -> Result<String, BytecodeError> {
+    let encoded = base64_encode(&write_program(program)?);
+    let mut out = format!("{TEXT_BEGIN}\n");
+    // the alphabet is ASCII, so every chunk is whole characters
+    for line in encoded.as_bytes().chunks(76) {
+        out.push_str(std::str::from_utf8(line).unwrap());
+        out.push('\n');
+    }
+    out.push_str(TEXT_END);
+    out.push('\n');
+    Ok(out)
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, b)| n | (*b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn base64_decode(text: &str) -> Result<Vec<u8>, BytecodeError> {
+    let digits = text.bytes().filter(|x| !x.is_ascii_whitespace()).collect::<Vec<_>>();
+    if !digits.len().is_multiple_of(4) {
+        return Err(BytecodeError::BadText);
+    }
+    let mut out = vec![];
+    for chunk in digits.chunks(4) {
+        let padding = chunk.iter().rev().take_while(|x| **x == b'=').count();
+        let mut n = 0u32;
+        for (i, digit) in chunk[..4 - padding].iter().enumerate() {
+            let value = BASE64.iter().position(|x| x == digit).ok_or(BytecodeError::BadText)?;
+            n |= (value as u32) << (18 - 6 * i);
+        }
+        if padding > 2 {
+            return Err(BytecodeError::BadText);
+        }
+        out.extend(&n.to_be_bytes()[1..4 - padding]);
+    }
+    Ok(out)
+}
+
+/// Whether the bytes look like a compiled spell, in either form, rather than source
+#[allow(unused)]
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC) || bytes.trim_ascii_start().starts_with(TEXT_BEGIN.as_bytes())
+}
+
+/// Decodes a program written by `write_program` or `write_text`
+pub fn read_program(bytes: &[u8]) -> Result<Vec<Instruction>, BytecodeError> {
+    if !bytes.starts_with(MAGIC) {
+        return read_text(bytes);
+    }
+    let mut reader = Reader { bytes, pos: MAGIC.len() };
+    let version = u16::from_le_bytes(reader.take()?);
+    if version != VERSION {
+        return Err(BytecodeError::UnsupportedVersion(version));
+    }
+    let _flags = u16::from_le_bytes(reader.take()?);
+    let length = reader.u32()? as usize;
+    let checksum = reader.u32()?;
+    let body = bytes.get(HEADER_LEN..HEADER_LEN + length).ok_or(BytecodeError::Truncated)?;
+    if crc32(body) != checksum {
+        return Err(BytecodeError::BadChecksum);
+    }
+    // offsets in errors are still from the start of the file
+    let mut reader = Reader { bytes: &bytes[..HEADER_LEN + length], pos: HEADER_LEN };
+
+    let count = reader.u32()?;
+    let mut pool = vec![];
+    for _ in 0..count {
+        pool.push(reader.constant()?);
+    }
+    let count = reader.u32()?;
+    let mut program = vec![];
+    for _ in 0..count {
+        program.push(reader.instruction(&pool)?);
+    }
+    Ok(program)
+}
+
+fn read_text(bytes: &[u8]) -> Result<Vec<Instruction>, BytecodeError> {
+    let text = std::str::from_utf8(bytes).map_err(|_| BytecodeError::NotBytecode)?.trim();
+    let encoded = text.strip_prefix(TEXT_BEGIN).ok_or(BytecodeError::NotBytecode)?
+        .strip_suffix(TEXT_END).ok_or(BytecodeError::Truncated)?;
+    let decoded = base64_decode(encoded)?;
+    if !decoded.starts_with(MAGIC) {
+        return Err(BytecodeError::NotBytecode);
+    }
+    read_program(&decoded)
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize
+}
+
+impl Reader<'_> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], BytecodeError> {
+        let v = self.bytes.get(self.pos..self.pos + N).ok_or(BytecodeError::Truncated)?;
+        self.pos += N;
+        Ok(v.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, BytecodeError> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, BytecodeError> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+
+    fn usize(&mut self) -> Result<usize, BytecodeError> {
+        Ok(self.u32()? as usize)
+    }
+
+    fn constant(&mut self) -> Result<Constant, BytecodeError> {
+        let offset = self.pos;
+        Ok(match self.u8()? {
+            0 => Constant::Int(i32::from_le_bytes(self.take()?)),
+            1 => Constant::Double(u64::from_le_bytes(self.take()?)),
+            2 => Constant::Type(self.tpe(0)?),
+            tag => return Err(BytecodeError::UnknownConstant { tag, offset })
+        })
+    }
+
+    fn pooled<'p>(&mut self, pool: &'p [Constant]) -> Result<&'p Constant, BytecodeError> {
+        let offset = self.pos;
+        let index = self.u32()?;
+        pool.get(index as usize).ok_or(BytecodeError::BadConstant { index, offset })
+    }
+
+    fn instruction(&mut self, pool: &[Constant]) -> Result<Instruction, BytecodeError> {
+        use Instruction::*;
+        let offset = self.pos;
+        let opcode = self.u8()?;
+        let bad_constant = |reader: &Self| BytecodeError::BadConstant {
+            index: u32::from_le_bytes(reader.bytes[reader.pos - 4..reader.pos].try_into().unwrap()),
+            offset: offset + 1
+        };
+        Ok(match opcode {
+            0 => match self.pooled(pool)? {
+                Constant::Int(v) => ImmediateInt(*v),
+                _ => return Err(bad_constant(self))
+            },
+            1 => match self.pooled(pool)? {
+                Constant::Double(v) => ImmediateDouble(f64::from_bits(*v)),
+                _ => return Err(bad_constant(self))
+            },
+            2 => Pop(self.usize()?),
+            3 => Copy(self.usize()?),
+            4 => Set(self.usize()?),
+            5 => AddI, 6 => SubI, 7 => MulI, 8 => DivI, 9 => ModI,
+            10 => AndI, 11 => OrI, 12 => XorI, 13 => ShlI, 14 => ShrI, 15 => ShrlI,
+            16 => LtI, 17 => GeI, 18 => NotI, 19 => EqI,
+            20 => AddD, 21 => SubD, 22 => MulD, 23 => DivD,
+            24 => LtD, 25 => GeD, 26 => EqD, 27 => IsInf, 28 => IsNaN,
+            29 => ConvID, 30 => ConvDI,
+            31 => Brz(self.usize()?),
+            32 => Brnz(self.usize()?),
+            33 => Jmp(self.usize()?),
+            34 => Call(self.usize()?),
+            35 => Return,
+            36 => {
+                let offset = self.pos;
+                let syscall = self.u8()?;
+                Syscall(*stack_machine::Syscall::ALL.get(syscall as usize).ok_or(BytecodeError::UnknownSyscall { syscall, offset })?)
+            }
+            37 | 41 => {
+                let Constant::Type(tpe) = self.pooled(pool)? else { return Err(bad_constant(self)) };
+                if opcode == 37 { AllocA(tpe.clone()) } else { AllocS(tpe.clone()) }
+            }
+            38 => GetA, 39 => SetA, 40 => LenA,
+            42 => GetS(self.usize()?),
+            43 => SetS(self.usize()?),
+            opcode => return Err(BytecodeError::UnknownOpcode { opcode, offset })
+        })
+    }
+
+    fn tpe(&mut self, depth: usize) -> Result<Tpe, BytecodeError> {
+        let offset = self.pos;
+        if depth > MAX_TYPE_DEPTH {
+            return Err(BytecodeError::TypeTooDeep { offset });
+        }
+        Ok(match self.u8()? {
+            0 => Tpe::Int,
+            1 => Tpe::Double,
+            2 => Tpe::Array(Box::new(self.tpe(depth + 1)?)),
+            3 => {
+                let count = self.u32()?;
+                let mut fields = vec![];
+                for _ in 0..count {
+                    fields.push(self.tpe(depth + 1)?);
+                }
+                Tpe::Struct(fields)
+            }
+            tag => return Err(BytecodeError::UnknownType { tag, offset })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compiler::Compiler, parser, stack_machine::Syscall};
+
+    #[test]
+    fn test_round_trip() {
+        let src = "struct P { q: int, r: double[] }\nvar p = new P\np.r = new double[2]\np.r[1] = -1.5\nprintln(get_neighbors(p.q, 2).size)";
+        let mut compiler = Compiler::new();
+        compiler.compile_program(&parser::parse_program(src).unwrap()).unwrap();
+        let bytes = write_program(&compiler.program).unwrap();
+        assert_eq!(format!("{:?}", read_program(&bytes).unwrap()), format!("{:?}", compiler.program));
+        let text = write_text(&compiler.program).unwrap();
+        assert!(text.lines().all(|x| x.len() <= 76));
+        assert!(is_bytecode(text.as_bytes()));
+        assert_eq!(format!("{:?}", read_program(text.as_bytes()).unwrap()), format!("{:?}", compiler.program));
+
+        let program = vec![Instruction::ImmediateInt(i32::MIN), Instruction::ImmediateDouble(f64::NAN), Instruction::Syscall(Syscall::GetNeighbors)];
+        assert_eq!(format!("{:?}", read_program(&write_program(&program).unwrap()).unwrap()), format!("{program:?}"));
+    }
+
+    #[test]
+    fn test_constant_pool() {
+        let program = vec![
+            Instruction::ImmediateInt(7), Instruction::ImmediateInt(7), Instruction::ImmediateDouble(7.0),
+            Instruction::AllocA(Tpe::Int), Instruction::AllocA(Tpe::Int)
+        ];
+        let bytes = write_program(&program).unwrap();
+        // three constants, each only once
+        assert_eq!(bytes[HEADER_LEN], 3);
+        assert_eq!(&bytes[HEADER_LEN + 4..HEADER_LEN + 9], &[0, 7, 0, 0, 0]);
+        assert_eq!(format!("{:?}", read_program(&bytes).unwrap()), format!("{program:?}"));
+    }
+
+    #[test]
+    fn test_encodings() {
+        for bytes in [&b""[..], b"f", b"fo", b"foo", b"foob", b"fooba", b"foobar"] {
+            assert_eq!(base64_decode(&base64_encode(bytes)).unwrap(), bytes);
+        }
+        assert_eq!(base64_encode(b"foobar"), "Zm9vYmFy");
+        assert_eq!(base64_encode(b"fo"), "Zm8=");
+        assert_eq!(base64_decode("Zm8"), Err(BytecodeError::BadText));
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+        assert_eq!(base64_decode("Zm*="), Err(BytecodeError::BadText));
+    }
+
+    #[test]
+    fn test_bad_bytecode() {
+        assert_eq!(read_program(b"var x = 1").unwrap_err(), BytecodeError::NotBytecode);
+        let bytes = write_program(&[Instruction::Jmp(3), Instruction::Return]).unwrap();
+        assert_eq!(read_program(&bytes[..bytes.len() - 1]).unwrap_err(), BytecodeError::Truncated);
+
+        let mut old = bytes.clone();
+        old[4] = 1;
+        assert_eq!(read_program(&old).unwrap_err(), BytecodeError::UnsupportedVersion(1));
+        let mut corrupted = bytes.clone();
+        corrupted[HEADER_LEN + 9] = 4;
+        assert_eq!(read_program(&corrupted).unwrap_err(), BytecodeError::BadChecksum);
+
+        // the checksum has to be fixed up to get to the problems after it
+        let with_body = |mut bytes: Vec<u8>, at: usize, value: u8| {
+            bytes[at] = value;
+            let checksum = crc32(&bytes[HEADER_LEN..]);
+            bytes[12..16].copy_from_slice(&checksum.to_le_bytes());
+            read_program(&bytes).unwrap_err()
+        };
+        let bytes = write_program(&[Instruction::Return, Instruction::Syscall(Syscall::Halt)]).unwrap();
+        // pool count, instruction count, then Return at 24 and the syscall at 26
+        assert_eq!(with_body(bytes.clone(), 26, 200), BytecodeError::UnknownSyscall { syscall: 200, offset: 26 });
+        assert_eq!(with_body(bytes, 24, 99), BytecodeError::UnknownOpcode { opcode: 99, offset: 24 });
+        let bytes = write_program(&[Instruction::ImmediateDouble(1.0), Instruction::ImmediateInt(2)]).unwrap();
+        // the double's load is at 39, pointing at constant 0
+        assert_eq!(with_body(bytes, 39, 1), BytecodeError::BadConstant { index: 1, offset: 39 });
+        let bytes = write_program(&[Instruction::ImmediateInt(2)]).unwrap();
+        assert_eq!(with_body(bytes, 20, 9), BytecodeError::UnknownConstant { tag: 9, offset: 20 });
+        let deep = (0..100).fold(Tpe::Int, |x, _| Tpe::Array(Box::new(x)));
+        // the type starts at 21, after the constant's tag, and one byte in is one deeper
+        assert_eq!(read_program(&write_program(&[Instruction::AllocA(deep)]).unwrap()).unwrap_err(), BytecodeError::TypeTooDeep { offset: 21 + MAX_TYPE_DEPTH + 1 });
+
+        let text = write_text(&[Instruction::Return]).unwrap();
+        assert_eq!(read_program(text.replace('A', "*").as_bytes()).unwrap_err(), BytecodeError::BadText);
+        assert_eq!(read_program(text.lines().next().unwrap().as_bytes()).unwrap_err(), BytecodeError::Truncated);
+
+        // rather than cut down to a different jump that would still load
+        assert_eq!(write_program(&[Instruction::Return, Instruction::Jmp(1 << 32)]), Err(BytecodeError::OperandTooLarge { operand: 1 << 32, index: 1 }));
+    }
+}
@@ -1,7 +1,14 @@
-use std::{collections::HashMap, ops::Range};
+// errors carry the types involved so they can be explained, and only happen once per mistake
+#![allow(clippy::result_large_err)]
+
+use std::{collections::{HashMap, HashSet}, fmt::Display, ops::Range};
 
 use crate::{parser::{Expression, Literal, Op, Statement, Tag, TypeName, UnaryOp}, stack_machine::{self, Instruction, Syscall, Tpe}};
 
+// the variable holding the value a match statement is comparing, the space means it can't
+// clash with a real one
+const MATCH_VALUE: &str = "match value";
+
 pub struct Compiler {
     pub stack: Vec<(CompStackI, CompType)>,
     pub program: Vec<Instruction>,
@@ -10,13 +17,119 @@ pub struct Compiler {
     current_function: Option<DeclaredFunction>,
     predefined: Vec<RawFunction>,
     function_addresses: HashMap<FunctionSignature, usize>,
-    structs: Vec<CompStruct>
+    structs: Vec<CompStruct>,
+    errors: Vec<CompErr>,
+    // where each user-defined function was declared, for pointing at redeclarations
+    function_sites: HashMap<FunctionSignature, Range<usize>>,
+    // where the variable with each name was most recently declared.  Redeclaring a variable
+    // that's still in scope is an error, so this is always the one in scope
+    variable_sites: HashMap<String, Range<usize>>,
+    pub warnings: Vec<CompWarning>,
+    // false while compiling the stdlib, whose locations aren't in the user's source
+    user_code: bool,
+    // every variable the user declared, and the declaration sites of the ones that are read
+    declared_variables: Vec<Tag<String>>,
+    used_variables: HashSet<Range<usize>>,
+    // the instructions and source location of each loop whose condition is literally true
+    infinite_loops: Vec<(Range<usize>, Range<usize>)>,
+    // the loops around the statement being compiled, innermost last
+    loops: Vec<LoopContext>,
+    // only the language server reads this
+    #[allow(unused)]
+    pub info: CodeInfo,
+    // from each offset on, the instructions are part of the given statement.  Later entries take
+    // over from earlier ones
+    spans: Vec<SpanMarker>,
+    current_span: Option<Range<usize>>
+}
+
+// the statement the instructions from an offset on belong to, and what's in scope for them
+struct SpanMarker {
+    start: usize,
+    span: Option<Range<usize>>,
+    // true where the statement begins, false where it carries on after one nested in it
+    begins: bool,
+    variables: Vec<Local>
+}
+
+/// What the compiler worked out about the user's code, for editor tooling
+#[allow(unused)]
+#[derive(Debug, Default)]
+pub struct CodeInfo {
+    // the type of every expression, and of every variable where it's declared
+    pub types: Vec<(Range<usize>, CompType)>,
+    // the function each call goes to, by where the function's name is
+    pub calls: Vec<(Range<usize>, FunctionSignature)>
+}
+
+/// Where each instruction of a compiled program came from, for debugging it
+#[derive(Debug, Clone, Default)]
+pub struct SourceMap {
+    // the statement each instruction is part of, None for the stdlib and for the code the
+    // compiler adds around the user's
+    pub spans: Vec<Option<Range<usize>>>,
+    // the line, counting from 1, each instruction's statement starts on
+    pub lines: Vec<Option<usize>>,
+    // the first instruction of each statement in the user's code, in order.  A debugger pauses
+    // at these
+    pub statements: Vec<usize>,
+    // the variables in scope from each offset on, in order
+    pub scopes: Vec<(usize, Vec<Local>)>,
+    // the address of each function, in order.  The top level code starts at 0 and isn't one
+    pub functions: Vec<(usize, DeclaredFunction)>
+}
+
+/// A variable, and where it is in the stack frame of the function it's declared in
+#[derive(Debug, Clone, PartialEq)]
+pub struct Local {
+    pub name: String,
+    pub tpe: CompType,
+    // counting up from the first argument, or from the bottom of the stack at the top level
+    pub slot: usize
+}
+
+impl SourceMap {
+    #[allow(unused)]
+    pub fn span(&self, pc: usize) -> Option<Range<usize>> {
+        self.spans.get(pc).cloned().flatten()
+    }
+
+    /// The function an instruction is in, or None for the top level code
+    #[allow(unused)]
+    pub fn function(&self, pc: usize) -> Option<&DeclaredFunction> {
+        let index = self.functions.partition_point(|x| x.0 <= pc);
+        index.checked_sub(1).map(|x| &self.functions[x].1)
+    }
+
+    #[allow(unused)]
+    pub fn line(&self, pc: usize) -> Option<usize> {
+        self.lines.get(pc).copied().flatten()
+    }
+
+    /// The variables in scope at an instruction, oldest first
+    #[allow(unused)]
+    pub fn variables(&self, pc: usize) -> &[Local] {
+        let index = self.scopes.partition_point(|x| x.0 <= pc);
+        index.checked_sub(1).map_or(&[], |x| &self.scopes[x].1)
+    }
+}
+
+/// Where `break` and `continue` go for a loop being compiled.  The jumps are patched once the
+/// loop is finished and the targets are known
+struct LoopContext {
+    label: Option<String>,
+    // the stack length the code at each target expects, everything above is popped first
+    break_stack_len: usize,
+    continue_stack_len: usize,
+    // offsets of the Jmp instructions to patch
+    breaks: Vec<usize>,
+    continues: Vec<usize>
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct CompStruct {
-    name: String,
-    fields: Vec<(String, CompType)>
+    pub name: String,
+    pub fields: Vec<(String, CompType)>
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -31,6 +144,21 @@ pub enum CompType {
     Struct(CompStruct)
 }
 
+impl Display for CompType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CompType::Int => write!(f, "int"),
+            CompType::Double => write!(f, "double"),
+            CompType::Char => write!(f, "char"),
+            CompType::Bool => write!(f, "bool"),
+            CompType::String => write!(f, "string"),
+            CompType::Array(inner) => write!(f, "{inner}[]"),
+            CompType::Void => write!(f, "void"),
+            CompType::Struct(v) => write!(f, "{}", v.name)
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum CompStackI {
     Temp,
@@ -39,21 +167,110 @@ pub enum CompStackI {
     ReturnValue
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum CompilerError {
-    TypeMismatch,
-    VariableNotFound,
-    Redeclaration,
+    TypeMismatch { expected: CompType, found: CompType },
+    NotAnArray { found: CompType },
+    InvalidOperands { op: Op, left: CompType, right: CompType },
+    InvalidUnaryOperand { op: UnaryOp, found: CompType },
+    BranchMismatch { if_true: CompType, if_false: CompType, if_true_location: Range<usize> },
+    VariableNotFound { name: String },
+    // previous is None for built-in functions
+    Redeclaration { name: String, previous: Option<Range<usize>> },
     CannotAssign,
     FunctionsMustBeTopLevel,
+    StructsMustBeTopLevel,
     NotInFunction,
-    FunctionNotFound,
-    WrongNumberOfArguments,
-    PropertyNotFound,
-    TypeNotFound
+    FunctionNotFound { signature: FunctionSignature, candidates: Vec<FunctionSignature> },
+    WrongNumberOfArguments { expected: usize, found: usize },
+    PropertyNotFound { name: String, tpe: CompType, fields: Vec<String> },
+    TypeNotFound { name: String },
+    // points at the closing brace of the function
+    MissingReturn { name: String, return_type: CompType },
+    NotInLoop { keyword: &'static str },
+    LabelNotFound { label: String },
+    CannotMatch { found: CompType }
+}
+
+impl CompilerError {
+    /// Other places in the source that help explain the error, with a label for each
+    pub fn secondary_spans(&self) -> Vec<(Range<usize>, String)> {
+        match self {
+            CompilerError::Redeclaration { previous: Some(loc), .. } => vec![(loc.clone(), "previously declared here".to_owned())],
+            CompilerError::BranchMismatch { if_true, if_true_location, .. } => vec![(if_true_location.clone(), format!("this branch is `{if_true}`"))],
+            _ => vec![]
+        }
+    }
+}
+
+impl Display for CompilerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CompilerError::TypeMismatch { expected, found } => write!(f, "mismatched types: expected `{expected}`, found `{found}`"),
+            CompilerError::NotAnArray { found } => write!(f, "cannot index into `{found}`, only arrays and strings"),
+            CompilerError::InvalidOperands { op, left, right } => write!(f, "no operator `{}` for `{left}` and `{right}`", op.symbol()),
+            CompilerError::InvalidUnaryOperand { op, found } => write!(f, "cannot apply unary `{}` to `{found}`", op.symbol()),
+            CompilerError::BranchMismatch { if_true, if_false, .. } => write!(f, "if and else branches have different types: `{if_true}` and `{if_false}`"),
+            CompilerError::VariableNotFound { name } => write!(f, "no variable named `{name}` in scope"),
+            CompilerError::Redeclaration { name, previous: Some(_) } => write!(f, "`{name}` is already declared"),
+            CompilerError::Redeclaration { name, previous: None } => write!(f, "`{name}` is already declared as a built-in function"),
+            CompilerError::CannotAssign => write!(f, "can only assign to variables, array elements, and struct fields"),
+            CompilerError::FunctionsMustBeTopLevel => write!(f, "functions must be declared at the top level"),
+            CompilerError::StructsMustBeTopLevel => write!(f, "structs must be declared at the top level"),
+            CompilerError::NotInFunction => write!(f, "`return` outside of a function"),
+            CompilerError::FunctionNotFound { signature, candidates } if candidates.is_empty() => write!(f, "no function named `{}` (looking for `{signature}`)", signature.name),
+            CompilerError::FunctionNotFound { signature, candidates } => {
+                write!(f, "no function `{signature}`, the candidates are ")?;
+                let candidates = candidates.iter().map(|x| format!("`{x}`")).collect::<Vec<_>>();
+                write!(f, "{}", candidates.join(", "))
+            }
+            CompilerError::WrongNumberOfArguments { expected, found } => write!(f, "expected {expected} arguments, found {found}"),
+            CompilerError::PropertyNotFound { name, tpe, fields } if fields.is_empty() => write!(f, "`{tpe}` has no field `{name}`, it has no fields"),
+            CompilerError::PropertyNotFound { name, tpe, fields } => {
+                let fields = fields.iter().map(|x| format!("`{x}`")).collect::<Vec<_>>();
+                write!(f, "`{tpe}` has no field `{name}`, the fields are {}", fields.join(", "))
+            }
+            CompilerError::TypeNotFound { name } => write!(f, "no type named `{name}`"),
+            CompilerError::MissingReturn { name, return_type } => write!(f, "`{name}` can reach its end without returning a `{return_type}`"),
+            CompilerError::NotInLoop { keyword } => write!(f, "`{keyword}` outside of a loop"),
+            CompilerError::LabelNotFound { label } => write!(f, "no loop labelled `{label}` around this"),
+            CompilerError::CannotMatch { found } => write!(f, "can only match on `int`, `char` and `string`, not `{found}`")
+        }
+    }
+}
+
+/// Something that compiles but is probably a mistake
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompilerWarning {
+    UnusedVariable { name: String },
+    UnreachableCode,
+    UnusedFunction { signature: FunctionSignature },
+    OverloadsBuiltin { signature: FunctionSignature },
+    ConstantCondition { value: bool },
+    InfiniteLoop
+}
+
+impl Display for CompilerWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CompilerWarning::UnusedVariable { name } => write!(f, "variable `{name}` is never read"),
+            CompilerWarning::UnreachableCode => write!(f, "unreachable code"),
+            CompilerWarning::UnusedFunction { signature } => write!(f, "function `{signature}` is never called"),
+            CompilerWarning::OverloadsBuiltin { signature } => write!(f, "`{signature}` adds an overload to the built-in function `{}`", signature.name),
+            CompilerWarning::ConstantCondition { value } => write!(f, "condition is always {value}"),
+            CompilerWarning::InfiniteLoop => write!(f, "loop never ends and never does anything, the spell will freeze")
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CompWarning {
+    pub warning: CompilerWarning,
+    pub location: Range<usize>
 }
+
 #[allow(unused)]
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct CompErr {
     pub error: CompilerError,
     pub location: Range<usize>
@@ -66,10 +283,21 @@ struct FunctionCallToFix {
 }
 
 #[derive(Debug, Clone)]
-struct DeclaredFunction {
-    name: String,
-    args: Vec<(String, CompType)>,
-    return_type: Option<CompType>
+pub struct DeclaredFunction {
+    pub name: String,
+    pub args: Vec<(String, CompType)>,
+    pub return_type: Option<CompType>
+}
+
+impl Display for DeclaredFunction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let args = self.args.iter().map(|(name, tpe)| format!("{name}: {tpe}")).collect::<Vec<_>>();
+        write!(f, "{}({})", self.name, args.join(", "))?;
+        if let Some(tpe) = &self.return_type {
+            write!(f, " -> {tpe}")?;
+        }
+        Ok(())
+    }
 }
 
 struct RawFunction {
@@ -78,11 +306,18 @@ struct RawFunction {
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
-struct FunctionSignature {
+pub struct FunctionSignature {
     pub name: String,
     pub args: Vec<CompType>
 }
 
+impl Display for FunctionSignature {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let args = self.args.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+        write!(f, "{}({})", self.name, args.join(", "))
+    }
+}
+
 impl From<&DeclaredFunction> for FunctionSignature {
     fn from(value: &DeclaredFunction) -> Self {
         FunctionSignature { name: value.name.clone(), args: value.args.iter().map(|x| x.1.clone()).collect() }
@@ -111,6 +346,14 @@ struct OpEvaluation {
     tpe: CompType
 }
 
+// the type of an expression, and the types of its parts in the order `Compiler::parts` gives
+// them.  Type checking builds this once, and code generation and editor tooling read it rather
+// than checking each part again
+struct Typed {
+    tpe: Result<CompType, CompErr>,
+    parts: Vec<Typed>
+}
+
 impl Compiler {
     pub fn new() -> Compiler {
         let predefined: Vec<RawFunction> = vec![
@@ -172,7 +415,19 @@ impl Compiler {
             current_function: None,
             predefined,
             function_addresses: HashMap::new(),
-            structs: vec![]
+            structs: vec![],
+            errors: vec![],
+            function_sites: HashMap::new(),
+            variable_sites: HashMap::new(),
+            warnings: vec![],
+            user_code: true,
+            declared_variables: vec![],
+            used_variables: HashSet::new(),
+            infinite_loops: vec![],
+            loops: vec![],
+            info: CodeInfo::default(),
+            spans: vec![],
+            current_span: None
         }
     }
 
@@ -184,8 +439,8 @@ impl Compiler {
             TypeName::String => CompType::String,
             TypeName::Bool => CompType::Bool,
             TypeName::Array(box Tag { item: v, .. }) => CompType::Array(Box::new(self.resolve_type(v)?)),
-            TypeName::Struct(name) => CompType::Struct(self.structs.iter().find(|x| &x.name == &**name)
-                .ok_or(CompErr { error: CompilerError::TypeNotFound, location: name.loc.clone() })?
+            TypeName::Struct(name) => CompType::Struct(self.structs.iter().find(|x| x.name == **name)
+                .ok_or(CompErr { error: CompilerError::TypeNotFound { name: name.item.clone() }, location: name.loc.clone() })?
                 .clone())
         })
     }
@@ -204,49 +459,70 @@ impl Compiler {
 
     }
 
-    pub fn compile_program(&mut self, inp: &[Statement]) -> Result<(), CompErr> {
+    /// Compiles a whole program, carrying on past errors so that every one of them is returned
+    pub fn compile_program(&mut self, inp: &[Statement]) -> Result<(), Vec<CompErr>> {
         let stdlib = true;
-        let mut program = inp.to_vec();
+        let mut program = vec![];
         if stdlib {
             program.extend(crate::parser::spellcode::program(include_str!("../stdlib.spc")).unwrap());
         }
+        // the stdlib goes first so redeclaring one of its functions is reported in the user's code
+        let user_start = program.len();
+        program.extend(inp.iter().cloned());
+
+        let mut struct_sites: HashMap<&str, Range<usize>> = HashMap::new();
         for st in &program {
-            let Statement::StructDef { name: Tag { item: name, .. }, fields } = st else { continue };
+            let Statement::StructDef { name: Tag { item: name, loc, .. }, fields } = st else { continue };
+            if let Some(previous) = struct_sites.get(name.as_str()) {
+                self.errors.push(CompErr { error: CompilerError::Redeclaration { name: name.clone(), previous: Some(previous.clone()) }, location: loc.clone() });
+                continue;
+            }
+            struct_sites.insert(name, loc.clone());
             let mut f = vec![];
             for (name, tpe) in fields {
-                f.push((name.item.clone(), self.resolve_type(tpe)?));
+                match self.resolve_type(tpe) {
+                    Ok(v) => f.push((name.item.clone(), v)),
+                    Err(e) => self.errors.push(e)
+                }
             }
             self.structs.push(CompStruct { name: name.clone(), fields: f });
         }
 
-        for st in &program {
-            let Statement::FunctionDef { name: Tag { item: name, loc: name_l }, arguments, return_type, block: _ } = st else { continue };
-
-            let mut args = vec![];
-            for (Tag { item: arg_name, .. }, Tag { item: tpe, .. }) in arguments {
-                args.push((arg_name.clone(), self.resolve_type(tpe)?));
-            }
+        for (i, st) in program.iter().enumerate() {
+            let Statement::FunctionDef { name: Tag { item: name, loc: name_l, .. }, arguments, return_type, .. } = st else { continue };
 
-            let return_type = match return_type {
-                Some(v) => Some(self.resolve_type(v)?),
-                None => None
+            let f = match self.declare_function(name, arguments, return_type) {
+                Ok(v) => v,
+                Err(e) => {
+                    self.errors.push(e);
+                    continue;
+                }
             };
-
-            let f = DeclaredFunction { name: name.clone(), args, return_type };
             
-            if self.functions.iter().any(|x| FunctionSignature::from(x) == FunctionSignature::from(&f)) {
-                return Err(CompErr { error: CompilerError::Redeclaration, location: name_l.clone() });
+            let signature = FunctionSignature::from(&f);
+            if self.functions.iter().any(|x| FunctionSignature::from(x) == signature) {
+                let previous = self.function_sites.get(&signature).cloned();
+                self.errors.push(CompErr { error: CompilerError::Redeclaration { name: name.clone(), previous }, location: name_l.clone() });
+                continue;
             }
 
+            if i >= user_start {
+                // user functions are the ones with sites, so this only looks at the built-in ones
+                if self.functions.iter().any(|x| &x.name == name && !self.function_sites.contains_key(&x.into())) {
+                    self.warnings.push(CompWarning { warning: CompilerWarning::OverloadsBuiltin { signature: signature.clone() }, location: name_l.clone() });
+                }
+                self.function_sites.insert(signature, name_l.clone());
+            }
             self.functions.push(f);
         }
 
-        for st in &program {
-            if let Statement::FunctionDef { .. } = st { continue };
-            if let Statement::StructDef { .. } = st { continue };
-            self.compile_statement(st)?;
-        }
+        let is_code = |x: &&Statement| !matches!(x, Statement::FunctionDef { .. } | Statement::StructDef { .. });
+        self.user_code = false;
+        self.compile_block(program[..user_start].iter().filter(is_code));
+        self.user_code = true;
+        self.compile_block(program[user_start..].iter().filter(is_code));
 
+        self.mark_span(None, false);
         self.program.push(Instruction::Syscall(Syscall::Halt));
 
         if stdlib {
@@ -256,30 +532,40 @@ impl Compiler {
             }
         }
 
-        for st in &program {
-            let Statement::FunctionDef { name: Tag { item: name, .. }, arguments, return_type, block } = st else { continue };
-            let mut args = vec![];
-            for (_, Tag { item: tpe, .. }) in arguments {
-                args.push(self.resolve_type(tpe)?);
-            }
-            let signature = FunctionSignature { name: name.clone(), args };
+        for (i, st) in program.iter().enumerate() {
+            let Statement::FunctionDef { name: Tag { item: name, .. }, arguments, return_type, block, end } = st else { continue };
+            self.user_code = i >= user_start;
+            // a function with unresolvable types was already reported when it was declared
+            let Ok(func) = self.declare_function(name, arguments, return_type) else { continue };
+            let signature = FunctionSignature::from(&func);
 
             self.function_addresses.insert(signature.clone(), self.program.len());
 
             self.stack.clear();
 
             self.current_function = Some(self.functions.iter().find(|x| FunctionSignature::from(*x) == signature).unwrap().clone());
-            for (Tag { item: arg_name, .. }, Tag { item: tpe, .. }) in arguments {
-                self.stack.push((CompStackI::Variable(arg_name.clone()), self.resolve_type(tpe)?));
+            for (arg_name, tpe) in &func.args {
+                self.stack.push((CompStackI::Variable(arg_name.clone()), tpe.clone()));
             }
-            if let Some(tpe) = return_type {
-                self.stack.push((CompStackI::ReturnValue, self.resolve_type(tpe)?));
+            for ((Tag { item: arg_name, loc, .. }, _), (_, tpe)) in arguments.iter().zip(&func.args) {
+                self.variable_sites.insert(arg_name.clone(), loc.clone());
+                if self.user_code {
+                    self.info.types.push((loc.clone(), tpe.clone()));
+                }
+            }
+            if let Some(tpe) = &func.return_type {
+                self.stack.push((CompStackI::ReturnValue, tpe.clone()));
             }
             self.stack.push((CompStackI::ReturnAddress, CompType::Int));
             let stack_len = self.stack.len();
 
-            for st in block {
-                self.compile_statement(st)?;
+            self.mark_span(None, false);
+            self.compile_block(block);
+            // the implicit return belongs to the closing brace, which is worth stopping at
+            self.mark_span(Some(end.loc.clone()), true);
+
+            if let Some(return_type) = &func.return_type && !Self::always_returns(block) {
+                self.errors.push(CompErr { error: CompilerError::MissingReturn { name: name.clone(), return_type: return_type.clone() }, location: end.loc.clone() });
             }
 
             if self.find_stack_item(|x| matches!(x.0, CompStackI::ReturnAddress)).is_some() {
@@ -289,33 +575,199 @@ impl Compiler {
             }
         }
 
+        if !self.errors.is_empty() {
+            let mut errors = std::mem::take(&mut self.errors);
+            errors.sort_by_key(|x| x.location.start);
+            return Err(errors);
+        }
+
         for item in &self.function_calls {
             // TODO figure out if a function can ever not have an address
             self.program[item.program_offset] = Instruction::Call(self.function_addresses[&item.function]);
         }
 
+        self.check_warnings();
+
         Ok(())
     }
 
+    /// Adds the warnings that need the whole program compiled first
+    fn check_warnings(&mut self) {
+        for var in &self.declared_variables {
+            if !self.used_variables.contains(&var.loc) {
+                self.warnings.push(CompWarning { warning: CompilerWarning::UnusedVariable { name: var.item.clone() }, location: var.loc.clone() });
+            }
+        }
+
+        for (signature, location) in &self.function_sites {
+            if !self.function_calls.iter().any(|x| &x.function == signature) {
+                self.warnings.push(CompWarning { warning: CompilerWarning::UnusedFunction { signature: signature.clone() }, location: location.clone() });
+            }
+        }
+
+        // functions run until the next one starts, the top level code comes before all of them
+        let mut starts = self.function_addresses.values().copied().collect::<Vec<_>>();
+        starts.sort();
+        let bodies = starts.iter().zip(starts.iter().skip(1).chain([&self.program.len()])).map(|(a, b)| *a..*b).collect::<Vec<_>>();
+
+        // a loop can stop or do something useful by making a syscall, directly or through
+        // any function it calls, by returning, or by breaking out
+        let mut syscalls = HashSet::new();
+        loop {
+            let before = syscalls.len();
+            for body in &bodies {
+                if self.reaches_syscall(body.clone(), &syscalls) {
+                    syscalls.insert(body.start);
+                }
+            }
+            if syscalls.len() == before { break; }
+        }
+
+        for (instructions, location) in &self.infinite_loops {
+            let leaves = self.program[instructions.clone()].iter()
+                .any(|x| matches!(x, Instruction::Return) || matches!(x, Instruction::Jmp(addr) if !instructions.contains(addr)));
+            if !leaves && !self.reaches_syscall(instructions.clone(), &syscalls) {
+                self.warnings.push(CompWarning { warning: CompilerWarning::InfiniteLoop, location: location.clone() });
+            }
+        }
+
+        self.warnings.sort_by_key(|x| x.location.start);
+    }
+
+    fn reaches_syscall(&self, instructions: Range<usize>, syscall_functions: &HashSet<usize>) -> bool {
+        self.program[instructions].iter().any(|x| match x {
+            Instruction::Syscall(_) => true,
+            Instruction::Call(addr) => syscall_functions.contains(addr),
+            _ => false
+        })
+    }
+
+    fn declare_function(&self, name: &str, arguments: &[(Tag<String>, Tag<TypeName>)], return_type: &Option<Tag<TypeName>>) -> Result<DeclaredFunction, CompErr> {
+        let mut args = vec![];
+        for (Tag { item: arg_name, .. }, Tag { item: tpe, .. }) in arguments {
+            args.push((arg_name.clone(), self.resolve_type(tpe)?));
+        }
+
+        let return_type = match return_type {
+            Some(v) => Some(self.resolve_type(v)?),
+            None => None
+        };
+
+        Ok(DeclaredFunction { name: name.to_owned(), args, return_type })
+    }
+
+    /// Compiles each statement in turn.  A statement with an error is recorded and rolled back,
+    /// then compilation continues with the next one, so later errors are found too
+    fn compile_block<'a>(&mut self, block: impl IntoIterator<Item = &'a Statement>) {
+        let mut returned = false;
+        let mut unreachable: Option<Range<usize>> = None;
+        for st in block {
+            if returned {
+                let loc = st.loc();
+                unreachable = Some(unreachable.map_or(loc.clone(), |x| x.start..loc.end));
+            }
+            returned |= Self::always_returns(std::slice::from_ref(st)) || matches!(st, Statement::Break { .. } | Statement::Continue { .. });
+
+            let stack_len = self.stack.len();
+            let program_len = self.program.len();
+            if let Err(e) = self.compile_statement(st) {
+                self.errors.push(e);
+                self.stack.truncate(stack_len);
+                self.program.truncate(program_len);
+                self.function_calls.retain(|x| x.program_offset < program_len);
+                for l in &mut self.loops {
+                    l.breaks.retain(|x| *x < program_len);
+                    l.continues.retain(|x| *x < program_len);
+                }
+            }
+        }
+        if let Some(location) = unreachable && self.user_code {
+            self.warnings.push(CompWarning { warning: CompilerWarning::UnreachableCode, location });
+        }
+    }
+
     pub fn compile_statement(&mut self, statement: &Statement) -> Result<(), CompErr> {
+        let outer = self.mark_span(Some(statement.loc()), true);
+        let result = self.compile_statement_code(statement);
+        self.mark_span(outer, false);
+        result
+    }
+
+    /// Attributes the instructions compiled from now on to a statement, giving back the one they
+    /// were attributed to before.  `begins` is whether the statement starts here
+    fn mark_span(&mut self, span: Option<Range<usize>>, begins: bool) -> Option<Range<usize>> {
+        let span = span.filter(|_| self.user_code);
+        let variables = self.stack.iter().enumerate().filter_map(|(slot, (item, tpe))| match item {
+            CompStackI::Variable(name) if name != MATCH_VALUE => Some(Local { name: name.clone(), tpe: tpe.clone(), slot }),
+            _ => None
+        }).collect();
+        let marker = SpanMarker { start: self.program.len(), span: span.clone(), begins, variables };
+        match self.spans.last_mut() {
+            Some(last) if last.start == marker.start => *last = marker,
+            _ => self.spans.push(marker)
+        }
+        std::mem::replace(&mut self.current_span, span)
+    }
+
+    /// Where each instruction in the program came from in its source, once it has compiled
+    #[allow(unused)]
+    pub fn source_map(&self, src: &str) -> SourceMap {
+        let mut spans = vec![None; self.program.len()];
+        for (i, marker) in self.spans.iter().enumerate() {
+            let end = self.spans.get(i + 1).map_or(self.program.len(), |x| x.start);
+            for item in spans.iter_mut().take(end).skip(marker.start) {
+                item.clone_from(&marker.span);
+            }
+        }
+        let lines = spans.iter().map(|x| x.as_ref().map(|x| crate::diagnostics::line_col(src, x.start).0)).collect();
+        let statements = self.spans.iter()
+            .filter(|x| x.begins && x.span.is_some() && x.start < self.program.len())
+            .map(|x| x.start)
+            .collect();
+        let scopes = self.spans.iter().map(|x| (x.start, x.variables.clone())).collect();
+        let mut functions = self.function_addresses.iter()
+            .filter_map(|(signature, address)| Some((*address, self.functions.iter().find(|x| FunctionSignature::from(*x) == *signature)?.clone())))
+            .collect::<Vec<_>>();
+        functions.sort_by_key(|x| x.0);
+        SourceMap { spans, lines, statements, scopes, functions }
+    }
+
+    fn compile_statement_code(&mut self, statement: &Statement) -> Result<(), CompErr> {
+        // a for loop's condition is recorded once its variable is declared
+        match statement {
+            Statement::ExprS(expr) | Statement::VariableDecl(_, expr) | Statement::If { condition: expr, .. } | Statement::Match { value: expr, .. }
+                | Statement::ForEach { array: expr, .. } | Statement::While { condition: expr, .. } | Statement::Return { expr: Some(expr), .. } => self.record_expression(expr),
+            Statement::Assignment { left, value } => {
+                self.record_expression(left);
+                self.record_expression(value);
+            }
+            _ => {}
+        }
+
         match statement {
             Statement::ExprS(expression) => { self.compile_expression(expression, CompStackI::Temp)?; }
-            Statement::VariableDecl(Tag { item: name, loc }, expression) => {
+            Statement::VariableDecl(Tag { item: name, loc, .. }, expression) => {
                 if self.stack.iter().any(|(value, _)| matches!(value, CompStackI::Variable(v) if v == name)) {
-                    return Err(CompErr { error: CompilerError::Redeclaration, location: loc.clone() });
+                    let previous = self.variable_sites.get(name).cloned();
+                    return Err(CompErr { error: CompilerError::Redeclaration { name: name.clone(), previous }, location: loc.clone() });
+                }
+                let tpe = self.compile_expression(expression, CompStackI::Variable(name.clone()))?;
+                self.variable_sites.insert(name.clone(), loc.clone());
+                if self.user_code {
+                    self.declared_variables.push(Tag::new(name.clone(), loc.clone()));
+                    self.info.types.push((loc.clone(), tpe));
                 }
-                self.compile_expression(expression, CompStackI::Variable(name.clone()))?;
             }
-            Statement::Assignment { left: Tag { item: left, loc: left_loc }, value } => {
+            Statement::Assignment { left: Tag { item: left, loc: left_loc, .. }, value } => {
                 match left {
-                    Expression::VarAccess(Tag { item: name, loc }) => {
+                    Expression::VarAccess(Tag { item: name, loc, .. }) => {
                         let tpe = self.compile_expression(value, CompStackI::Temp)?;
                         let Some((idx, value_tpe)) = self.find_variable(name)
                             else {
-                                return Err(CompErr { error: CompilerError::VariableNotFound, location: loc.clone() })
+                                return Err(CompErr { error: CompilerError::VariableNotFound { name: name.clone() }, location: loc.clone() })
                             };
                         if tpe != value_tpe {
-                            return Err(CompErr { error: CompilerError::TypeMismatch, location: loc.clone() });
+                            return Err(CompErr { error: CompilerError::TypeMismatch { expected: value_tpe, found: tpe }, location: value.loc.clone() });
                         }
                         self.program.push(Instruction::Set(idx - 1));
                         self.stack.pop();
@@ -326,16 +778,16 @@ impl Compiler {
                         let inner = match self.compile_expression(array, CompStackI::Temp)? {
                             CompType::Array(box v) => v,
                             CompType::String => CompType::Char,
-                            _ => return Err(CompErr { error: CompilerError::TypeMismatch, location: index.loc.clone() })
+                            found => return Err(CompErr { error: CompilerError::NotAnArray { found }, location: array.loc.clone() })
                         };
                         if inner != tpe {
-                            return Err(CompErr { error: CompilerError::TypeMismatch, location: left_loc.clone() })
+                            return Err(CompErr { error: CompilerError::TypeMismatch { expected: inner, found: tpe }, location: value.loc.clone() })
                         }
                         let array_index = self.stack.len() - 1;
-                        let CompType::Int = self.compile_expression(index, CompStackI::Temp)?
-                            else {
-                                return Err(CompErr { error: CompilerError::TypeMismatch, location: index.loc.clone() })
-                            };
+                        let index_tpe = self.compile_expression(index, CompStackI::Temp)?;
+                        if index_tpe != CompType::Int {
+                            return Err(CompErr { error: CompilerError::TypeMismatch { expected: CompType::Int, found: index_tpe }, location: index.loc.clone() })
+                        }
                         let index_index = self.stack.len() - 1;
                         // copy item
                         self.program.push(Instruction::Copy(self.stack.len() - value_index));
@@ -353,18 +805,18 @@ impl Compiler {
                         self.stack.pop();
                     }
                     Expression::PropertyAccess(box inner, name) => {
-                        let CompType::Struct(v) = self.compile_expression(inner, CompStackI::Temp)? else {
-                            return Err(CompErr { error: CompilerError::PropertyNotFound, location: left_loc.clone() })
+                        let tpe = self.compile_expression(inner, CompStackI::Temp)?;
+                        let CompType::Struct(v) = &tpe else {
+                            return Err(Self::property_not_found(&tpe, name))
                         };
                         let struct_idx = self.stack.len() - 1;
 
                         let (idx, (_, struct_tpe)) = v.fields.iter().enumerate().find(|(_, x)| x.0 == **name)
-                            .ok_or(CompErr { error: CompilerError::PropertyNotFound, location: name.loc.clone() })?
-                            .clone();
+                            .ok_or_else(|| Self::property_not_found(&tpe, name))?;
 
-                        let tpe = self.compile_expression(value, CompStackI::Temp)?;
-                        if &tpe != struct_tpe {
-                            return Err(CompErr { error: CompilerError::TypeMismatch, location: name.loc.clone() })
+                        let value_tpe = self.compile_expression(value, CompStackI::Temp)?;
+                        if &value_tpe != struct_tpe {
+                            return Err(CompErr { error: CompilerError::TypeMismatch { expected: struct_tpe.clone(), found: value_tpe }, location: value.loc.clone() })
                         }
                         self.program.push(Instruction::Copy(self.stack.len() - struct_idx));
                         self.stack.push((CompStackI::Temp, CompType::Struct(v.clone())));
@@ -377,18 +829,20 @@ impl Compiler {
                 }
             }
             Statement::If { condition, block, else_block } => {
+                if let Expression::Lit(Tag { item: Literal::BoolL(value), .. }) = condition.item && self.user_code {
+                    self.warnings.push(CompWarning { warning: CompilerWarning::ConstantCondition { value }, location: condition.loc.clone() });
+                }
                 let tpe = self.compile_expression(condition, CompStackI::Temp)?;
                 if tpe != CompType::Bool {
-                    return Err(CompErr { error: CompilerError::TypeMismatch, location: condition.loc.clone() })
+                    // the body can still be checked, so carry on
+                    self.errors.push(CompErr { error: CompilerError::TypeMismatch { expected: CompType::Bool, found: tpe.clone() }, location: condition.loc.clone() });
                 }
                 let branch_false = self.program.len();
                 self.program.push(Instruction::Brz(0));
                 self.stack.pop();
 
                 let stack_len = self.stack.len();
-                for st in block {
-                    self.compile_statement(st)?;
-                }
+                self.compile_block(block);
                 let diff = self.stack.len() - stack_len;
                 self.program.push(Instruction::Pop(diff));
                 for _ in 0..diff {
@@ -402,9 +856,7 @@ impl Compiler {
                     self.program.push(Instruction::Jmp(0));
                     self.program[branch_false] = Instruction::Brz(self.program.len());
 
-                    for st in else_b {
-                        self.compile_statement(st)?;
-                    }
+                    self.compile_block(else_b);
                     let diff = self.stack.len() - stack_len;
                     self.program.push(Instruction::Pop(diff));
                     for _ in 0..diff {
@@ -414,7 +866,47 @@ impl Compiler {
                     self.program[jump_after_else] = Instruction::Jmp(self.program.len());
                 }
             }
-            Statement::CFor { box init, condition, box increment, block } => {
+            Statement::Match { keyword: _, value, arms, default } => {
+                let tpe = self.get_type(value)?;
+                if !matches!(tpe, CompType::Int | CompType::Char | CompType::String) {
+                    return Err(CompErr { error: CompilerError::CannotMatch { found: tpe }, location: value.loc.clone() });
+                }
+                let mut seen: Vec<&Literal> = vec![];
+                for pattern in arms.iter().flat_map(|x| &x.0) {
+                    let found = self.get_type(&Expression::Lit(pattern.clone()))?;
+                    if found != tpe {
+                        return Err(CompErr { error: CompilerError::TypeMismatch { expected: tpe, found }, location: pattern.loc.clone() });
+                    }
+                    if seen.contains(&&pattern.item) && self.user_code {
+                        self.warnings.push(CompWarning { warning: CompilerWarning::UnreachableCode, location: pattern.loc.clone() });
+                    }
+                    seen.push(&pattern.item);
+                }
+
+                // becomes an if else chain comparing each pattern to the value
+                let stack_len = self.stack.len();
+                self.compile_expression(value, CompStackI::Variable(MATCH_VALUE.to_owned()))?;
+                let subject = Tag::new(Expression::VarAccess(Tag::new(MATCH_VALUE.to_owned(), value.loc.clone())), value.loc.clone());
+                let mut chain = default.as_ref().map(|x| x.1.clone()).unwrap_or_default();
+                for (patterns, block) in arms.iter().rev() {
+                    let condition = patterns.iter()
+                        .map(|x| Self::pattern_condition(&subject, x))
+                        .reduce(|a, b| {
+                            let loc = a.loc.clone();
+                            Tag::new(Expression::Math(Box::new(a), Tag::new(Op::BoolOr, loc.clone()), Box::new(b)), loc)
+                        })
+                        .unwrap();
+                    chain = vec![Statement::If { condition, block: block.clone(), else_block: Some(chain) }];
+                }
+                self.compile_block(&chain);
+
+                let diff = self.stack.len() - stack_len;
+                self.program.push(Instruction::Pop(diff));
+                for _ in 0..diff {
+                    self.stack.pop();
+                }
+            }
+            Statement::CFor { label, box init, condition, box increment, block } => {
                 let stack_len_start = self.stack.len();
                 if let Some(v) = init {
                     self.compile_statement(v)?;
@@ -423,19 +915,27 @@ impl Compiler {
                 let stack_len_cond = self.stack.len();
                 let start = self.program.len();
 
+                self.record_expression(condition);
                 let cond_tpe = self.compile_expression(condition, CompStackI::Temp)?;
                 if cond_tpe != CompType::Bool {
-                    return Err(CompErr { error: CompilerError::TypeMismatch, location: condition.loc.clone() })
+                    self.errors.push(CompErr { error: CompilerError::TypeMismatch { expected: CompType::Bool, found: cond_tpe.clone() }, location: condition.loc.clone() });
                 }
 
                 let jump_after = self.program.len();
                 self.program.push(Instruction::Brz(0));
                 self.stack.pop();
                 let condition_pop = self.stack.len() - stack_len_start;
-                
-                for st in block {
-                    self.compile_statement(st)?;
-                }
+                let stack_len_body = self.stack.len();
+
+                self.enter_loop(label, stack_len_body, stack_len_body);
+                self.compile_block(block);
+                // the body's variables are gone before the increment so continue can jump to it
+                let body_pop = self.stack.len() - stack_len_body;
+                self.program.push(Instruction::Pop(body_pop));
+                for _ in 0..body_pop { self.stack.pop(); }
+                let continue_target = self.program.len();
+                let exits = self.exit_loop();
+
                 if let Some(v) = increment {
                     self.compile_statement(v)?;
                 }
@@ -446,6 +946,8 @@ impl Compiler {
                 self.program.push(Instruction::Jmp(start));
 
                 self.program[jump_after] = Instruction::Brz(self.program.len());
+                self.patch_loop_exits(exits, self.program.len(), continue_target);
+                self.check_infinite_loop(condition, start);
 
                 self.program.push(Instruction::Pop(condition_pop));
                 //for _ in 0..condition_pop { self.stack.pop(); }
@@ -453,11 +955,11 @@ impl Compiler {
                     self.stack.pop();
                 }
             }
-            Statement::ForEach { variable, array, block } => {
+            Statement::ForEach { label, variable, array, block } => {
                 let inner = match self.compile_expression(array, CompStackI::Temp)? {
                     CompType::Array(v) => *v.clone(),
                     CompType::String => CompType::Char,
-                    _ => return Err(CompErr { error: CompilerError::TypeMismatch, location: array.loc.clone() })
+                    found => return Err(CompErr { error: CompilerError::NotAnArray { found }, location: array.loc.clone() })
                 };
 
                 self.program.push(Instruction::Copy(1));
@@ -516,19 +1018,24 @@ impl Compiler {
                 self.stack.pop();
                 self.stack.pop();
                 self.stack.push((CompStackI::Variable(variable.item.clone()), inner.clone()));
+                if self.user_code {
+                    self.info.types.push((variable.loc.clone(), inner.clone()));
+                }
 
                 // array
                 // length
                 // index
                 // variable
 
-                for st in block {
-                    self.compile_statement(st)?;
-                }
+                // the variable is popped along with the rest of the body
+                self.enter_loop(label, stack_len, stack_len);
+                self.compile_block(block);
 
                 let n = self.stack.len() - stack_len;
                 self.program.push(Instruction::Pop(n));
                 for _ in 0..n { self.stack.pop(); }
+                let continue_target = self.program.len();
+                let exits = self.exit_loop();
 
                 // array
                 // length
@@ -543,24 +1050,26 @@ impl Compiler {
                 self.program.push(Instruction::Jmp(jump_target));
 
                 self.program[branch_idx] = Instruction::Brz(self.program.len());
+                self.patch_loop_exits(exits, self.program.len(), continue_target);
             }
-            Statement::While { condition, block } => {
+            Statement::While { label, condition, block } => {
                 let stack_len_cond = self.stack.len();
                 let start = self.program.len();
 
                 let cond_tpe = self.compile_expression(condition, CompStackI::Temp)?;
                 if cond_tpe != CompType::Bool {
-                    return Err(CompErr { error: CompilerError::TypeMismatch, location: condition.loc.clone() })
+                    self.errors.push(CompErr { error: CompilerError::TypeMismatch { expected: CompType::Bool, found: cond_tpe.clone() }, location: condition.loc.clone() });
                 }
 
                 let jump_after = self.program.len();
                 self.program.push(Instruction::Brz(0));
                 self.stack.pop();
                 let condition_pop = self.stack.len() - stack_len_cond;
-                
-                for st in block {
-                    self.compile_statement(st)?;
-                }
+
+                // continuing re-evaluates the condition, which expects the stack from before it
+                self.enter_loop(label, self.stack.len(), stack_len_cond);
+                self.compile_block(block);
+                let exits = self.exit_loop();
 
                 let st_pop = self.stack.len() - stack_len_cond;
                 self.program.push(Instruction::Pop(st_pop));
@@ -568,6 +1077,8 @@ impl Compiler {
                 self.program.push(Instruction::Jmp(start));
 
                 self.program[jump_after] = Instruction::Brz(self.program.len());
+                self.patch_loop_exits(exits, self.program.len(), start);
+                self.check_infinite_loop(condition, start);
 
                 self.program.push(Instruction::Pop(condition_pop));
             }
@@ -575,8 +1086,8 @@ impl Compiler {
                 let Some(func) = self.current_function.clone() else { return Err(CompErr { error: CompilerError::NotInFunction, location: keyword.loc.clone() }); };
                 if let Some(ret) = expr {
                     let tpe = self.compile_expression(ret, CompStackI::Temp)?;
-                    if Some(tpe) != func.return_type {
-                        return Err(CompErr { error: CompilerError::TypeMismatch, location: ret.loc.clone() });
+                    if func.return_type.as_ref() != Some(&tpe) {
+                        return Err(CompErr { error: CompilerError::TypeMismatch { expected: func.return_type.unwrap_or(CompType::Void), found: tpe }, location: ret.loc.clone() });
                     }
                     let pos = self.find_stack_item(|x| matches!(x.0, CompStackI::ReturnValue)).unwrap();
                     self.program.push(Instruction::Set(pos.0 - 1));
@@ -590,9 +1101,28 @@ impl Compiler {
                 self.program.push(Instruction::Return);
                 //self.stack.pop();
             }
+            Statement::Break { keyword, label } | Statement::Continue { keyword, label } => {
+                let is_break = matches!(statement, Statement::Break { .. });
+                let found = match label {
+                    Some(label) => self.loops.iter().rposition(|x| x.label.as_ref() == Some(&label.item))
+                        .ok_or_else(|| CompErr { error: CompilerError::LabelNotFound { label: label.item.clone() }, location: label.loc.clone() })?,
+                    None => self.loops.len().checked_sub(1)
+                        .ok_or_else(|| CompErr { error: CompilerError::NotInLoop { keyword: if is_break { "break" } else { "continue" } }, location: keyword.loc.clone() })?
+                };
+                let target = &self.loops[found];
+                let stack_len = if is_break { target.break_stack_len } else { target.continue_stack_len };
+                // anything after this in the block is unreachable, so the compiler's stack stays
+                // as it is
+                self.program.push(Instruction::Pop(self.stack.len() - stack_len));
+                if is_break {
+                    self.loops[found].breaks.push(self.program.len());
+                } else {
+                    self.loops[found].continues.push(self.program.len());
+                }
+                self.program.push(Instruction::Jmp(usize::MAX));
+            }
             Statement::FunctionDef { name: Tag { loc, .. }, .. } => return Err(CompErr { error: CompilerError::FunctionsMustBeTopLevel, location: loc.clone() }),
-            // TODO: rename error?
-            Statement::StructDef { name: Tag { loc, .. }, .. } => return Err(CompErr { error: CompilerError::FunctionsMustBeTopLevel, location: loc.clone() })
+            Statement::StructDef { name: Tag { loc, .. }, .. } => return Err(CompErr { error: CompilerError::StructsMustBeTopLevel, location: loc.clone() })
         }
 
         Ok(())
@@ -602,15 +1132,15 @@ impl Compiler {
     fn get_unary_op(&self, op: Tag<UnaryOp>, inner: &CompType) -> Result<OpEvaluation, CompErr> {
         match (&*op, inner) {
             (UnaryOp::UnaryMinus, CompType::Int) => Ok(OpEvaluation { pop: 0, push: vec![], instructions: vec![Instruction::ImmediateInt(-1), Instruction::MulI], tpe: CompType::Int }),
-            (UnaryOp::UnaryMinus, CompType::Double) => Ok(OpEvaluation { pop: 0, push: vec![], instructions: vec![Instruction::ImmediateDouble(-1.0), Instruction::MulI], tpe: CompType::Double }),
+            (UnaryOp::UnaryMinus, CompType::Double) => Ok(OpEvaluation { pop: 0, push: vec![], instructions: vec![Instruction::ImmediateDouble(-1.0), Instruction::MulD], tpe: CompType::Double }),
             (UnaryOp::BitwiseNot, CompType::Int) => Ok(OpEvaluation { pop: 0, push: vec![], instructions: vec![Instruction::NotI], tpe: CompType::Int }),
             (UnaryOp::BooleanNot, CompType::Bool) => Ok(OpEvaluation { pop: 0, push: vec![], instructions: vec![Instruction::ImmediateInt(1), Instruction::XorI], tpe: CompType::Bool }),
-            _ => Err(CompErr { error: CompilerError::TypeMismatch, location: op.loc.clone() })
+            _ => Err(CompErr { error: CompilerError::InvalidUnaryOperand { op: op.item.clone(), found: inner.clone() }, location: op.loc.clone() })
         }
     }
 
     fn get_op(&self, left: &CompType, op: Tag<Op>, right: &CompType) -> Result<OpEvaluation, CompErr> {
-        let (ins, tpe) = match (left, op.item, right) {
+        let (ins, tpe) = match (left, &op.item, right) {
             (CompType::Int, Op::Plus, CompType::Int) => (Instruction::AddI, CompType::Int),
             (CompType::Int, Op::Minus, CompType::Int) => (Instruction::SubI, CompType::Int),
             (CompType::Int, Op::Times, CompType::Int) => (Instruction::MulI, CompType::Int),
@@ -650,6 +1180,7 @@ impl Compiler {
             (CompType::Int, Op::Or, CompType::Int) => (Instruction::OrI, CompType::Int),
             (CompType::Int, Op::Xor, CompType::Int) => (Instruction::XorI, CompType::Int),
 
+            // compile_expression short circuits these instead, this is only for their type
             (CompType::Bool, Op::BoolAnd, CompType::Bool) => (Instruction::AndI, CompType::Bool),
             (CompType::Bool, Op::BoolOr, CompType::Bool) => (Instruction::OrI, CompType::Bool),
             (CompType::Bool, Op::Xor, CompType::Bool) => (Instruction::XorI, CompType::Bool),
@@ -696,13 +1227,40 @@ impl Compiler {
             }
 
 
-            _ => return Err(CompErr { location: op.loc, error: CompilerError::TypeMismatch })
+            _ => return Err(CompErr { location: op.loc, error: CompilerError::InvalidOperands { op: op.item.clone(), left: left.clone(), right: right.clone() } })
         };
 
         Ok(OpEvaluation { pop: 0, push: vec![], instructions: vec![ins], tpe })
     }
 
+    /// Type checks the expression without compiling it
     fn get_type(&self, expr: &Expression) -> Result<CompType, CompErr> {
+        self.check_expression(expr).tpe
+    }
+
+    /// The subexpressions of an expression, in the order they're type checked
+    fn parts(expr: &Expression) -> Vec<&Tag<Expression>> {
+        match expr {
+            Expression::Lit(_) | Expression::VarAccess(_) | Expression::NewStruct(_) => vec![],
+            Expression::Math(box left, _, box right) | Expression::ArrayAccess { array: box left, index: box right } => vec![left, right],
+            Expression::UnaryOperation(_, box inner) | Expression::PropertyAccess(box inner, _) | Expression::NewArray(_, box inner) => vec![inner],
+            Expression::FunctionCall { args, .. } => args.iter().collect(),
+            Expression::Ternary { box condition, box if_true, box if_false } => vec![condition, if_true, if_false]
+        }
+    }
+
+    /// Type checks the expression and everything in it, each part only once.  Every part is
+    /// checked even when an earlier one fails, so editor tooling still gets the types of the
+    /// parts that are fine
+    fn check_expression(&self, expr: &Expression) -> Typed {
+        let parts = Self::parts(expr).into_iter().map(|x| self.check_expression(x)).collect::<Vec<_>>();
+        let tpe = self.expression_type(expr, &parts);
+        Typed { tpe, parts }
+    }
+
+    // the type of the expression given the types of its parts, the errors in which come first
+    fn expression_type(&self, expr: &Expression, parts: &[Typed]) -> Result<CompType, CompErr> {
+        let part = |i: usize| parts[i].tpe.clone();
         Ok(match expr {
             Expression::Lit(Tag { item: lit, .. }) => match lit {
                 Literal::IntL(_) => CompType::Int,
@@ -711,73 +1269,155 @@ impl Compiler {
                 Literal::StringL(_) => CompType::String,
                 Literal::CharL(_) => CompType::Char,
             },
-            Expression::Math(box left, op, box right) => {
-                let l = self.get_type(left)?;
-                let r = self.get_type(right)?;
+            Expression::Math(_, op, _) => {
+                let l = part(0)?;
+                let r = part(1)?;
                 self.get_op(&l, op.clone(), &r)?.tpe
             }
-            Expression::UnaryOperation(op, inner) => self.get_unary_op(op.clone(), &self.get_type(inner)?)?.tpe,
-            Expression::FunctionCall { name: Tag { item: name, loc }, args } => {
+            Expression::UnaryOperation(op, _) => self.get_unary_op(op.clone(), &part(0)?)?.tpe,
+            Expression::FunctionCall { name: Tag { item: name, loc, .. }, .. } => {
                 let signature = FunctionSignature {
                     name: name.clone(),
-                    args: args.iter().map(|x| self.get_type(x)).collect::<Result<_, _>>()?
+                    args: parts.iter().map(|x| x.tpe.clone()).collect::<Result<_, _>>()?
                 };
                 if let Some(v) = self.functions.iter().find(|x| FunctionSignature::from(*x) == signature) {
-                    v.return_type.as_ref().map(|x| x.clone()).unwrap_or(CompType::Void)
+                    v.return_type.clone().unwrap_or(CompType::Void)
                 } else {
-                    return Err(CompErr { error: CompilerError::FunctionNotFound, location: loc.clone() })
+                    return Err(self.function_not_found(signature, loc.clone()))
                 }
             }
-            Expression::PropertyAccess(box expression, Tag { item: name, loc }) => {
-                if matches!(self.get_type(expression)?, CompType::Array(_) | CompType::String) && name == "size" {
+            Expression::PropertyAccess(_, name) => {
+                let tpe = part(0)?;
+                if matches!(tpe, CompType::Array(_) | CompType::String) && name.item == "size" {
                     CompType::Int
-                } else if let CompType::Struct(CompStruct { fields, .. }) = self.get_type(expression)? {
-                    fields.iter().find(|x| &x.0 == name)
-                        .ok_or(CompErr { error: CompilerError::PropertyNotFound, location: loc.clone() })?
+                } else if let CompType::Struct(CompStruct { fields, .. }) = &tpe {
+                    fields.iter().find(|x| x.0 == name.item)
+                        .ok_or_else(|| Self::property_not_found(&tpe, name))?
                         .1.clone()
                 } else {
-                    return Err(CompErr { error: CompilerError::PropertyNotFound, location: loc.clone() })
+                    return Err(Self::property_not_found(&tpe, name))
+                }
+            }
+            Expression::Ternary { condition, if_true, if_false } => {
+                let cond_tpe = part(0)?;
+                if cond_tpe != CompType::Bool {
+                    return Err(CompErr { error: CompilerError::TypeMismatch { expected: CompType::Bool, found: cond_tpe }, location: condition.loc.clone() });
+                }
+                let tpe_if_true = part(1)?;
+                let tpe_if_false = part(2)?;
+                if tpe_if_true != tpe_if_false {
+                    return Err(CompErr {
+                        error: CompilerError::BranchMismatch { if_true: tpe_if_true, if_false: tpe_if_false, if_true_location: if_true.loc.clone() },
+                        location: if_false.loc.clone()
+                    });
                 }
+                tpe_if_true
             }
-            Expression::Ternary { if_true, .. } => self.get_type(if_true)?,
-            Expression::ArrayAccess { array, .. } => {
-                let tpe = self.get_type(array)?;
-                match tpe {
-                    CompType::Array(box v) => v.clone(),
+            Expression::ArrayAccess { array, index } => {
+                let inner = match part(0)? {
+                    CompType::Array(box v) => v,
                     CompType::String => CompType::Char,
-                    _ => return Err(CompErr { error: CompilerError::TypeMismatch, location: array.loc.clone() })
+                    found => return Err(CompErr { error: CompilerError::NotAnArray { found }, location: array.loc.clone() })
+                };
+                let index_tpe = part(1)?;
+                if index_tpe != CompType::Int {
+                    return Err(CompErr { error: CompilerError::TypeMismatch { expected: CompType::Int, found: index_tpe }, location: index.loc.clone() })
                 }
+                inner
             }
             Expression::VarAccess(tag) => if let Some((_, t)) = self.find_variable(&tag.item) { t } else {
-                return Err(CompErr { error: CompilerError::VariableNotFound, location: tag.loc.clone() })
+                return Err(CompErr { error: CompilerError::VariableNotFound { name: tag.item.clone() }, location: tag.loc.clone() })
+            }
+            Expression::NewArray(tag, length) => {
+                let inner = self.resolve_type(&tag.item)?;
+                let length_tpe = part(0)?;
+                if length_tpe != CompType::Int {
+                    return Err(CompErr { error: CompilerError::TypeMismatch { expected: CompType::Int, found: length_tpe }, location: length.loc.clone() })
+                }
+                CompType::Array(Box::new(inner))
             }
-            Expression::NewArray(tag, _) => CompType::Array(Box::new(self.resolve_type(&tag.item)?)),
             Expression::NewStruct(name) => {
                 CompType::Struct(self.structs.iter().find(|x| x.name == **name)
-                    .ok_or(CompErr { error: CompilerError::TypeNotFound, location: name.loc.clone() })?
+                    .ok_or(CompErr { error: CompilerError::TypeNotFound { name: name.item.clone() }, location: name.loc.clone() })?
                     .clone())
             }
         })
     }
 
+    fn function_not_found(&self, signature: FunctionSignature, location: Range<usize>) -> CompErr {
+        let candidates = self.functions.iter()
+            .filter(|x| x.name == signature.name)
+            .map(FunctionSignature::from)
+            .collect::<Vec<_>>();
+        if let [only] = candidates.as_slice() && only.args.len() != signature.args.len() {
+            return CompErr { error: CompilerError::WrongNumberOfArguments { expected: only.args.len(), found: signature.args.len() }, location };
+        }
+        CompErr { error: CompilerError::FunctionNotFound { signature, candidates }, location }
+    }
+
+    fn property_not_found(tpe: &CompType, name: &Tag<String>) -> CompErr {
+        let fields = match tpe {
+            CompType::Struct(v) => v.fields.iter().map(|x| x.0.clone()).collect(),
+            CompType::Array(_) | CompType::String => vec!["size".to_owned()],
+            _ => vec![]
+        };
+        CompErr { error: CompilerError::PropertyNotFound { name: name.item.clone(), tpe: tpe.clone(), fields }, location: name.loc.clone() }
+    }
+
+    /// Notes the types of the expression and everything in it, along with the function each
+    /// call resolves to.  Parts that don't type check are skipped
+    fn record_expression(&mut self, expr: &Tag<Expression>) {
+        if !self.user_code {
+            return;
+        }
+        let typed = self.check_expression(expr);
+        self.record_typed(expr, &typed);
+    }
+
+    fn record_typed(&mut self, expr: &Tag<Expression>, typed: &Typed) {
+        for (part, typed) in Self::parts(expr).into_iter().zip(&typed.parts) {
+            self.record_typed(part, typed);
+        }
+        if let Expression::FunctionCall { name, .. } = &expr.item
+            && let Ok(args) = typed.parts.iter().map(|x| x.tpe.clone()).collect() {
+            let signature = FunctionSignature { name: name.item.clone(), args };
+            if self.functions.iter().any(|x| FunctionSignature::from(x) == signature) {
+                self.info.calls.push((name.loc.clone(), signature));
+            }
+        }
+        if !expr.loc.is_empty() && let Ok(tpe) = &typed.tpe {
+            self.info.types.push((expr.loc.clone(), tpe.clone()));
+        }
+    }
+
+    /// Every function that can be called, the predefined ones included
+    #[allow(unused)]
+    pub fn functions(&self) -> &[DeclaredFunction] {
+        &self.functions
+    }
+
+    /// Where a function in the user's code was declared
+    #[allow(unused)]
+    pub fn function_site(&self, signature: &FunctionSignature) -> Option<Range<usize>> {
+        self.function_sites.get(signature).cloned()
+    }
+
     /// Compiles the given expression, leaves the result on the top of the stack with the given
     /// item type
     pub fn compile_expression(&mut self, expr: &Expression, out: CompStackI) -> Result<CompType, CompErr> {
+        let typed = self.check_expression(expr);
+        self.compile_typed(expr, &typed, out)
+    }
+
+    // only generates code for expressions that type check, so nothing here needs to check again
+    fn compile_typed(&mut self, expr: &Expression, typed: &Typed, out: CompStackI) -> Result<CompType, CompErr> {
+        let tpe = typed.tpe.clone()?;
         match expr {
             Expression::Lit(Tag { item, .. }) => {
-                let tpe = match item {
-                    Literal::IntL(v) => {
-                        self.program.push(Instruction::ImmediateInt(*v));
-                        CompType::Int
-                    }
-                    Literal::DoubleL(v) => {
-                        self.program.push(Instruction::ImmediateDouble(*v));
-                        CompType::Double
-                    }
-                    Literal::BoolL(v) => {
-                        self.program.push(Instruction::ImmediateInt(if *v { 1 } else { 0 }));
-                        CompType::Bool
-                    }
+                match item {
+                    Literal::IntL(v) => self.program.push(Instruction::ImmediateInt(*v)),
+                    Literal::DoubleL(v) => self.program.push(Instruction::ImmediateDouble(*v)),
+                    Literal::BoolL(v) => self.program.push(Instruction::ImmediateInt(if *v { 1 } else { 0 })),
                     Literal::StringL(v) => {
                         let chars = v.chars().collect::<Vec<_>>();
                         self.program.push(Instruction::ImmediateInt(chars.len() as i32));
@@ -789,22 +1429,38 @@ impl Compiler {
                             self.program.push(Instruction::Copy(3));
                             self.program.push(Instruction::SetA);
                         }
-
-                        CompType::String
-                    }
-                    Literal::CharL(v) => {
-                        self.program.push(Instruction::ImmediateInt(u32::from(*v) as i32));
-                        CompType::Char
                     }
-
+                    Literal::CharL(v) => self.program.push(Instruction::ImmediateInt(u32::from(*v) as i32))
                 };
                 self.stack.push((out, tpe.clone()));
-                Ok(tpe)
+            }
+            Expression::Math(left, op @ Tag { item: Op::BoolAnd | Op::BoolOr, .. }, right) => {
+                // the left side is the result unless the right one is needed, so it goes in the
+                // output slot and the right side is only evaluated after a branch
+                self.compile_typed(left, &typed.parts[0], out)?;
+                let stack_len = self.stack.len();
+                self.program.push(Instruction::Copy(1));
+                let branch_to_end = self.program.len();
+                self.program.push(Instruction::Brz(0));
+
+                self.compile_typed(right, &typed.parts[1], CompStackI::Temp)?;
+                let offset = self.stack.len() - stack_len;
+                self.program.push(Instruction::Set(offset));
+                self.stack.pop();
+                self.program.push(Instruction::Pop(self.stack.len() - stack_len));
+                for _ in 0..(self.stack.len() - stack_len) {
+                    self.stack.pop();
+                }
+
+                self.program[branch_to_end] = match op.item {
+                    Op::BoolAnd => Instruction::Brz(self.program.len()),
+                    _ => Instruction::Brnz(self.program.len())
+                };
             }
             Expression::Math(left, op, right) => {
-                let v2 = self.compile_expression(right, CompStackI::Temp)?;
+                let v2 = self.compile_typed(right, &typed.parts[1], CompStackI::Temp)?;
                 let pos = self.stack.len() - 1;
-                let v1 = self.compile_expression(left, CompStackI::Temp)?;
+                let v1 = self.compile_typed(left, &typed.parts[0], CompStackI::Temp)?;
                 self.program.push(Instruction::Copy(self.stack.len() - pos));
                 self.stack.push((CompStackI::Temp, v2.clone()));
 
@@ -816,55 +1472,38 @@ impl Compiler {
                 for _ in 0..res.pop { self.stack.pop(); }
                 self.stack.extend(res.push);
 
-                self.stack.push((out, res.tpe.clone()));
-
-                Ok(res.tpe)
+                self.stack.push((out, tpe.clone()));
             }
             Expression::UnaryOperation(op, inner) => {
-                let v = self.compile_expression(inner, CompStackI::Temp)?;
+                let v = self.compile_typed(inner, &typed.parts[0], CompStackI::Temp)?;
                 self.stack.pop();
                 let res = self.get_unary_op(op.clone(), &v)?;
                 self.program.extend(res.instructions);
                 for _ in 0..res.pop { self.stack.pop(); }
                 self.stack.extend(res.push);
                 
-                self.stack.push((out, res.tpe.clone()));
-
-                Ok(res.tpe)
+                self.stack.push((out, tpe.clone()));
             }
             Expression::FunctionCall { name, args } => {
                 let signature = FunctionSignature {
                     name: name.item.clone(),
-                    args: args.iter().map(|x| self.get_type(x)).collect::<Result<Vec<_>, _>>()?
+                    args: typed.parts.iter().map(|x| x.tpe.clone()).collect::<Result<Vec<_>, _>>()?
                 };
-                let Some(found) = self.functions.iter().find(|x| FunctionSignature::from(*x) == signature)
-                    else {
-                        println!("looking for signature {signature:?} {args:?}");
-                        return Err(CompErr { error: CompilerError::FunctionNotFound, location: name.loc.clone() }) };
-                let found = found.clone();
-                if found.args.len() != args.len() {
-                    return Err(CompErr { error: CompilerError::WrongNumberOfArguments, location: name.loc.clone() })
-                }
+                let found = self.functions.iter().find(|x| FunctionSignature::from(*x) == signature).unwrap().clone();
                 let mut arg_positions = vec![];
                 for (_, tpe) in &found.args {
                     self.program.push(Instruction::ImmediateInt(0));
                     self.stack.push((CompStackI::Temp, tpe.clone()));
                     arg_positions.push(self.stack.len());
                 }
-                let return_type = if let Some(ret) = &found.return_type {
+                if found.return_type.is_some() {
                     self.program.push(Instruction::ImmediateInt(0));
-                    self.stack.push((out, ret.clone()));
-                    ret.clone()
-                } else {
-                    CompType::Void
-                };
+                    self.stack.push((out, tpe.clone()));
+                }
                 let stack_len = self.stack.len();
 
                 for (i, arg) in args.iter().enumerate() {
-                    let tpe = self.compile_expression(arg, CompStackI::Temp)?;
-                    if tpe != found.args[i].1 {
-                        return Err(CompErr { error: CompilerError::TypeMismatch, location: arg.loc.clone() });
-                    }
+                    self.compile_typed(arg, &typed.parts[i], CompStackI::Temp)?;
                     self.program.push(Instruction::Set(self.stack.len() - arg_positions[i]));
                     self.stack.pop();
                 }
@@ -874,47 +1513,33 @@ impl Compiler {
                 }
                 self.function_calls.push(FunctionCallToFix { program_offset: self.program.len(), function: signature });
                 self.program.push(Instruction::Call(usize::MAX));
-
-                Ok(return_type)
             }
-            Expression::PropertyAccess(box expression, Tag { item: name, loc }) => {
-                let obj = self.compile_expression(expression, CompStackI::Temp)?;
-                match (obj, name.as_str()) {
-                    (CompType::Array(_) | CompType::String, "size") => {
-                        self.program.push(Instruction::LenA);
-                        self.stack.pop();
-                        self.stack.push((out, CompType::Int));
-                        return Ok(CompType::Int)
-                    }
-                    (CompType::Struct(CompStruct { fields, .. }), _) => {
-                        let (idx, (_, tpe)) = fields.iter().enumerate().find(|x| &x.1.0 == name)
-                            .ok_or(CompErr { error: CompilerError::PropertyNotFound, location: loc.clone() })?;
+            Expression::PropertyAccess(box expression, name) => {
+                let obj = self.compile_typed(expression, &typed.parts[0], CompStackI::Temp)?;
+                match &obj {
+                    CompType::Struct(CompStruct { fields, .. }) => {
+                        let idx = fields.iter().position(|x| x.0 == name.item).unwrap();
                         self.program.push(Instruction::GetS(idx));
-                        self.stack.pop();
-                        self.stack.push((out, tpe.clone()));
-                        return Ok(tpe.clone())
                     }
-
-                    _ => return Err(CompErr { error: CompilerError::PropertyNotFound, location: loc.clone() })
+                    // size of an array or string
+                    _ => self.program.push(Instruction::LenA)
                 }
+                self.stack.pop();
+                self.stack.push((out, tpe.clone()));
             }
             Expression::Ternary { condition, if_true, if_false } => {
-                self.stack.push((out, CompType::Int));
+                self.stack.push((out, tpe.clone()));
                 self.program.push(Instruction::ImmediateInt(-1));
                 let stack_len = self.stack.len();
-                let cond_tpe = self.compile_expression(condition, CompStackI::Temp)?;
-                if cond_tpe != CompType::Bool {
-                    return Err(CompErr { error: CompilerError::TypeMismatch, location: condition.loc.clone() });
-                }
+                self.compile_typed(condition, &typed.parts[0], CompStackI::Temp)?;
                 let branch_to_false = self.program.len();
                 self.program.push(Instruction::Brz(0));
                 self.stack.pop();
 
-                let tpe_if_true = self.compile_expression(if_true, CompStackI::Temp)?;
+                self.compile_typed(if_true, &typed.parts[1], CompStackI::Temp)?;
                 let offset = self.stack.len() - stack_len;
                 self.program.push(Instruction::Set(offset));
                 self.stack.pop();
-                self.stack[stack_len - 1].1 = tpe_if_true.clone();
                 self.program.push(Instruction::Pop(self.stack.len() - stack_len));
                 for _ in 0..(self.stack.len() - stack_len) {
                     self.stack.pop();
@@ -923,7 +1548,7 @@ impl Compiler {
                 self.program.push(Instruction::Jmp(0));
 
                 self.program[branch_to_false] = Instruction::Brz(self.program.len());
-                let tpe_if_false = self.compile_expression(if_false, CompStackI::Temp)?;
+                self.compile_typed(if_false, &typed.parts[2], CompStackI::Temp)?;
                 let offset = self.stack.len() - stack_len;
                 self.program.push(Instruction::Set(offset));
                 self.stack.pop();
@@ -932,24 +1557,11 @@ impl Compiler {
                     self.stack.pop();
                 }
                 self.program[jump_to_after] = Instruction::Jmp(self.program.len());
-                if tpe_if_true != tpe_if_false {
-                    return Err(CompErr { error: CompilerError::TypeMismatch, location: if_false.loc.clone() });
-                }
-
-                Ok(tpe_if_false)
             }
             Expression::ArrayAccess { array, index } => {
-                let inner = match self.compile_expression(array, CompStackI::Temp)? {
-                    CompType::Array(box inner) => inner.clone(),
-                    CompType::String => CompType::Char,
-                    _ => return Err(CompErr { error: CompilerError::TypeMismatch, location: array.loc.clone() })
-                };
+                self.compile_typed(array, &typed.parts[0], CompStackI::Temp)?;
                 let array_addr = self.stack.len() - 1;
-                let CompType::Int = self.compile_expression(index, CompStackI::Temp)?
-                    else {
-                        return Err(CompErr { error: CompilerError::TypeMismatch, location: index.loc.clone() })
-                    };
-
+                self.compile_typed(index, &typed.parts[1], CompStackI::Temp)?;
 
                 self.program.push(Instruction::Copy(self.stack.len() - array_addr));
                 self.stack.push((CompStackI::Temp, CompType::Void));
@@ -957,47 +1569,123 @@ impl Compiler {
                 self.stack.pop();
                 self.stack.pop();
 
-                self.stack.push((out, inner.clone()));
-
-                Ok(inner)
+                self.stack.push((out, tpe.clone()));
             }
-            Expression::VarAccess(Tag { item: name, loc }) => {
-                let Some((idx, tpe)) = self.find_variable(name)
-                    else {
-                        return Err(CompErr { error: CompilerError::VariableNotFound, location: loc.clone() })
-                    };
+            Expression::VarAccess(Tag { item: name, .. }) => {
+                let (idx, _) = self.find_variable(name).unwrap();
 
                 self.program.push(Instruction::Copy(idx));
                 self.stack.push((out, tpe.clone()));
-
-                Ok(tpe)
-            }
-            Expression::NewArray(tpe, box length) => {
-                let inner_type = self.resolve_type(tpe)?;
-                if self.compile_expression(length, CompStackI::Temp)? != CompType::Int {
-                    return Err(CompErr { error: CompilerError::TypeMismatch, location: tpe.loc.clone() })
+                if let Some(site) = self.variable_sites.get(name) && self.user_code {
+                    self.used_variables.insert(site.clone());
                 }
-                self.program.push(Instruction::AllocA(self.runtime_type(&inner_type)));
+            }
+            Expression::NewArray(_, box length) => {
+                let CompType::Array(inner_type) = &tpe else { unreachable!() };
+                self.compile_typed(length, &typed.parts[0], CompStackI::Temp)?;
+                self.program.push(Instruction::AllocA(self.runtime_type(inner_type)));
                 self.stack.pop();
-                self.stack.push((out, CompType::Array(Box::new(inner_type.clone()))));
-                Ok(CompType::Array(Box::new(inner_type)))
+                self.stack.push((out, tpe.clone()));
             }
-            Expression::NewStruct(tpe) => {
-                let CompType::Struct(v) = self.resolve_type(&TypeName::Struct(tpe.to_owned()))? else {
-                    return Err(CompErr { error: CompilerError::TypeMismatch, location: tpe.loc.clone() })
-                };
+            Expression::NewStruct(_) => {
+                let CompType::Struct(v) = &tpe else { unreachable!() };
                 self.program.push(Instruction::AllocS(Tpe::Struct(v.fields.iter().map(|x| self.runtime_type(&x.1)).collect())));
-                self.stack.push((out, CompType::Struct(v.clone())));
-                Ok(CompType::Struct(v.clone()))
+                self.stack.push((out, tpe.clone()));
             }
         }
-    }
 
+        Ok(tpe)
+    }
 
     fn find_stack_item<F: Fn(&(CompStackI, CompType)) -> bool>(&self, cond: F) -> Option<(usize, CompType)> {
         self.stack.iter().rev().zip(1..).find_map(|(x, i)| if cond(x) { Some((i, x.1.clone())) } else { None })
     }
 
+    /// An expression that's true if the value matches the pattern
+    fn pattern_condition(subject: &Tag<Expression>, pattern: &Tag<Literal>) -> Tag<Expression> {
+        // the comparison isn't in the source, so it has an empty location, which also keeps it
+        // out of the way of editor tooling
+        let loc = pattern.loc.start..pattern.loc.start;
+        let compare = |left, op, right| Tag::new(Expression::Math(Box::new(left), Tag::new(op, loc.clone()), Box::new(right)), loc.clone());
+        let Literal::StringL(string) = &pattern.item else {
+            return compare(subject.clone(), Op::Eq, Tag::new(Expression::Lit(pattern.clone()), pattern.loc.clone()));
+        };
+        // strings are compared a character at a time once their sizes are known to match, in
+        // line, so there's no function for a spell's own to clash with
+        let lit = |item| Tag::new(Expression::Lit(Tag::new(item, loc.clone())), loc.clone());
+        let size = Tag::new(Expression::PropertyAccess(Box::new(subject.clone()), Tag::new("size".to_owned(), loc.clone())), loc.clone());
+        let chars = string.chars().enumerate().map(|(i, c)| compare(
+            Tag::new(Expression::ArrayAccess { array: Box::new(subject.clone()), index: Box::new(lit(Literal::IntL(i as i32))) }, loc.clone()),
+            Op::Eq,
+            lit(Literal::CharL(c))
+        ));
+        std::iter::once(compare(size, Op::Eq, lit(Literal::IntL(string.chars().count() as i32))))
+            .chain(chars)
+            .reduce(|a, b| compare(a, Op::BoolAnd, b))
+            .unwrap()
+    }
+
+    /// Whether every way through the block ends in a `return`, rather than reaching the end
+    fn always_returns(block: &[Statement]) -> bool {
+        block.iter().any(|st| match st {
+            Statement::Return { .. } => true,
+            Statement::If { block, else_block: Some(else_block), .. } => Self::always_returns(block) && Self::always_returns(else_block),
+            Statement::Match { arms, default: Some((_, default)), .. } => arms.iter().all(|x| Self::always_returns(&x.1)) && Self::always_returns(default),
+            // never finishing is as good as returning
+            Statement::While { label, condition, block } | Statement::CFor { label, condition, block, .. } =>
+                matches!(condition.item, Expression::Lit(Tag { item: Literal::BoolL(true), .. }))
+                    && !Self::breaks_out(block, label.as_ref().map(|x| x.item.as_str()), true),
+            _ => false
+        })
+    }
+
+    /// Whether the block of a loop with the given label contains a `break` out of it.
+    /// `innermost` is false inside nested loops, where only labelled breaks count
+    fn breaks_out(block: &[Statement], label: Option<&str>, innermost: bool) -> bool {
+        block.iter().any(|st| match st {
+            Statement::Break { label: None, .. } => innermost,
+            Statement::Break { label: Some(l), .. } => Some(l.item.as_str()) == label,
+            Statement::If { block, else_block, .. } =>
+                Self::breaks_out(block, label, innermost) || else_block.as_ref().is_some_and(|x| Self::breaks_out(x, label, innermost)),
+            Statement::Match { arms, default, .. } =>
+                arms.iter().any(|x| Self::breaks_out(&x.1, label, innermost)) || default.as_ref().is_some_and(|x| Self::breaks_out(&x.1, label, innermost)),
+            Statement::While { block, .. } | Statement::CFor { block, .. } | Statement::ForEach { block, .. } => Self::breaks_out(block, label, false),
+            _ => false
+        })
+    }
+
+    /// Starts a loop whose body is about to be compiled
+    fn enter_loop(&mut self, label: &Option<Tag<String>>, break_stack_len: usize, continue_stack_len: usize) {
+        self.loops.push(LoopContext {
+            label: label.as_ref().map(|x| x.item.clone()),
+            break_stack_len,
+            continue_stack_len,
+            breaks: vec![],
+            continues: vec![]
+        });
+    }
+
+    fn exit_loop(&mut self) -> LoopContext {
+        self.loops.pop().unwrap()
+    }
+
+    fn patch_loop_exits(&mut self, exits: LoopContext, break_target: usize, continue_target: usize) {
+        for i in exits.breaks {
+            self.program[i] = Instruction::Jmp(break_target);
+        }
+        for i in exits.continues {
+            self.program[i] = Instruction::Jmp(continue_target);
+        }
+    }
+
+    /// Remembers a loop from start to the end of the program if its condition is `true`, to
+    /// check once the functions it calls are known
+    fn check_infinite_loop(&mut self, condition: &Tag<Expression>, start: usize) {
+        if let Expression::Lit(Tag { item: Literal::BoolL(true), .. }) = condition.item && self.user_code {
+            self.infinite_loops.push((start..self.program.len(), condition.loc.clone()));
+        }
+    }
+
     fn find_variable(&self, name: &str) -> Option<(usize, CompType)> {
         self.find_stack_item(|x| if let CompStackI::Variable(n) = &x.0 && n == name { true } else { false })
     }
@@ -1006,8 +1694,8 @@ impl Compiler {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::parser;
-    use crate::stack_machine::{ExecutionException, StackItem, Syscall, VM};
+    use crate::{parser, rng::Rng};
+    use crate::stack_machine::{ExecutionException, StackItem, Step, Syscall, VM};
 
     macro_rules! test_math {
         ($name:ident: $($program:expr => $result:pat $(if $condition:expr)?),+ $(,)?) => {
@@ -1054,6 +1742,507 @@ mod tests {
         panic!("never exited");
     }
 
+    /// Compiles and runs a whole program, returning what it printed
+    fn run_program(program: &str) -> String {
+        let parsed = parser::parse_program(program).expect("parse error");
+        let mut compiler = Compiler::new();
+        compiler.compile_program(&parsed).expect("compile error");
+        let mut vm = VM::new(compiler.program);
+        let mut out = String::new();
+        for _ in 0..100000 {
+            match vm.tick_nohandle() {
+                Ok(()) => {}
+                Err(ExecutionException::SyscallException(Syscall::PrintChar)) => {
+                    let c: i32 = vm.stack.pop().unwrap().try_into().unwrap();
+                    out.push(char::from_u32(c as u32).unwrap());
+                }
+                Err(ExecutionException::SyscallException(Syscall::Halt)) => return out,
+                Err(e) => panic!("{e:?} after printing {out:?}")
+            }
+        }
+        panic!("never halted, printed {out:?}");
+    }
+
+    #[test]
+    fn test_break_continue() {
+        assert_eq!(run_program("var i = 0\nwhile true {\n    var j = i * 2\n    if j > 6 { break }\n    print(j)\n    i = i + 1\n}"), "0246");
+        assert_eq!(run_program("for (var i = 0; i < 6; i = i + 1) {\n    var x = i\n    if x % 2 == 0 { continue }\n    print(x)\n}"), "135");
+        assert_eq!(run_program("for (var i = 0; i < 6; i = i + 1) {\n    var x = i\n    if x == 3 { break }\n    print(x)\n}\nprint(9)"), "0129");
+        assert_eq!(run_program("for c in \"abcdef\" {\n    var d = c\n    if d == 'c' { continue }\n    if d == 'e' { break }\n    print(d)\n}"), "abd");
+        assert_eq!(run_program("var i = 0\nwhile i < 5 {\n    i = i + 1\n    if i == 2 { continue }\n    print(i)\n}"), "1345");
+    }
+
+    #[test]
+    fn test_source_map() {
+        let program = "fun get(a: int[], i: int) -> int {\n    return a[i]\n}\nvar a = new int[2]\nfor (var i = 0; i < 3; i = i + 1) {\n    println(get(a, i))\n}";
+        let mut compiler = Compiler::new();
+        compiler.compile_program(&parser::parse_program(program).unwrap()).unwrap();
+        let map = compiler.source_map(program);
+        assert_eq!(map.spans.len(), compiler.program.len());
+        assert_eq!(map.function(0).map(|x| x.name.as_str()), None);
+        let (address, function) = map.functions.iter().find(|x| x.1.name == "get").unwrap();
+        assert_eq!(map.function(*address).unwrap().to_string(), function.to_string());
+        // the built-in functions and the stdlib aren't in the user's source
+        let (address, _) = map.functions.iter().find(|x| x.1.name == "putc").unwrap();
+        assert_eq!(map.span(*address), None);
+
+        let mut vm = VM::new(compiler.program);
+        vm.source_map = Some(map);
+        let error = loop {
+            match vm.tick() {
+                Ok(()) | Err(ExecutionException::SyscallException(_)) => {}
+                Err(e) => break e
+            }
+        };
+        assert_eq!(error, ExecutionException::ArrayIndexOutOfBounds);
+        assert_eq!(&program[vm.current_span().unwrap()], "return a[i]");
+        assert_eq!(vm.current_function().unwrap().name, "get");
+    }
+
+    #[test]
+    fn test_stack_trace() {
+        let program = "fun inner(a: int[]) -> int {\n    return a[5]\n}\nfun outer(a: int[]) -> int {\n    var x = inner(a)\n    return x\n}\nprintln(outer(new int[1]))";
+        let mut compiler = Compiler::new();
+        compiler.compile_program(&parser::parse_program(program).unwrap()).unwrap();
+        let mut vm = VM::new(compiler.program.clone());
+        vm.source_map = Some(compiler.source_map(program));
+        while vm.tick().is_ok() {}
+        let trace = vm.stack_trace().into_iter()
+            .map(|x| (x.function.map(|x| x.name), &program[x.span.unwrap()]))
+            .collect::<Vec<_>>();
+        assert_eq!(trace, vec![
+            (Some("inner".to_owned()), "return a[5]"),
+            (Some("outer".to_owned()), "x = inner(a)"),
+            (None, "println(outer(new int[1]))")
+        ]);
+    }
+
+    #[test]
+    fn test_debugger() {
+        let program = "struct P { q: int, tag: string }\nfun twice(p: P) -> int {\n    var n = p.q * 2\n    return n\n}\nvar p = new P\np.tag = \"hi\"\nfor c in \"ab\" {\n    p.q = p.q + twice(p)\n}\nvar xs = new double[2]\nprintln(p.q)";
+        let mut compiler = Compiler::new();
+        compiler.compile_program(&parser::parse_program(program).unwrap()).unwrap();
+        let mut vm = VM::new(compiler.program.clone());
+        vm.source_map = Some(compiler.source_map(program));
+        let run = |vm: &mut VM| loop {
+            match vm.tick() {
+                Ok(()) => {}
+                Err(ExecutionException::Paused) => return vm.current_line(),
+                Err(_) => return None
+            }
+        };
+
+        assert!(!vm.set_breakpoint(1));
+        assert!(vm.set_breakpoint(9));
+        assert_eq!(vm.breakpoints(), vec![9]);
+        assert_eq!(run(&mut vm), Some(9));
+        vm.step(Step::Into);
+        assert_eq!(run(&mut vm), Some(3));
+        let variables = vm.variables(0).unwrap();
+        assert_eq!(variables.iter().map(|x| (x.name.as_str(), x.value.as_str())).collect::<Vec<_>>(), vec![("p", "P { q: 0, tag: \"hi\" }")]);
+        let outer = vm.variables(1).unwrap().into_iter().map(|x| x.name).collect::<Vec<_>>();
+        assert_eq!(outer, vec!["p", "c"]);
+        assert!(vm.variables(2).is_none());
+        vm.step(Step::Over);
+        assert_eq!(run(&mut vm), Some(4));
+        assert_eq!(vm.variables(0).unwrap()[1].value, "0");
+        vm.step(Step::Out);
+        // the breakpoint comes first
+        assert_eq!(run(&mut vm), Some(9));
+        assert_eq!(vm.variables(0).unwrap()[1].value, "'b'");
+        assert!(vm.clear_breakpoint(9));
+        assert!(!vm.clear_breakpoint(9));
+        vm.step(Step::Over);
+        assert_eq!(run(&mut vm), Some(11));
+        vm.step(Step::Over);
+        assert_eq!(run(&mut vm), Some(12));
+        assert_eq!(vm.variables(0).unwrap()[1].value, "[0.0, 0.0]");
+        vm.step(Step::Over);
+        assert_eq!(run(&mut vm), None);
+    }
+
+    #[test]
+    fn test_loop_exits_keep_stack() {
+        // each iteration leaves through a break or continue, anything they miss popping builds up
+        let program = "var n = 0
+            outer: for (var i = 0; i < 500; i = i + 1) {
+                var x = i
+                for c in \"ab\" {
+                    var y = c
+                    while true {
+                        var z = x
+                        if z % 3 == 0 { continue outer }
+                        if z == 497 { break outer }
+                        break
+                    }
+                    n = n + 1
+                }
+            }";
+        let parsed = parser::parse_program(program).unwrap();
+        let mut compiler = Compiler::new();
+        compiler.compile_program(&parsed).unwrap();
+        let mut vm = VM::new(compiler.program);
+        while vm.tick().is_ok() {}
+        // just n, the for loop's i, and the temporaries for `\"ab\"`
+        assert!(vm.stack.len() < 8, "{:?}", vm.stack);
+        assert_eq!(vm.stack[0], StackItem::Int(331 * 2));
+    }
+
+    #[test]
+    fn test_labelled_break_continue() {
+        assert_eq!(
+            run_program("outer: for (var i = 0; i < 3; i = i + 1) {\n    for (var j = 0; j < 3; j = j + 1) {\n        if j == 2 { continue outer }\n        if i == 2 { break outer }\n        print(i * 10 + j)\n        print(' ')\n    }\n}"),
+            "0 1 10 11 "
+        );
+        assert_eq!(
+            run_program("outer: while true {\n    for c in \"xyz\" {\n        var a = 5\n        if c == 'y' { break outer }\n        print(c)\n    }\n}\nprint('!')"),
+            "x!"
+        );
+        assert_eq!(
+            run_program("fun f() -> int {\n    outer: while true {\n        while true { break outer }\n    }\n    return 3\n}\nprint(f())"),
+            "3"
+        );
+    }
+
+    #[test]
+    fn test_break_errors() {
+        assert_eq!(compile_errors("break\nvar x = 1\nif x == 1 { continue }"), vec!["break", "continue"]);
+        assert_eq!(compile_errors("fun f() { break }\nf()"), vec!["break"]);
+        assert_eq!(compile_errors("a: while true { while true { break b } }"), vec!["b"]);
+        assert_eq!(error_messages("while true { continue x }"), vec!["no loop labelled `x` around this"]);
+        assert_eq!(error_messages("break"), vec!["`break` outside of a loop"]);
+        // breaking means the loop can end, so the function can reach its end
+        assert_eq!(compile_errors("fun f() -> int {\n    while true { break }\n}\nprint(f())"), vec!["}"]);
+    }
+
+    #[test]
+    fn test_else_if() {
+        let program = "fun f(x: int) {\n    if x < 0 { print('-') } else if x == 0 { print('0') } else if x < 10 { print('s') } else { print('b') }\n}\nf(-4)\nf(0)\nf(3)\nf(30)";
+        assert_eq!(run_program(program), "-0sb");
+    }
+
+    #[test]
+    fn test_match() {
+        let dispatch = "fun f(effect: int) {\n    match effect {\n        0 => { print(\"fire\") }\n        1, 2 => {\n            var x = 1\n            print(effect + x)\n        }\n        _ => { print('?') }\n    }\n    print(' ')\n}\nfor (var i = 0; i < 4; i = i + 1) { f(i) }";
+        assert_eq!(run_program(dispatch), "fire 2 3 ? ");
+        assert_eq!(run_program("var c = 'b'\nmatch c { 'a' => { print(1) } 'b' => { print(2) } }\nmatch c { 'z' => { print(3) } }\nprint(c)"), "2b");
+        assert_eq!(run_program("fun f(s: string) {\n    match s {\n        \"fire\" => { print('f') }\n        \"ice\", \"snow\" => { print('i') }\n        _ => { print('-') }\n    }\n}\nf(\"ice\")\nf(\"fire\")\nf(\"fir\")"), "if-");
+        // string patterns don't lean on a function a spell might declare too
+        assert_eq!(run_program("fun string_equals(a: string, b: string) { print('!') }\nmatch \"\" { \"a\" => { print(1) } \"\" => { print(2) } }\nstring_equals(\"a\", \"a\")"), "2!");
+        // arms can break out of the loop around the match
+        assert_eq!(run_program("var i = 0\nwhile true {\n    i = i + 1\n    match i { 3 => { break } _ => { continue } }\n}\nprint(i)"), "3");
+        assert_eq!(run_program("fun f(x: int) -> char {\n    match x { 1 => { return 'a' } _ => { return 'b' } }\n}\nprint(f(1))\nprint(f(2))"), "ab");
+    }
+
+    #[test]
+    fn test_match_errors() {
+        assert_eq!(error_messages("match true { _ => { } }"), vec!["can only match on `int`, `char` and `string`, not `bool`"]);
+        assert_eq!(compile_errors("var x = 1\nmatch x { 1 => { } 'a' => { } }"), vec!["'a'"]);
+        // without a default arm nothing might match
+        assert_eq!(compile_errors("fun f(x: int) -> int {\n    match x { 1 => { return 1 } }\n}\nprint(f(1))"), vec!["}"]);
+        assert_eq!(compile_warnings("var x = 1\nmatch x { 1 => { } 2, 1 => { } }"), vec![(CompilerWarning::UnreachableCode, "1")]);
+    }
+
+    fn compile_errors(program: &str) -> Vec<&str> {
+        let parsed = parser::parse_program(program).expect("parse error");
+        let mut compiler = Compiler::new();
+        let errors = compiler.compile_program(&parsed).expect_err("should not compile");
+        errors.iter().map(|x| &program[x.location.clone()]).collect()
+    }
+
+    #[test]
+    fn test_multiple_errors() {
+        assert_eq!(compile_errors("var a = 1 + true\nvar b = c\nvar d = 5"), vec!["+", "c"]);
+        assert_eq!(compile_errors("var a = 1\na = true\na = 2\na = 'c'"), vec!["true", "'c'"]);
+        assert_eq!(
+            compile_errors("fun f() -> int {\n    return 'x'\n}\nfun g() {\n    while 1 { undefined_fn() }\n    putc(5)\n}\nf()"),
+            vec!["'x'", "1", "undefined_fn", "putc"]
+        );
+        assert_eq!(compile_errors("struct S { a: Missing, b: int }\nvar s = new S\ns.b = 1\ns.a = 2"), vec!["Missing", "a"]);
+    }
+
+    #[test]
+    fn test_missing_return() {
+        assert_eq!(compile_errors("fun f() -> int {\n    putc('a')\n}\nprint(f())"), vec!["}"]);
+        assert_eq!(compile_errors("fun f(a: bool) -> int {\n    if a { return 1 }\n}\nprint(f(true))"), vec!["}"]);
+        assert_eq!(compile_errors("fun f(a: bool) -> int {\n    while a { return 1 }\n}\nprint(f(true))"), vec!["}"]);
+        let errors = compile_errors("fun f(a: bool) -> int {\n    if a { putc('a') } else { return 2 }\n}\nprint(f(true))");
+        assert_eq!(errors, vec!["}"]);
+
+        let program = "fun f(a: bool) -> int {\n    if a { return 1 } else { return 2 }\n}\nprint(f(true))";
+        assert!(Compiler::new().compile_program(&parser::parse_program(program).unwrap()).is_ok());
+        let program = "fun f() -> int {\n    while true { putc('a') }\n}\nprint(f())";
+        assert!(Compiler::new().compile_program(&parser::parse_program(program).unwrap()).is_ok());
+        let program = "fun f() { putc('a') }\nf()";
+        assert!(Compiler::new().compile_program(&parser::parse_program(program).unwrap()).is_ok());
+    }
+
+    fn error_messages(program: &str) -> Vec<String> {
+        let parsed = parser::parse_program(program).expect("parse error");
+        let mut compiler = Compiler::new();
+        let errors = compiler.compile_program(&parsed).expect_err("should not compile");
+        errors.iter().map(|x| x.error.to_string()).collect()
+    }
+
+    #[test]
+    fn test_error_messages() {
+        assert_eq!(error_messages("var a = 1\na = true"), vec!["mismatched types: expected `int`, found `bool`"]);
+        assert_eq!(error_messages("var a = 1 + true"), vec!["no operator `+` for `int` and `bool`"]);
+        assert_eq!(error_messages("var a = -true"), vec!["cannot apply unary `-` to `bool`"]);
+        assert_eq!(error_messages("var a = b"), vec!["no variable named `b` in scope"]);
+        assert_eq!(error_messages("var a = if true { 1 } else { 'c' }"), vec!["if and else branches have different types: `int` and `char`"]);
+        assert_eq!(
+            error_messages("fun f(a: int) { }\nfun f(a: char) { }\nf(true)"),
+            vec!["no function `f(bool)`, the candidates are `f(int)`, `f(char)`"]
+        );
+        assert_eq!(
+            error_messages("struct S { a: int, b: char }\nvar s = new S\nvar x = s.c"),
+            vec!["`S` has no field `c`, the fields are `a`, `b`"]
+        );
+        assert_eq!(error_messages("var a = 1\nvar a = 2"), vec!["`a` is already declared"]);
+        assert_eq!(error_messages("fun f() -> char { }\nputc(f())"), vec!["`f` can reach its end without returning a `char`"]);
+    }
+
+    fn compile_warnings(program: &str) -> Vec<(CompilerWarning, &str)> {
+        let parsed = parser::parse_program(program).expect("parse error");
+        let mut compiler = Compiler::new();
+        compiler.compile_program(&parsed).expect("compile error");
+        compiler.warnings.into_iter().map(|x| (x.warning, &program[x.location])).collect()
+    }
+
+    #[test]
+    fn test_warnings() {
+        assert_eq!(compile_warnings("var a = 1\nvar b = 2\nputc(if a == b { 'a' } else { 'b' })"), vec![]);
+        assert_eq!(compile_warnings("var a = 1\nvar b = 2\nb = a"), vec![(CompilerWarning::UnusedVariable { name: "b".to_owned() }, "b")]);
+        assert_eq!(
+            compile_warnings("fun f() -> int {\n    return 1\n    putc('a')\n    putc('b')\n}\nvar x = f()\nprint(x)"),
+            vec![(CompilerWarning::UnreachableCode, "putc('a')\n    putc('b')")]
+        );
+        assert_eq!(compile_warnings("while true {\n    break\n    putc('a')\n}"), vec![(CompilerWarning::UnreachableCode, "putc('a')")]);
+        assert_eq!(
+            compile_warnings("fun f(a: int) { }\nfun f(a: char) { }\nf(1)"),
+            vec![(CompilerWarning::UnusedFunction { signature: FunctionSignature { name: "f".to_owned(), args: vec![CompType::Char] } }, "f")]
+        );
+        assert_eq!(
+            compile_warnings("fun print(a: bool) { }\nprint(true)"),
+            vec![(CompilerWarning::OverloadsBuiltin { signature: FunctionSignature { name: "print".to_owned(), args: vec![CompType::Bool] } }, "print")]
+        );
+        assert_eq!(
+            compile_warnings("if true { putc('a') } else { putc('b') }\nif false { putc('c') }"),
+            vec![(CompilerWarning::ConstantCondition { value: true }, "true"), (CompilerWarning::ConstantCondition { value: false }, "false")]
+        );
+    }
+
+    #[test]
+    fn test_infinite_loop_warnings() {
+        assert_eq!(compile_warnings("var i = 0\nwhile true { i = i + 1 }"), vec![(CompilerWarning::InfiniteLoop, "true")]);
+        assert_eq!(compile_warnings("while true { putc('a') }"), vec![]);
+        // the syscall is a few calls away
+        assert_eq!(compile_warnings("fun f() { println(5) }\nfun g() { f() }\nwhile true { g() }"), vec![]);
+        assert_eq!(compile_warnings("fun f() { }\nwhile true { f() }"), vec![(CompilerWarning::InfiniteLoop, "true")]);
+        assert_eq!(compile_warnings("fun f() -> int { while true { return 1 } }\nprint(f())"), vec![]);
+        assert_eq!(compile_warnings("for (var i = 0; true; i = i + 1) { }"), vec![(CompilerWarning::InfiniteLoop, "true")]);
+        assert_eq!(compile_warnings("while true { break }"), vec![]);
+        assert_eq!(compile_warnings("a: while true { while true { break a } }"), vec![]);
+        assert_eq!(compile_warnings("while true { a: while true { break a } }"), vec![(CompilerWarning::InfiniteLoop, "true")]);
+    }
+
+    // the generator's random numbers, and whether a subexpression of the wrong type went into
+    // the expression being generated
+    struct Gen(Rng, bool);
+
+    impl Gen {
+        fn below(&mut self, n: usize) -> usize {
+            self.0.below(n)
+        }
+    }
+
+    #[derive(Clone, Copy, PartialEq, Debug)]
+    enum GenType { Int, Double, Bool, Char, Str, IntArray, S }
+
+    const GEN_TYPES: [GenType; 7] = [GenType::Int, GenType::Double, GenType::Bool, GenType::Char, GenType::Str, GenType::IntArray, GenType::S];
+
+    const GEN_PRELUDE: &str = "
+        struct S { a: int, name: string }
+        fun f(x: int) -> int { return x }
+        fun f(x: char) -> bool { return true }
+        fun f(x: bool, y: int) -> char { return 'a' }
+        fun g(x: S) -> int[] { return new int[x.a] }
+    ";
+
+    const GEN_VARIABLES: &str = "var i = 1; var d = 1.5; var b = true; var c = 'c'; var s = \"str\"; var a = new int[3]; var st = new S";
+
+    /// Generates the source of an expression that should have type `tpe`, except that now and
+    /// then a subexpression of the wrong type is used instead
+    fn gen_expression(rng: &mut Gen, tpe: GenType, depth: usize) -> String {
+        if rng.below(12) == 0 {
+            let wrong = GEN_TYPES[rng.below(GEN_TYPES.len())];
+            rng.1 |= wrong != tpe;
+            // in brackets, `-3.size` would parse as a double
+            return format!("({})", gen_typed(rng, wrong, depth));
+        }
+        gen_typed(rng, tpe, depth)
+    }
+
+    fn gen_typed(rng: &mut Gen, tpe: GenType, depth: usize) -> String {
+        use GenType::*;
+        let leaf = depth == 0 || rng.below(4) == 0;
+        let d = depth.saturating_sub(1);
+        if !leaf && rng.below(6) == 0 {
+            return format!("(if {} {{ {} }} else {{ {} }})", gen_expression(rng, Bool, d), gen_expression(rng, tpe, d), gen_expression(rng, tpe, d));
+        }
+        match (tpe, leaf) {
+            (Int, true) => ["7", "i", "-3"][rng.below(3)].to_owned(),
+            (Double, true) => ["0.5", "d"][rng.below(2)].to_owned(),
+            (Bool, true) => ["true", "false", "b"][rng.below(3)].to_owned(),
+            (Char, true) => ["'x'", "c"][rng.below(2)].to_owned(),
+            (Str, true) => ["\"hi\"", "s"][rng.below(2)].to_owned(),
+            (IntArray, true) => "a".to_owned(),
+            (S, true) => ["st", "new S"][rng.below(2)].to_owned(),
+            (Int, false) => match rng.below(7) {
+                0 => format!("({} {} {})", gen_expression(rng, Int, d), ["+", "-", "*", "/", "%", "&", "|", "^", "<<"][rng.below(9)], gen_expression(rng, Int, d)),
+                1 => format!("(~{})", gen_expression(rng, Int, d)),
+                2 => format!("f({})", gen_expression(rng, Int, d)),
+                3 => format!("{}[{}]", gen_expression(rng, IntArray, d), gen_expression(rng, Int, d)),
+                4 => {
+                    let sized = [IntArray, Str][rng.below(2)];
+                    format!("{}.size", gen_expression(rng, sized, d))
+                }
+                5 => format!("{}.a", gen_expression(rng, S, d)),
+                _ => gen_typed(rng, Int, 0)
+            },
+            (Double, false) => format!("({} {} {})", gen_expression(rng, Double, d), ["+", "-", "*", "/"][rng.below(4)], gen_expression(rng, Double, d)),
+            (Bool, false) => match rng.below(5) {
+                0 => {
+                    let operands = [Int, Double, Char][rng.below(3)];
+                    // chars can only be compared for equality
+                    let ops = if operands == Char { &["==", "!="][..] } else { &["<", "<=", "==", "!=", ">", ">="] };
+                    format!("({} {} {})", gen_expression(rng, operands, d), ops[rng.below(ops.len())], gen_expression(rng, operands, d))
+                }
+                1 => format!("({} {} {})", gen_expression(rng, Bool, d), ["&&", "||", "^"][rng.below(3)], gen_expression(rng, Bool, d)),
+                2 => format!("(!{})", gen_expression(rng, Bool, d)),
+                3 => format!("f({})", gen_expression(rng, Char, d)),
+                _ => gen_typed(rng, Bool, 0)
+            },
+            (Char, false) => match rng.below(2) {
+                0 => format!("{}[{}]", gen_expression(rng, Str, d), gen_expression(rng, Int, d)),
+                _ => format!("f({}, {})", gen_expression(rng, Bool, d), gen_expression(rng, Int, d))
+            },
+            (Str, false) => format!("{}.name", gen_expression(rng, S, d)),
+            (IntArray, false) => match rng.below(2) {
+                0 => format!("new int[{}]", gen_expression(rng, Int, d)),
+                _ => format!("g({})", gen_expression(rng, S, d))
+            },
+            (S, false) => gen_typed(rng, S, 0)
+        }
+    }
+
+    // a compiler with the generator's functions and variables declared
+    fn gen_compiler() -> Compiler {
+        let mut compiler = Compiler::new();
+        compiler.compile_program(&parser::parse_program(GEN_PRELUDE).unwrap()).unwrap();
+        compiler.stack.clear();
+        compiler.current_function = None;
+        for st in parser::parse_program(GEN_VARIABLES).unwrap() {
+            compiler.compile_statement(&st).unwrap();
+        }
+        compiler
+    }
+
+    fn gen_comp_type(compiler: &Compiler, tpe: GenType) -> CompType {
+        match tpe {
+            GenType::Int => CompType::Int,
+            GenType::Double => CompType::Double,
+            GenType::Bool => CompType::Bool,
+            GenType::Char => CompType::Char,
+            GenType::Str => CompType::String,
+            GenType::IntArray => CompType::Array(Box::new(CompType::Int)),
+            GenType::S => CompType::Struct(compiler.structs.iter().find(|x| x.name == "S").unwrap().clone())
+        }
+    }
+
+    #[test]
+    fn test_generated_expression_types() {
+        let mut compiler = gen_compiler();
+        let mut rng = Gen(Rng(0x5eed), false);
+        let mut checked = 0;
+        for _ in 0..3000 {
+            let tpe = GEN_TYPES[rng.below(GEN_TYPES.len())];
+            rng.1 = false;
+            let src = gen_expression(&mut rng, tpe, 4);
+            let parsed = parser::spellcode::expression(&src).unwrap_or_else(|e| panic!("{src}: {e}"));
+
+            let stack_len = compiler.stack.len();
+            let found = compiler.compile_expression(&parsed, CompStackI::Temp);
+            if let Ok(found) = &found {
+                assert_eq!(&compiler.stack.last().unwrap().1, found, "{src}");
+            }
+            // with a wrong part it may or may not check, an overload might take it
+            if !rng.1 {
+                assert_eq!(found.map_err(|x| format!("{:?}", x.error)), Ok(gen_comp_type(&compiler, tpe)), "{src}");
+                checked += 1;
+            }
+            compiler.stack.truncate(stack_len);
+        }
+        // make sure the generator isn't only making broken expressions
+        assert!(checked > 1000, "only {checked} expressions were well typed");
+    }
+
+    #[test]
+    fn test_expression_types() {
+        use GenType::*;
+        let mut compiler = gen_compiler();
+        for (src, tpe) in [
+            ("f(1)", Int),
+            ("f('x')", Bool),
+            ("f(true, 2)", Char),
+            ("f(f(c), f(i))", Char),
+            ("f(f(s[0]), g(st)[f(1)])", Char),
+            ("if b { if f('x') { f(1) } else { 2 } } else { g(st)[0] }", Int),
+            ("if f(c) { if b { 'a' } else { f(b, 1) } } else { if !b { s[i] } else { c } }", Char),
+            ("(if b { st } else { new S }).name", Str),
+            ("f(if b { 'a' } else { c })", Bool),
+            ("f(if b { 1 } else { i }) + (if f(false, 0) == 'a' { 1 } else { 2 })", Int),
+            ("(if b { a } else { g(st) }).size", Int),
+            ("-(if b { d } else { 0.5 })", Double)
+        ] {
+            let stack_len = compiler.stack.len();
+            let parsed = parser::spellcode::expression(src).unwrap();
+            assert_eq!(compiler.compile_expression(&parsed, CompStackI::Temp).map_err(|x| format!("{:?}", x.error)), Ok(gen_comp_type(&compiler, tpe)), "{src}");
+            compiler.stack.truncate(stack_len);
+        }
+
+        for src in ["f(1.5)", "f(f(1), 2)", "if b { f(1) } else { f('x') }", "if f(1) { 1 } else { 2 }", "(if b { st } else { a }).name"] {
+            let parsed = parser::spellcode::expression(src).unwrap();
+            assert!(compiler.compile_expression(&parsed, CompStackI::Temp).is_err(), "{src}");
+        }
+    }
+
+    #[test]
+    fn test_ternary_types() {
+        assert!(matches!(compile_and_run_expr("if true { 1 } else { 'c' }"), Err(CompErr { error: CompilerError::BranchMismatch { .. }, .. })));
+        assert!(matches!(compile_and_run_expr("if 1 { 1 } else { 2 }"), Err(CompErr { error: CompilerError::TypeMismatch { .. }, .. })));
+        assert!(matches!(compile_and_run_expr("if true { if false { 1 } else { 2 } } else { if true { 3 } else { 'c' } }"), Err(CompErr { error: CompilerError::BranchMismatch { .. }, .. })));
+        assert!(matches!(compile_and_run_expr("if true { 1.5 } else { 2.5 }"), Ok(Ok(StackItem::Double(1.5)))));
+    }
+
+    #[test]
+    fn test_secondary_spans() {
+        let program = "var a = 1\nvar a = 2";
+        let parsed = parser::parse_program(program).expect("parse error");
+        let errors = Compiler::new().compile_program(&parsed).expect_err("should not compile");
+        let spans = errors[0].error.secondary_spans();
+        assert_eq!(spans.len(), 1);
+        assert_eq!(&program[spans[0].0.clone()], "a");
+        assert_eq!(spans[0].0.start, 4);
+
+        let program = "var a = if true { 1 } else { 'c' }";
+        let parsed = parser::parse_program(program).expect("parse error");
+        let errors = Compiler::new().compile_program(&parsed).expect_err("should not compile");
+        assert_eq!(&program[errors[0].location.clone()], "'c'");
+        assert_eq!(&program[errors[0].error.secondary_spans()[0].0.clone()], "1");
+    }
+
     test_math! { test_addition: int_exp
         1 + 1,
         1 + 7,
@@ -1080,6 +2269,56 @@ mod tests {
         1.5 != 2.5, 1.5 != 1.5, 1.5 != 0.5,
     }
 
+    test_math! { test_boolean_operators: bool_exp
+        true && true, true && false, false && true, false && false,
+        true || true, true || false, false || true, false || false,
+        true && false || true, false || true && false, (false || true) && (true && !false),
+        1 < 2 && 2 < 3 || 4 < 3,
+        if true && false { false } else { true || false },
+    }
+
+    #[test]
+    fn test_short_circuit() {
+        // the right side would go out of bounds
+        assert!(matches!(compile_and_run_expr("false && new int[0][1] == 0"), Ok(Ok(StackItem::Int(0)))));
+        assert!(matches!(compile_and_run_expr("true || new int[0][1] == 0"), Ok(Ok(StackItem::Int(1)))));
+        assert!(matches!(compile_and_run_expr("true && new int[0][1] == 0"), Ok(Err(ExecutionException::ArrayIndexOutOfBounds))));
+        assert!(matches!(compile_and_run_expr("false || new int[0][1] == 0"), Ok(Err(ExecutionException::ArrayIndexOutOfBounds))));
+        assert!(matches!(compile_and_run_expr("true && new int[3][1] == 0"), Ok(Ok(StackItem::Int(1)))));
+        assert!(matches!(compile_and_run_expr("(1 > 2 || new int[0][0] == 0) && true"), Ok(Err(ExecutionException::ArrayIndexOutOfBounds))));
+    }
+
+    #[test]
+    fn test_short_circuit_stack() {
+        // the right sides leave arrays under their results, both ways through have to clean up
+        for program in ["false && new int[2][1] == 0", "true && new int[2][1] == 0", "true || new int[2][1] == 0", "false || new int[2][1] == 0",
+                        "(true && new int[2][1] == 0) || (false && new int[1][0] == 0)"] {
+            let parsed = parser::spellcode::expression(program).unwrap();
+            let mut compiler = Compiler::new();
+            compiler.compile_expression(&parsed, CompStackI::Temp).unwrap();
+            let mut vm = VM::new(compiler.program.clone());
+            vm.program.push(Instruction::Syscall(Syscall::Halt));
+            while vm.tick().is_ok() {}
+            assert_eq!(vm.stack.len(), compiler.stack.len(), "{program}");
+        }
+    }
+
+    test_math! { test_unary_minus: int_exp
+        -(1 + 2),
+        - 5,
+        -(-7),
+        3 - -4,
+        2 * -(3 + 4),
+        -(2147483647) - 1,
+        -if true { 1 } else { 2 },
+    }
+
+    test_math! { test_unary_minus_double StackItem::Double;
+        -(1.5 + 2.0),
+        - 0.25,
+        3.0 - -(4.5),
+    }
+
     test_math! { test_ternary: int_exp
         if true { 1 } else { 0 },
         if true { 0 } else { 1 },
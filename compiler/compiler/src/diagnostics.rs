@@ -0,0 +1,181 @@
+use std::{fmt::Display, ops::Range};
+
+use peg::{error::ParseError, str::LineCol};
+
+use crate::compiler::{CompErr, CompWarning};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    // the spell still compiles
+    Warning
+}
+
+/// A problem with a spell, located in the source so the editor can highlight it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    // the tokens the parser would have accepted instead, empty unless this is a parse error
+    pub expected: Vec<String>,
+    // byte range into the source
+    pub location: Range<usize>,
+    // both 1-based, columns are counted in characters
+    pub line: usize,
+    pub column: usize,
+    // other places worth pointing at, e.g. where a variable was first declared
+    pub notes: Vec<Note>
+}
+
+/// A labelled secondary location attached to a [`Diagnostic`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Note {
+    pub message: String,
+    pub location: Range<usize>,
+    pub line: usize,
+    pub column: usize
+}
+
+impl Diagnostic {
+    pub fn new(src: &str, message: String, location: Range<usize>) -> Diagnostic {
+        let (line, column) = line_col(src, location.start);
+        Diagnostic { severity: Severity::Error, message, expected: vec![], location, line, column, notes: vec![] }
+    }
+
+    pub fn from_parse_error(src: &str, err: &ParseError<LineCol>) -> Diagnostic {
+        let start = err.location.offset;
+        let found = src[start..].chars().next();
+        let end = found.map_or(start, |c| start + c.len_utf8());
+
+        let mut expected = err.expected.tokens().map(pretty_token).collect::<Vec<_>>();
+        expected.sort();
+        expected.dedup();
+
+        let found = match found {
+            Some(c) => format!("`{}`", c.escape_debug()),
+            None => "end of input".to_owned()
+        };
+        let message = match expected.as_slice() {
+            [] => format!("unexpected {found}"),
+            [one] => format!("expected {one}, found {found}"),
+            many => format!("expected one of {}, found {found}", many.join(", "))
+        };
+
+        // not err.location's line and column, the error recovery may have blanked out
+        // multi-byte characters earlier on the line
+        let (line, column) = line_col(src, start);
+        Diagnostic { severity: Severity::Error, message, expected, location: start..end, line, column, notes: vec![] }
+    }
+
+    pub fn from_comp_err(src: &str, err: &CompErr) -> Diagnostic {
+        let mut diagnostic = Diagnostic::new(src, err.error.to_string(), err.location.clone());
+        diagnostic.notes = err.error.secondary_spans().into_iter().map(|(location, message)| {
+            let (line, column) = line_col(src, location.start);
+            Note { message, location, line, column }
+        }).collect();
+        diagnostic
+    }
+
+    pub fn from_comp_warning(src: &str, warning: &CompWarning) -> Diagnostic {
+        let mut diagnostic = Diagnostic::new(src, warning.warning.to_string(), warning.location.clone());
+        diagnostic.severity = Severity::Warning;
+        diagnostic
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}, column {}: ", self.line, self.column)?;
+        if self.severity == Severity::Warning {
+            write!(f, "warning: ")?;
+        }
+        write!(f, "{}", self.message)?;
+        for note in &self.notes {
+            write!(f, "\n  line {}, column {}: {}", note.line, note.column, note.message)?;
+        }
+        Ok(())
+    }
+}
+
+/// Converts a byte offset into a 1-based line and column
+pub fn line_col(src: &str, offset: usize) -> (usize, usize) {
+    let before = &src[..offset.min(src.len())];
+    let line_start = before.rfind('\n').map_or(0, |x| x + 1);
+    (before.matches('\n').count() + 1, before[line_start..].chars().count() + 1)
+}
+
+// peg reports literals with their quotes and escapes still on, e.g. "\"}\""
+fn pretty_token(token: &str) -> String {
+    if token.len() >= 2 && token.starts_with('"') && token.ends_with('"') {
+        format!("`{}`", token[1..token.len() - 1].replace("\\\"", "\"").replace("\\\\", "\\"))
+    } else {
+        token.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::spellcode;
+
+    fn parse_diagnostic(src: &str) -> Diagnostic {
+        Diagnostic::from_parse_error(src, &spellcode::program(src).expect_err("should not parse"))
+    }
+
+    #[test]
+    fn test_line_col() {
+        assert_eq!(line_col("abc", 0), (1, 1));
+        assert_eq!(line_col("abc", 2), (1, 3));
+        assert_eq!(line_col("abc\ndef", 4), (2, 1));
+        assert_eq!(line_col("a\nbc\ndef", 7), (3, 3));
+        assert_eq!(line_col("ä\nöü", 5), (2, 2));
+    }
+
+    #[test]
+    fn test_parse_error_location() {
+        let d = parse_diagnostic("var x = 1 +* 2");
+        assert_eq!(d.location, 11..12);
+        assert_eq!((d.line, d.column), (1, 12));
+
+        let d = parse_diagnostic("var x = 1\nvar y = (2");
+        assert_eq!(d.location, 20..20);
+        assert_eq!((d.line, d.column), (2, 11));
+        assert!(d.message.ends_with("found end of input"), "{}", d.message);
+    }
+
+    #[test]
+    fn test_parse_error_expected() {
+        let d = parse_diagnostic("var x = 1 +* 2");
+        assert!(d.expected.contains(&"literal".to_owned()), "{:?}", d.expected);
+        assert!(d.expected.contains(&"identifier".to_owned()), "{:?}", d.expected);
+        assert!(d.expected.contains(&"`(`".to_owned()), "{:?}", d.expected);
+        assert!(d.message.starts_with("expected one of"), "{}", d.message);
+        assert!(d.message.ends_with("found `*`"), "{}", d.message);
+
+        let d = parse_diagnostic("fun f(a: int { }");
+        assert!(d.expected.contains(&"`)`".to_owned()), "{:?}", d.expected);
+    }
+
+    #[test]
+    fn test_compile_error_notes() {
+        let src = "var a = 1\nvar a = 2";
+        let parsed = crate::parser::parse_program(src).unwrap();
+        let errors = crate::compiler::Compiler::new().compile_program(&parsed).unwrap_err();
+        let d = Diagnostic::from_comp_err(src, &errors[0]);
+        assert_eq!((d.line, d.column), (2, 5));
+        assert_eq!(d.notes.len(), 1);
+        assert_eq!((d.notes[0].line, d.notes[0].column), (1, 5));
+        assert_eq!(d.to_string(), "line 2, column 5: `a` is already declared\n  line 1, column 5: previously declared here");
+    }
+
+    #[test]
+    fn test_warning() {
+        let src = "var a = 1\nvar b = a\n";
+        let parsed = crate::parser::parse_program(src).unwrap();
+        let mut compiler = crate::compiler::Compiler::new();
+        compiler.compile_program(&parsed).unwrap();
+        let d = Diagnostic::from_comp_warning(src, &compiler.warnings[0]);
+        assert_eq!(d.severity, Severity::Warning);
+        assert_eq!(d.to_string(), "line 2, column 5: warning: variable `b` is never read");
+    }
+}
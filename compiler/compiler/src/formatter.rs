@@ -0,0 +1,620 @@
+use std::collections::BTreeMap;
+
+use peg::{error::ParseError, str::LineCol};
+
+use crate::parser::{self, Expression, Literal, Op, Statement, Tag, TypeName, UnaryOp};
+
+const INDENT: &str = "    ";
+
+// how tightly each kind of expression binds, the operators are numbered by their level in the
+// grammar and everything that isn't an operator binds tightest
+const UNARY: u8 = 9;
+const ATOM: u8 = 10;
+
+/// Formats a spell with one statement per line and four space indents.  Comments between
+/// statements are kept where they were, as are single blank lines between statements, and ones
+/// inside a statement go at the end of the line it's on.  Fails with the parse errors if the
+/// spell doesn't parse
+pub fn format_program(src: &str) -> Result<String, Vec<ParseError<LineCol>>> {
+    let (program, loose) = parser::parse_with_comments(src)?;
+    let mut formatter = Formatter::new(src, 0);
+    formatter.comments.extend(loose.iter().map(|x| (x.loc.start, x)));
+    formatter.block(&program);
+    formatter.comments_before(src.len());
+    Ok(formatter.out)
+}
+
+struct Formatter<'a> {
+    src: &'a str,
+    // the comments on the nodes reached so far that haven't been written yet, by where they start
+    comments: BTreeMap<usize, &'a Tag<String>>,
+    // off when squashing a statement onto one line, e.g. the start of a for loop, the comments
+    // in it are written by whatever is formatting the whole line
+    emit_comments: bool,
+    // everything in the source before this has been written
+    pos: usize,
+    out: String,
+    indent: usize,
+    // right after `{` or before `}`, where blank lines are dropped
+    skip_blank_line: bool
+}
+
+impl<'a> Formatter<'a> {
+    fn new(src: &'a str, pos: usize) -> Formatter<'a> {
+        Formatter { src, comments: BTreeMap::new(), emit_comments: true, pos, out: String::new(), indent: 0, skip_blank_line: true }
+    }
+
+    /// Starts a new line for something at `at` in the source, keeping a blank line if there was
+    /// one before it
+    fn start_line(&mut self, at: usize) {
+        let gap = &self.src[self.pos.min(at)..at];
+        let lines = gap.split('\n').collect::<Vec<_>>();
+        if !self.skip_blank_line && lines.len() > 2 && lines[1..lines.len() - 1].iter().any(|x| x.trim().is_empty()) {
+            self.out.push('\n');
+        }
+        self.skip_blank_line = false;
+        for _ in 0..self.indent {
+            self.out.push_str(INDENT);
+        }
+        self.out.push('\n');
+    }
+
+    fn line(&mut self, at: usize, text: &str) {
+        self.start_line(at);
+        self.append(text);
+    }
+
+    // adds to the end of the last line
+    fn append(&mut self, text: &str) {
+        self.out.pop();
+        self.out.push_str(text);
+        self.out.push('\n');
+    }
+
+    /// Writes the comments that start before `limit`.  Ones on the same line as the code before
+    /// them, or inside code that's already been written, go at the end of the last line
+    fn comments_before(&mut self, limit: usize) {
+        while self.emit_comments && let Some(entry) = self.comments.first_entry() && *entry.key() < limit {
+            let comment = entry.remove();
+            let text = comment.item.trim_end();
+            if !self.out.is_empty() && (comment.loc.start < self.pos || !self.src[self.pos..comment.loc.start].contains('\n')) {
+                self.append(&format!(" {text}"));
+            } else {
+                self.line(comment.loc.start, text);
+            }
+            self.pos = self.pos.max(comment.loc.end);
+        }
+    }
+
+    // where the next `token` is in the source, skipping over comments
+    fn find(&self, token: &str) -> Option<usize> {
+        parser::unquoted(&self.src.as_bytes()[self.pos..])
+            .map(|(i, _)| self.pos + i)
+            .find(|&i| self.src[i..].starts_with(token))
+    }
+
+    // where the statement's first token is, its location starts after keywords like `while`
+    fn statement_start(&self, statement: &Statement) -> usize {
+        let start = statement.loc().start;
+        parser::unquoted(&self.src.as_bytes()[self.pos..])
+            .find(|&(_, c)| !c.is_ascii_whitespace() && c != b';')
+            .map_or(start, |(i, _)| start.min(self.pos + i))
+    }
+
+    fn queue<T>(&mut self, tag: &'a Tag<T>) {
+        for comment in tag.trivia.leading.iter().chain(&tag.trivia.trailing) {
+            self.comments.insert(comment.loc.start, comment);
+        }
+    }
+
+    /// Queues the comments on the statement's own nodes, the statements in its blocks are
+    /// queued when their block is reached
+    fn queue_statement(&mut self, statement: &'a Statement) {
+        match statement {
+            Statement::ExprS(expr) => self.queue_expression(expr),
+            Statement::VariableDecl(name, value) => {
+                self.queue(name);
+                self.queue_expression(value);
+            }
+            Statement::Assignment { left, value } => {
+                self.queue_expression(left);
+                self.queue_expression(value);
+            }
+            Statement::If { condition, .. } => self.queue_expression(condition),
+            Statement::Match { keyword, value, arms, default } => {
+                self.queue(keyword);
+                self.queue_expression(value);
+                for pattern in arms.iter().flat_map(|x| &x.0) {
+                    self.queue(pattern);
+                }
+                if let Some((underscore, _)) = default {
+                    self.queue(underscore);
+                }
+            }
+            Statement::CFor { label, init, condition, increment, .. } => {
+                if let Some(label) = label {
+                    self.queue(label);
+                }
+                if let Some(init) = init.as_ref() {
+                    self.queue_statement(init);
+                }
+                self.queue_expression(condition);
+                if let Some(increment) = increment.as_ref() {
+                    self.queue_statement(increment);
+                }
+            }
+            Statement::ForEach { label, variable, array, .. } => {
+                if let Some(label) = label {
+                    self.queue(label);
+                }
+                self.queue(variable);
+                self.queue_expression(array);
+            }
+            Statement::While { label, condition, .. } => {
+                if let Some(label) = label {
+                    self.queue(label);
+                }
+                self.queue_expression(condition);
+            }
+            Statement::Return { keyword, expr } => {
+                self.queue(keyword);
+                if let Some(expr) = expr {
+                    self.queue_expression(expr);
+                }
+            }
+            Statement::Break { keyword, label } | Statement::Continue { keyword, label } => {
+                self.queue(keyword);
+                if let Some(label) = label {
+                    self.queue(label);
+                }
+            }
+            Statement::FunctionDef { name, arguments, return_type, end, .. } => {
+                self.queue(name);
+                for (name, tpe) in arguments {
+                    self.queue(name);
+                    self.queue_type(tpe);
+                }
+                if let Some(return_type) = return_type {
+                    self.queue_type(return_type);
+                }
+                self.queue(end);
+            }
+            Statement::StructDef { name, fields } => {
+                self.queue(name);
+                for (name, tpe) in fields {
+                    self.queue(name);
+                    self.queue_type(tpe);
+                }
+            }
+        }
+    }
+
+    fn queue_expression(&mut self, expr: &'a Tag<Expression>) {
+        self.queue(expr);
+        match &expr.item {
+            Expression::Lit(lit) => self.queue(lit),
+            Expression::Math(left, op, right) => {
+                self.queue_expression(left);
+                self.queue(op);
+                self.queue_expression(right);
+            }
+            Expression::FunctionCall { name, args } => {
+                self.queue(name);
+                for arg in args {
+                    self.queue_expression(arg);
+                }
+            }
+            Expression::PropertyAccess(x, name) => {
+                self.queue_expression(x);
+                self.queue(name);
+            }
+            Expression::Ternary { condition, if_true, if_false } => {
+                self.queue_expression(condition);
+                self.queue_expression(if_true);
+                self.queue_expression(if_false);
+            }
+            Expression::ArrayAccess { array, index } => {
+                self.queue_expression(array);
+                self.queue_expression(index);
+            }
+            Expression::VarAccess(name) | Expression::NewStruct(name) => self.queue(name),
+            Expression::NewArray(tpe, length) => {
+                self.queue_type(tpe);
+                self.queue_expression(length);
+            }
+            Expression::UnaryOperation(op, value) => {
+                self.queue(op);
+                self.queue_expression(value);
+            }
+        }
+    }
+
+    fn queue_type(&mut self, tpe: &'a Tag<TypeName>) {
+        self.queue(tpe);
+        match &tpe.item {
+            TypeName::Array(inner) => self.queue_type(inner),
+            TypeName::Struct(name) => self.queue(name),
+            _ => {}
+        }
+    }
+
+    fn block(&mut self, block: &'a [Statement]) {
+        for (i, statement) in block.iter().enumerate() {
+            self.queue_statement(statement);
+            self.comments_before(self.statement_start(statement));
+            self.statement(statement, i + 1 < block.len());
+        }
+    }
+
+    fn open_brace(&mut self) {
+        self.pos = self.find("{").map_or(self.pos, |x| x + 1);
+        self.append(" {");
+        self.indent += 1;
+        self.skip_blank_line = true;
+    }
+
+    fn close_brace(&mut self) {
+        let end = self.find("}").unwrap_or(self.pos);
+        self.comments_before(end);
+        self.indent -= 1;
+        self.skip_blank_line = true;
+        self.line(end, "}");
+        self.pos = end + 1;
+    }
+
+    fn braced(&mut self, block: &'a [Statement]) {
+        let empty = block.is_empty() && {
+            let pos = self.pos;
+            self.pos = self.find("{").map_or(self.pos, |x| x + 1);
+            let end = self.find("}").unwrap_or(self.pos);
+            self.pos = pos;
+            !self.emit_comments || self.comments.first_key_value().is_none_or(|(&start, _)| start > end)
+        };
+        if empty {
+            self.pos = self.find("{").map_or(self.pos, |x| x + 1);
+            self.pos = self.find("}").map_or(self.pos, |x| x + 1);
+            self.append(" { }");
+            return;
+        }
+        self.open_brace();
+        self.block(block);
+        self.close_brace();
+    }
+
+    // `more` is whether another statement follows this one in the same block
+    fn statement(&mut self, statement: &'a Statement, more: bool) {
+        let start = statement.loc().start;
+        match statement {
+            Statement::ExprS(expr) => self.line(start, &self.statement_expression(expr)),
+            Statement::VariableDecl(name, value) => self.line(start, &format!("var {} = {}", name.item, self.expression(value))),
+            Statement::Assignment { left, value } => self.line(start, &format!("{} = {}", self.statement_expression(left), self.expression(value))),
+            Statement::If { condition, block, else_block } => {
+                self.start_line(start);
+                self.if_chain(condition, block, else_block);
+            }
+            Statement::Match { keyword: _, value, arms, default } => {
+                self.line(start, &format!("match {}", self.expression(value)));
+                self.pos = value.loc.end;
+                self.open_brace();
+                for (patterns, block) in arms {
+                    self.comments_before(patterns[0].loc.start);
+                    let text = patterns.iter().map(|x| self.literal(x)).collect::<Vec<_>>().join(", ");
+                    self.line(patterns[0].loc.start, &format!("{text} =>"));
+                    self.pos = patterns.last().unwrap().loc.end;
+                    self.braced(block);
+                }
+                if let Some((underscore, block)) = default {
+                    self.comments_before(underscore.loc.start);
+                    self.line(underscore.loc.start, "_ =>");
+                    self.pos = underscore.loc.end;
+                    self.braced(block);
+                }
+                self.close_brace();
+            }
+            Statement::CFor { label, init, condition, increment, block } => {
+                let init = init.as_ref().as_ref().map(|x| self.inline(x)).unwrap_or_default();
+                let increment_text = increment.as_ref().as_ref().map(|x| self.inline(x)).unwrap_or_default();
+                self.line(start, &format!("{}for ({init}; {}; {increment_text})", Self::label(label), self.expression(condition)));
+                self.pos = increment.as_ref().as_ref().map_or(condition.loc.end, |x| x.loc().end);
+                self.braced(block);
+            }
+            Statement::ForEach { label, variable, array, block } => {
+                self.line(start, &format!("{}for {} in {}", Self::label(label), variable.item, self.expression(array)));
+                self.pos = array.loc.end;
+                self.braced(block);
+            }
+            Statement::While { label, condition, block } => {
+                self.line(start, &format!("{}while {}", Self::label(label), self.expression(condition)));
+                self.pos = condition.loc.end;
+                self.braced(block);
+            }
+            Statement::Return { keyword: _, expr: Some(expr) } => self.line(start, &format!("return {}", self.expression(expr))),
+            // without the semicolon the next statement would be taken as the value
+            Statement::Return { keyword: _, expr: None } => self.line(start, if more { "return;" } else { "return" }),
+            Statement::Break { keyword: _, label } => self.line(start, &format!("break{}", label.as_ref().map_or(String::new(), |x| format!(" {}", x.item)))),
+            Statement::Continue { keyword: _, label } => self.line(start, &format!("continue{}", label.as_ref().map_or(String::new(), |x| format!(" {}", x.item)))),
+            Statement::FunctionDef { name, arguments, return_type, block, end: _ } => {
+                let arguments = arguments.iter().map(|(name, tpe)| format!("{}: {}", name.item, type_name(tpe))).collect::<Vec<_>>().join(", ");
+                let return_type = return_type.as_ref().map_or(String::new(), |x| format!(" -> {}", type_name(x)));
+                self.line(start, &format!("fun {}({arguments}){return_type}", name.item));
+                self.pos = name.loc.end;
+                self.braced(block);
+            }
+            Statement::StructDef { name, fields } => {
+                self.line(start, &format!("struct {}", name.item));
+                self.pos = name.loc.end;
+                self.open_brace();
+                for (i, (field, tpe)) in fields.iter().enumerate() {
+                    self.comments_before(field.loc.start);
+                    let comma = if i + 1 < fields.len() { "," } else { "" };
+                    self.line(field.loc.start, &format!("{}: {}{comma}", field.item, type_name(tpe)));
+                    self.pos = tpe.loc.end;
+                }
+                self.close_brace();
+            }
+        }
+        self.pos = self.pos.max(statement.loc().end);
+    }
+
+    // continues the current line with an if statement, and any `else if`s after it
+    fn if_chain(&mut self, condition: &Tag<Expression>, block: &'a [Statement], else_block: &'a Option<Vec<Statement>>) {
+        self.append(&format!("if {}", self.expression(condition)));
+        self.pos = condition.loc.end;
+        self.braced(block);
+        let Some(else_block) = else_block else { return };
+        self.pos = self.find("else").map_or(self.pos, |x| x + "else".len());
+        self.append(" else");
+        match else_block.as_slice() {
+            // only if it was written as `else if`, rather than an if in braces
+            [Statement::If { condition, block, else_block }] if matches!((self.find("if"), self.find("{")), (Some(a), Some(b)) if a < b) => {
+                self.queue_expression(condition);
+                self.append(" ");
+                self.if_chain(condition, block, else_block);
+            }
+            _ => self.braced(else_block)
+        }
+    }
+
+    // a statement on one line, for the start of a for loop
+    fn inline(&self, statement: &'a Statement) -> String {
+        let mut formatter = Formatter::new(self.src, statement.loc().start);
+        formatter.emit_comments = false;
+        formatter.statement(statement, false);
+        formatter.out.lines().map(str::trim).filter(|x| !x.is_empty()).collect::<Vec<_>>().join(" ")
+    }
+
+    fn label(label: &Option<Tag<String>>) -> String {
+        label.as_ref().map_or(String::new(), |x| format!("{}: ", x.item))
+    }
+
+    /// An expression at the start of a statement.  One starting with `if` would be read as an if
+    /// statement, and one starting with `-` as part of the statement before, so those get brackets
+    fn statement_expression(&self, expr: &Tag<Expression>) -> String {
+        let text = self.expression(expr);
+        if text.starts_with("if ") || text.starts_with('-') {
+            format!("({text})")
+        } else {
+            text
+        }
+    }
+
+    fn expression(&self, expr: &Tag<Expression>) -> String {
+        self.operand(expr, 0)
+    }
+
+    // an expression in brackets if it binds less tightly than `min`
+    fn operand(&self, expr: &Tag<Expression>, min: u8) -> String {
+        let text = match &expr.item {
+            Expression::Lit(lit) => self.literal(lit),
+            Expression::Math(left, op, right) => {
+                let level = precedence(op);
+                format!("{} {} {}", self.operand(left, level), op.symbol(), self.operand(right, level + 1))
+            }
+            Expression::FunctionCall { name, args } => format!("{}({})", name.item, args.iter().map(|x| self.expression(x)).collect::<Vec<_>>().join(", ")),
+            // `5.size` would start with the double `5.`
+            Expression::PropertyAccess(x, name) if matches!(x.item, Expression::Lit(_)) => format!("({}).{}", self.expression(x), name.item),
+            Expression::PropertyAccess(x, name) => format!("{}.{}", self.operand(x, ATOM), name.item),
+            Expression::Ternary { condition, if_true, if_false } =>
+                format!("if {} {{ {} }} else {{ {} }}", self.expression(condition), self.expression(if_true), self.expression(if_false)),
+            // `new S[i]` would be a new array
+            Expression::ArrayAccess { array, index } if matches!(array.item, Expression::NewStruct(_)) => format!("({})[{}]", self.expression(array), self.expression(index)),
+            Expression::ArrayAccess { array, index } => format!("{}[{}]", self.operand(array, ATOM), self.expression(index)),
+            Expression::VarAccess(name) => name.item.clone(),
+            Expression::NewArray(tpe, length) => format!("new {}[{}]", type_name(tpe), self.expression(length)),
+            Expression::UnaryOperation(op, value) => {
+                let value = self.operand(value, UNARY);
+                // `-5` would be a negative literal instead
+                if op.item == UnaryOp::UnaryMinus && value.starts_with(|c: char| c.is_ascii_digit() || c == '.') {
+                    format!("-({value})")
+                } else {
+                    format!("{}{value}", op.symbol())
+                }
+            }
+            Expression::NewStruct(name) => format!("new {}", name.item)
+        };
+        let binds = match &expr.item {
+            Expression::Math(_, op, _) => precedence(op),
+            // bracketed before `.x` or `[i]`, which would otherwise look like they belong to the
+            // else branch, and which would leave it unbracketed at the start of a statement
+            Expression::UnaryOperation(..) | Expression::Ternary { .. } => UNARY,
+            _ => ATOM
+        };
+        if binds < min {
+            format!("({text})")
+        } else {
+            text
+        }
+    }
+
+    // literals are copied from the source so hex numbers and escapes stay as they were written
+    fn literal(&self, literal: &Tag<Literal>) -> String {
+        self.src[literal.loc.clone()].to_owned()
+    }
+}
+
+fn precedence(op: &Op) -> u8 {
+    match op {
+        Op::BoolOr => 0,
+        Op::BoolAnd => 1,
+        Op::Lt | Op::Le | Op::Eq | Op::Ne | Op::Gt | Op::Ge => 2,
+        Op::Or => 3,
+        Op::Xor => 4,
+        Op::And => 5,
+        Op::Shl | Op::Shr | Op::Shrl => 6,
+        Op::Plus | Op::Minus => 7,
+        Op::Times | Op::Divide | Op::Mod => 8
+    }
+}
+
+fn type_name(tpe: &Tag<TypeName>) -> String {
+    match &tpe.item {
+        TypeName::Int => "int".to_owned(),
+        TypeName::Double => "double".to_owned(),
+        TypeName::Char => "char".to_owned(),
+        TypeName::String => "string".to_owned(),
+        TypeName::Bool => "bool".to_owned(),
+        TypeName::Array(inner) => format!("{}[]", type_name(inner)),
+        TypeName::Struct(name) => name.item.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::Rng;
+
+    fn format(src: &str) -> String {
+        format_program(src).expect("parse error")
+    }
+
+    #[test]
+    fn test_format_statements() {
+        assert_eq!(format("var x=1;x=x+2\n\n\n   f( x,3 )"), "var x = 1\nx = x + 2\n\nf(x, 3)\n");
+        assert_eq!(
+            format("fun f(a:int,b:int[])->int{if a>0{return b[a]}else if a<0{return 0}else{while true{break}}\nreturn 1}"),
+            "fun f(a: int, b: int[]) -> int {\n    if a > 0 {\n        return b[a]\n    } else if a < 0 {\n        return 0\n    } else {\n        while true {\n            break\n        }\n    }\n    return 1\n}\n"
+        );
+        assert_eq!(format("struct S{a:int,b:S[]}"), "struct S {\n    a: int,\n    b: S[]\n}\n");
+        assert_eq!(format("outer: for(var i=0;i<3;i=i+1){for c in s{continue outer}}"), "outer: for (var i = 0; i < 3; i = i + 1) {\n    for c in s {\n        continue outer\n    }\n}\n");
+        assert_eq!(format("match x{1,2=>{f()},_=>{}}"), "match x {\n    1, 2 => {\n        f()\n    }\n    _ => { }\n}\n");
+        // an if in braces stays that way
+        assert_eq!(format("if a { } else { if b { } }"), "if a { } else {\n    if b { }\n}\n");
+        assert_eq!(format("fun f() { return; f() }"), "fun f() {\n    return;\n    f()\n}\n");
+    }
+
+    #[test]
+    fn test_format_expressions() {
+        assert_eq!(format("var x = ((1 + 2)) * (3 - (4 - 5)) - (6 * 7)"), "var x = (1 + 2) * (3 - (4 - 5)) - 6 * 7\n");
+        assert_eq!(format("var x = -(a.b[0]) + - (1) + (-1) + (- -a)"), "var x = -a.b[0] + -(1) + -1 + --a\n");
+        assert_eq!(format("var x = (a || b) && !(c == d) && (1).size"), "var x = (a || b) && !(c == d) && (1).size\n");
+        // literals are kept as written
+        assert_eq!(format("var x = 0xff + 0b10 + 1.\nvar s = \"a\\tb\""), "var x = 0xff + 0b10 + 1.\nvar s = \"a\\tb\"\n");
+        assert_eq!(format("(if a {1} else {2}).x = 3\n(-a).f = 1\n(new S)[0] = 1"), "(if a { 1 } else { 2 }).x = 3\n(-a).f = 1\n(new S)[0] = 1\n");
+    }
+
+    #[test]
+    fn test_format_comments() {
+        assert_eq!(
+            format("// a spell\n\nvar x = 1 // one\n/* two */ var y = 2\nfun f() { // start\n  f()\n\n  // end\n} // after"),
+            "// a spell\n\nvar x = 1 // one\n/* two */\nvar y = 2\nfun f() { // start\n    f()\n\n    // end\n} // after\n"
+        );
+        assert_eq!(format("f(1, /* a */ 2)\ng()"), "f(1, 2) /* a */\ng()\n");
+        assert_eq!(format("if a {\n    // nothing\n} else { }"), "if a {\n    // nothing\n} else { }\n");
+        assert_eq!(format("struct S {\n    a: int, // first\n    b: int\n}"), "struct S {\n    a: int, // first\n    b: int\n}\n");
+        assert_eq!(format("if a { f() } // after"), "if a {\n    f()\n} // after\n");
+        assert_eq!(format("var v = if c { a /* x */ } else { b } // y\nf()"), "var v = if c { a } else { b } /* x */ // y\nf()\n");
+        assert_eq!(format("// only\n\n/* comments */"), "// only\n\n/* comments */\n");
+        assert_eq!(format("var x = f(1, /* a */ 2)"), "var x = f(1, 2) /* a */\n");
+        assert_eq!(format("while /* w */ true { break }"), "while true { /* w */\n    break\n}\n");
+        assert_eq!(format("var a = (1 + /* in */ 2) * 3"), "var a = (1 + 2) * 3 /* in */\n");
+    }
+
+    // somewhere whitespace is allowed
+    fn space(rng: &mut Rng) -> String {
+        match rng.below(12) {
+            0 => "\n".to_owned(),
+            1 => "\t".to_owned(),
+            2 => format!(" /* c{} */ ", rng.below(100)),
+            3 => format!(" // c{}\n", rng.below(100)),
+            4 => String::new(),
+            _ => " ".to_owned()
+        }
+    }
+
+    fn gen_expression(rng: &mut Rng, depth: usize) -> String {
+        if depth == 0 || rng.below(4) == 0 {
+            return rng.pick(&["1", "-2", "0x1f", "1.5", "-.5", "true", "'a'", "'\\n'", "\"s // t\"", "x", "y"]).to_owned();
+        }
+        let d = depth - 1;
+        match rng.below(10) {
+            0 | 1 => {
+                let op = rng.pick(&["+", "-", "*", "/", "%", "<<", ">>", ">>>", "&", "|", "^", "<", "<=", "==", "!=", ">", ">=", "&&", "||"]);
+                format!("{}{}{op}{}{}", gen_expression(rng, d), space(rng), space(rng), gen_expression(rng, d))
+            }
+            2 => format!("({}{}{})", space(rng), gen_expression(rng, d), space(rng)),
+            3 => format!("{}{}", rng.pick(&["-", "!", "~", "- "]), gen_expression(rng, d)),
+            4 => format!("f({}{})", space(rng), (0..rng.below(3)).map(|_| gen_expression(rng, d)).collect::<Vec<_>>().join(", ")),
+            5 => format!("({}).a", gen_expression(rng, d)),
+            6 => format!("({})[{}{}]", gen_expression(rng, d), space(rng), gen_expression(rng, d)),
+            7 => format!("new int{}[{}]", rng.pick(&["", "[]"]), gen_expression(rng, d)),
+            8 => format!("(if {} {{{}{}}} else {{ {} }})", gen_expression(rng, d), space(rng), gen_expression(rng, d), gen_expression(rng, d)),
+            _ => "new S".to_owned()
+        }
+    }
+
+    fn gen_block(rng: &mut Rng, depth: usize) -> String {
+        let statements = (0..rng.below(4)).map(|_| gen_statement(rng, depth)).collect::<Vec<_>>();
+        let mut out = "{".to_owned();
+        for statement in statements {
+            out += &space(rng);
+            out += &statement;
+            out += if statement.ends_with(';') { "\n" } else { rng.pick(&["\n", ";", "\n\n", " // end\n"]) };
+        }
+        out + "}"
+    }
+
+    fn gen_statement(rng: &mut Rng, depth: usize) -> String {
+        let d = depth.saturating_sub(1);
+        let e = gen_expression(rng, 2);
+        match if depth == 0 { rng.below(5) } else { rng.below(13) } {
+            0 => format!("var x ={}{e}", space(rng)),
+            1 => format!("{} = {e}", rng.pick(&["x", "x[0]", "x.a", "x.a[1].b"])),
+            2 => format!("f({e})"),
+            // a bare return needs the semicolon, otherwise the next statement is its value
+            3 => rng.pick(&["break", "continue", "break outer", "return;"]).to_owned(),
+            4 => format!("return {e}"),
+            5 => format!("if {e} {}", gen_block(rng, d)),
+            6 => format!("if {e} {} else if x {} else {}", gen_block(rng, d), gen_block(rng, d), gen_block(rng, d)),
+            7 => format!("outer: while {e}{}{}", space(rng), gen_block(rng, d)),
+            8 => format!("for (var i = 0; i < {e}; i = i + 1) {}", gen_block(rng, d)),
+            9 => format!("for c in {e} {}", gen_block(rng, d)),
+            10 => format!("match {e} {{ 1, -2 => {}{}'a' => {} _ => {} }}", gen_block(rng, d), space(rng), gen_block(rng, d), gen_block(rng, d)),
+            11 => format!("fun g(a: int,{}b: S[][]) -> int {}", space(rng), gen_block(rng, d)),
+            _ => format!("struct S {{ a: int,{}b: int[] }}", space(rng))
+        }
+    }
+
+    // the comments with all whitespace removed, lines can be joined when comments move
+    fn comment_text(src: &str) -> String {
+        parser::spellcode::comments(src).unwrap().iter().map(|x| x.item.split_whitespace().collect::<String>()).collect()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut rng = Rng(0x2545F4914F6CDD1D);
+        for _ in 0..500 {
+            let src = (0..rng.below(5)).map(|_| {
+                let statement = gen_statement(&mut rng, 3);
+                let end = if statement.ends_with(';') { "\n" } else { rng.pick(&["\n", ";", "\n\n"]) };
+                statement + end
+            }).collect::<String>();
+            let parsed = parser::parse_program(&src).unwrap_or_else(|e| panic!("generated an invalid program {e:?}\n{src}"));
+            let formatted = format_program(&src).unwrap();
+            let reparsed = parser::parse_program(&formatted).unwrap_or_else(|e| panic!("formatted program doesn't parse {e:?}\n{src}\n---\n{formatted}"));
+            // Tag's Debug leaves out the locations
+            assert_eq!(format!("{reparsed:?}"), format!("{parsed:?}"), "\n{src}\n---\n{formatted}");
+            assert_eq!(format(&formatted), formatted, "not idempotent\n{src}");
+            assert_eq!(comment_text(&formatted), comment_text(&src), "\n{src}\n---\n{formatted}");
+        }
+    }
+}
@@ -0,0 +1,114 @@
+use crate::{bytecode, stack_machine::{self, HeapLimit, Instruction, Tpe, VM}, world::{self, MockWorld}};
+
+// how many instructions a fuzzed program gets to run, enough to fill the heap limit below many
+// times over
+const MAX_STEPS: usize = 20000;
+
+const DOUBLES: [f64; 8] = [0.0, 1.0, -1.0, 0.5, 1e300, f64::INFINITY, f64::NEG_INFINITY, f64::NAN];
+
+// how deep the types get, the bytecode allows deeper but that's only more of the same
+const MAX_DEPTH: usize = 4;
+
+/// Turns any bytes into a program, each instruction an opcode byte and then a byte per operand.
+/// Unlike bytecode, there's no wrong input, so the fuzzer spends its time in the VM.  Operands
+/// are kept small so jumps, copies and fields mostly land somewhere, with 255 standing for a
+/// huge one
+pub fn program_from_bytes(data: &[u8]) -> Vec<Instruction> {
+    use Instruction::*;
+    let mut bytes = data.iter().copied();
+    let mut program = vec![];
+    while let Some(opcode) = bytes.next() {
+        let mut operand = || match bytes.next().unwrap_or(0) {
+            255 => usize::MAX,
+            x => x as usize
+        };
+        program.push(match opcode % 44 {
+            0 => ImmediateInt(match operand() {
+                usize::MAX => i32::MIN,
+                // sizes and indices, negative ones too
+                x => x as i32 - 32
+            }),
+            1 => ImmediateDouble(DOUBLES[operand() % DOUBLES.len()]),
+            2 => Pop(operand()),
+            3 => Copy(operand()),
+            4 => Set(operand()),
+            5 => AddI, 6 => SubI, 7 => MulI, 8 => DivI, 9 => ModI,
+            10 => AndI, 11 => OrI, 12 => XorI, 13 => ShlI, 14 => ShrI, 15 => ShrlI,
+            16 => LtI, 17 => GeI, 18 => NotI, 19 => EqI,
+            20 => AddD, 21 => SubD, 22 => MulD, 23 => DivD,
+            24 => LtD, 25 => GeD, 26 => EqD, 27 => IsInf, 28 => IsNaN,
+            29 => ConvID, 30 => ConvDI,
+            31 => Brz(operand()),
+            32 => Brnz(operand()),
+            33 => Jmp(operand()),
+            34 => Call(operand()),
+            35 => Return,
+            36 => Syscall(stack_machine::Syscall::ALL[operand() % stack_machine::Syscall::ALL.len()]),
+            37 => AllocA(tpe(&mut bytes, 0)),
+            38 => GetA, 39 => SetA, 40 => LenA,
+            41 => AllocS(tpe(&mut bytes, 0)),
+            42 => GetS(operand()),
+            _ => SetS(operand())
+        });
+    }
+    program
+}
+
+fn tpe(bytes: &mut impl Iterator<Item = u8>, depth: usize) -> Tpe {
+    let tag = bytes.next().unwrap_or(0);
+    match tag % 4 {
+        _ if depth >= MAX_DEPTH => Tpe::Int,
+        0 => Tpe::Int,
+        1 => Tpe::Double,
+        2 => Tpe::Array(Box::new(tpe(bytes, depth + 1))),
+        // AllocS insists on a struct, so the field count comes from the same byte
+        _ => Tpe::Struct((0..tag / 4 % 4).map(|_| tpe(bytes, depth + 1)).collect())
+    }
+}
+
+/// Runs the bytes as a program against a mock world, and reads them as bytecode, running that
+/// too if it loads.  Whatever the program does, this should return rather than panic
+pub fn run(data: &[u8]) {
+    run_program(program_from_bytes(data));
+    if let Ok(program) = bytecode::read_program(data) {
+        run_program(program);
+    }
+}
+
+fn run_program(program: Vec<Instruction>) {
+    let mut vm = VM::new(program);
+    // small enough that running out, and collecting to make room, happens often
+    vm.heap_limit = HeapLimit { cells: 2000, objects: 200 };
+    vm.gc.budget = 8;
+    let _ = world::run(&mut vm, &mut MockWorld::default(), Some(MAX_STEPS));
+    vm.stack_trace();
+    vm.garbage_collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::Rng;
+
+    #[test]
+    fn test_random_programs() {
+        // without libfuzzer, random bytes from a fixed seed have to do
+        let mut rng = Rng(0x2545F4914F6CDD1D);
+        for _ in 0..2000 {
+            let len = rng.below(64);
+            let data = (0..len).map(|_| rng.next() as u8).collect::<Vec<_>>();
+            run(&data);
+        }
+    }
+
+    #[test]
+    fn test_program_from_bytes() {
+        let program = program_from_bytes(&[0, 42, 37, 7, 6, 0, 33, 255, 41, 1]);
+        assert_eq!(format!("{program:?}"), format!("{:?}", vec![
+            Instruction::ImmediateInt(10),
+            Instruction::AllocA(Tpe::Struct(vec![Tpe::Array(Box::new(Tpe::Int))])),
+            Instruction::Jmp(usize::MAX),
+            Instruction::AllocS(Tpe::Double)
+        ]));
+    }
+}
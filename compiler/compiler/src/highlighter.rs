@@ -0,0 +1,291 @@
+use std::ops::Range;
+
+use crate::parser::{self, Expression, Lexeme, Statement, Tag, TypeName};
+
+// words the grammar treats specially, nothing stops them from being used as names though
+const KEYWORDS: [&str; 13] = ["var", "if", "else", "match", "for", "in", "while", "return", "break", "continue", "fun", "struct", "new"];
+const BASIC_TYPES: [&str; 5] = ["int", "double", "char", "string", "bool"];
+
+/// What a token is, for choosing its colour
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+    Keyword = 0,
+    // variables, parameters and loop labels
+    Identifier = 1,
+    Type = 2,
+    // a function's name where it's called or declared
+    Function = 3,
+    Field = 4,
+    Literal = 5,
+    Operator = 6,
+    Comment = 7,
+    // where the parser gave up, or something no rule accepts
+    Error = 8
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SemanticToken {
+    pub kind: TokenKind,
+    // byte range into the source
+    pub location: Range<usize>
+}
+
+/// Splits a spell into highlighted tokens, in order.  Brackets and other punctuation are left
+/// out.  Names are told apart by where they are in the parsed spell, so a broken statement is
+/// only highlighted by its keywords and its tokens
+pub fn semantic_tokens(src: &str) -> Vec<SemanticToken> {
+    let (program, errors) = parser::parse_recovering(src);
+    let mut names = Names::default();
+    for statement in program.iter().flatten() {
+        names.statement(statement);
+    }
+
+    // can't fail, anything unexpected becomes an unknown token
+    let lexemes = parser::spellcode::tokens(src).unwrap();
+    let mut tokens: Vec<SemanticToken> = vec![];
+    for Tag { item: lexeme, loc, .. } in &lexemes {
+        let literal = names.literals.iter().find(|x| x.start <= loc.start && loc.end <= x.end);
+        let in_literal = literal.is_some();
+        let kind = match lexeme {
+            Lexeme::Comment => TokenKind::Comment,
+            Lexeme::Literal => TokenKind::Literal,
+            // the sign of a negative number
+            Lexeme::Operator if in_literal => TokenKind::Literal,
+            Lexeme::Operator => TokenKind::Operator,
+            Lexeme::Punctuation => continue,
+            Lexeme::Unknown if names.defaults.contains(loc) => TokenKind::Keyword,
+            Lexeme::Unknown => TokenKind::Error,
+            Lexeme::Word => {
+                let word = &src[loc.clone()];
+                if let Some((_, kind)) = names.kinds.iter().find(|x| x.0 == *loc) {
+                    *kind
+                } else if in_literal {
+                    TokenKind::Literal
+                } else if KEYWORDS.contains(&word) {
+                    TokenKind::Keyword
+                } else if BASIC_TYPES.contains(&word) {
+                    TokenKind::Type
+                } else {
+                    TokenKind::Identifier
+                }
+            }
+        };
+        // a negative number is lexed as the minus and then the digits
+        if let Some(literal) = literal && let Some(last) = tokens.last_mut() && last.location.start >= literal.start {
+            last.location.end = loc.end;
+            continue;
+        }
+        tokens.push(SemanticToken { kind, location: loc.clone() });
+    }
+
+    for error in &errors {
+        let at = error.location.offset;
+        if let Some(token) = tokens.iter_mut().find(|x| x.location.contains(&at)) {
+            token.kind = TokenKind::Error;
+        } else if let Some(c) = src[at..].chars().next() {
+            // punctuation, which isn't otherwise a token
+            let index = tokens.partition_point(|x| x.location.start < at);
+            tokens.insert(index, SemanticToken { kind: TokenKind::Error, location: at..at + c.len_utf8() });
+        }
+    }
+    tokens
+}
+
+// the names in the parsed spell and what they are, found by walking the tree
+#[derive(Default)]
+struct Names {
+    kinds: Vec<(Range<usize>, TokenKind)>,
+    literals: Vec<Range<usize>>,
+    // the `_` arms of matches
+    defaults: Vec<Range<usize>>
+}
+
+impl Names {
+    fn add(&mut self, name: &Tag<String>, kind: TokenKind) {
+        self.kinds.push((name.loc.clone(), kind));
+    }
+
+    fn block(&mut self, block: &[Statement]) {
+        for statement in block {
+            self.statement(statement);
+        }
+    }
+
+    fn statement(&mut self, statement: &Statement) {
+        match statement {
+            Statement::ExprS(expr) => self.expression(expr),
+            Statement::VariableDecl(name, value) => {
+                self.add(name, TokenKind::Identifier);
+                self.expression(value);
+            }
+            Statement::Assignment { left, value } => {
+                self.expression(left);
+                self.expression(value);
+            }
+            Statement::If { condition, block, else_block } => {
+                self.expression(condition);
+                self.block(block);
+                if let Some(else_block) = else_block {
+                    self.block(else_block);
+                }
+            }
+            Statement::Match { value, arms, default, .. } => {
+                self.expression(value);
+                for (patterns, block) in arms {
+                    self.literals.extend(patterns.iter().map(|x| x.loc.clone()));
+                    self.block(block);
+                }
+                if let Some((underscore, block)) = default {
+                    self.defaults.push(underscore.loc.clone());
+                    self.block(block);
+                }
+            }
+            Statement::CFor { label, init, condition, increment, block } => {
+                if let Some(label) = label {
+                    self.add(label, TokenKind::Identifier);
+                }
+                if let Some(init) = init.as_ref() {
+                    self.statement(init);
+                }
+                self.expression(condition);
+                if let Some(increment) = increment.as_ref() {
+                    self.statement(increment);
+                }
+                self.block(block);
+            }
+            Statement::ForEach { label, variable, array, block } => {
+                if let Some(label) = label {
+                    self.add(label, TokenKind::Identifier);
+                }
+                self.add(variable, TokenKind::Identifier);
+                self.expression(array);
+                self.block(block);
+            }
+            Statement::While { label, condition, block } => {
+                if let Some(label) = label {
+                    self.add(label, TokenKind::Identifier);
+                }
+                self.expression(condition);
+                self.block(block);
+            }
+            Statement::Return { expr, .. } => {
+                if let Some(expr) = expr {
+                    self.expression(expr);
+                }
+            }
+            Statement::Break { label, .. } | Statement::Continue { label, .. } => {
+                if let Some(label) = label {
+                    self.add(label, TokenKind::Identifier);
+                }
+            }
+            Statement::FunctionDef { name, arguments, return_type, block, .. } => {
+                self.add(name, TokenKind::Function);
+                for (arg, tpe) in arguments {
+                    self.add(arg, TokenKind::Identifier);
+                    self.type_name(tpe);
+                }
+                if let Some(tpe) = return_type {
+                    self.type_name(tpe);
+                }
+                self.block(block);
+            }
+            Statement::StructDef { name, fields } => {
+                self.add(name, TokenKind::Type);
+                for (field, tpe) in fields {
+                    self.add(field, TokenKind::Field);
+                    self.type_name(tpe);
+                }
+            }
+        }
+    }
+
+    fn expression(&mut self, expr: &Tag<Expression>) {
+        match &expr.item {
+            Expression::Lit(lit) => self.literals.push(lit.loc.clone()),
+            Expression::Math(box left, _, box right) | Expression::ArrayAccess { array: box left, index: box right } => {
+                self.expression(left);
+                self.expression(right);
+            }
+            Expression::FunctionCall { name, args } => {
+                self.add(name, TokenKind::Function);
+                for arg in args {
+                    self.expression(arg);
+                }
+            }
+            Expression::PropertyAccess(box inner, name) => {
+                self.expression(inner);
+                self.add(name, TokenKind::Field);
+            }
+            Expression::Ternary { box condition, box if_true, box if_false } => {
+                self.expression(condition);
+                self.expression(if_true);
+                self.expression(if_false);
+            }
+            Expression::VarAccess(name) => self.add(name, TokenKind::Identifier),
+            Expression::NewArray(tpe, box length) => {
+                self.type_name(tpe);
+                self.expression(length);
+            }
+            Expression::UnaryOperation(_, box inner) => self.expression(inner),
+            Expression::NewStruct(name) => self.add(name, TokenKind::Type)
+        }
+    }
+
+    fn type_name(&mut self, tpe: &Tag<TypeName>) {
+        match &tpe.item {
+            TypeName::Array(box inner) => self.type_name(inner),
+            TypeName::Struct(name) => self.add(name, TokenKind::Type),
+            _ => self.kinds.push((tpe.loc.clone(), TokenKind::Type))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // each token's text and kind
+    fn highlight(src: &str) -> Vec<(&str, TokenKind)> {
+        semantic_tokens(src).into_iter().map(|x| (&src[x.location], x.kind)).collect()
+    }
+
+    #[test]
+    fn test_tokens() {
+        use TokenKind::*;
+        assert_eq!(highlight("var x = 0x1F >>> -2 // shift\nx = 'a'"), vec![
+            ("var", Keyword), ("x", Identifier), ("=", Operator), ("0x1F", Literal), (">>>", Operator), ("-2", Literal), ("// shift", Comment),
+            ("x", Identifier), ("=", Operator), ("'a'", Literal)
+        ]);
+        assert_eq!(highlight("struct Node { q: int }\nvar n = new Node[0b10]\nprintln(n[0].q - 1.5e3)"), vec![
+            ("struct", Keyword), ("Node", Type), ("q", Field), ("int", Type),
+            ("var", Keyword), ("n", Identifier), ("=", Operator), ("new", Keyword), ("Node", Type), ("0b10", Literal),
+            ("println", Function), ("n", Identifier), ("0", Literal), ("q", Field), ("-", Operator), ("1.5e3", Literal)
+        ]);
+        assert_eq!(highlight("fun f(s: string[]) -> bool { return true }"), vec![
+            ("fun", Keyword), ("f", Function), ("s", Identifier), ("string", Type), ("->", Operator), ("bool", Type),
+            ("return", Keyword), ("true", Literal)
+        ]);
+        assert_eq!(highlight("outer: for c in \"a/*b\" { match c { 'a' => { break outer } _ => { } } }"), vec![
+            ("outer", Identifier), ("for", Keyword), ("c", Identifier), ("in", Keyword), ("\"a/*b\"", Literal),
+            ("match", Keyword), ("c", Identifier), ("'a'", Literal), ("=>", Operator), ("break", Keyword), ("outer", Identifier),
+            ("_", Keyword), ("=>", Operator)
+        ]);
+        // names the grammar allows even though they're keywords elsewhere
+        assert_eq!(highlight("var int = 1"), vec![("var", Keyword), ("int", Identifier), ("=", Operator), ("1", Literal)]);
+    }
+
+    #[test]
+    fn test_error_tokens() {
+        use TokenKind::*;
+        // the broken statement keeps its keywords, and the rest is still classified
+        assert_eq!(highlight("var x = 1 +* 2\nf(x)"), vec![
+            ("var", Keyword), ("x", Identifier), ("=", Operator), ("1", Literal), ("+", Operator), ("*", Error), ("2", Literal),
+            ("f", Function), ("x", Identifier)
+        ]);
+        assert_eq!(highlight("var a = (1]"), vec![("var", Keyword), ("a", Identifier), ("=", Operator), ("1", Literal), ("]", Error)]);
+        assert_eq!(highlight("var s = \"open\nx # y"), vec![
+            ("var", Keyword), ("s", Identifier), ("=", Operator), ("\"", Error), ("open", Identifier),
+            ("x", Identifier), ("#", Error), ("y", Identifier)
+        ]);
+    }
+}
@@ -1,12 +1,20 @@
 #![feature(box_patterns)]
+// the FFI entry points take raw pointers straight from C#
+#![allow(clippy::not_unsafe_ptr_arg_deref)]
 
 use std::{ffi::{CStr, CString}, sync::Mutex};
 
-use crate::{compiler::Compiler, stack_machine::{StackItem, VM}};
+use crate::{compiler::Compiler, diagnostics::{Diagnostic, Severity}, stack_machine::{StackItem, VM}};
 
 mod stack_machine;
 mod parser;
 mod compiler;
+mod diagnostics;
+mod formatter;
+mod highlighter;
+mod bytecode;
+#[cfg(test)]
+mod rng;
 
 struct VMs {
     vms: Vec<(i64, VM)>,
@@ -26,7 +34,88 @@ pub struct CompileResult {
     error: *mut i8,
     // the start and end (exclusive) of the error, or both -1 if there isn't one
     error_start: i64,
-    error_end: i64
+    error_end: i64,
+    // every diagnostic, read with compileresult_diagnostic_count and
+    // compileresult_get_diagnostic
+    diagnostics: *mut CompileDiagnostics
+}
+
+// Owns the diagnostics of a CompileResult, including the message strings handed
+// out by compileresult_get_diagnostic
+pub struct CompileDiagnostics {
+    items: Vec<(Diagnostic, CString)>
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct SemanticTokenInfo {
+    // 0 keyword, 1 identifier, 2 type, 3 function, 4 struct field, 5 literal,
+    // 6 operator, 7 comment, 8 error
+    kind: i64,
+    // the start and end (exclusive) byte offsets
+    start: i64,
+    end: i64
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct SourceLocation {
+    // the start and end (exclusive) byte offsets of the statement
+    start: i64,
+    end: i64,
+    // the name of the function it's in, empty for the top level code.  Freed
+    // with free_source_location
+    function: *mut i8
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct FrameInfo {
+    // the instruction being run, a call in all but the innermost frame
+    pc: i64,
+    // the start and end (exclusive) byte offsets of the statement, or both -1
+    // if it isn't in the spell's code
+    start: i64,
+    end: i64,
+    // the name of the function, empty for the top level code or if it isn't
+    // known.  Freed with free_stack_trace
+    function: *mut i8
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct VariableInfo {
+    // the variable's name and type, and its value written out as it would be in
+    // a spell.  All freed with free_variables
+    name: *mut i8,
+    tpe: *mut i8,
+    value: *mut i8
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct HeapUsage {
+    // the heap's size, counting each object and each value in one as a cell,
+    // and its limits
+    cells: i64,
+    objects: i64,
+    max_cells: i64,
+    max_objects: i64
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct DiagnosticInfo {
+    // the message, owned by the CompileResult it came from
+    message: *const i8,
+    // the start and end (exclusive) byte offsets
+    start: i64,
+    end: i64,
+    // 1-based line and column of the start
+    line: i64,
+    column: i64,
+    // 0 for errors, 1 for warnings
+    severity: i64
 }
 
 /// Frees the error string from a CompileResult, must be called after compile()
@@ -39,6 +128,43 @@ pub extern "C" fn free_compileresult(inp: *const CompileResult) {
     if !v.error.is_null() {
         drop(unsafe { CString::from_raw(v.error) });
     }
+    if !v.diagnostics.is_null() {
+        drop(unsafe { Box::from_raw(v.diagnostics) });
+    }
+}
+
+/// Returns the number of diagnostics a compile produced, errors if it failed and
+/// warnings if it succeeded
+#[unsafe(no_mangle)]
+pub extern "C" fn compileresult_diagnostic_count(inp: *const CompileResult) -> i64 {
+    let v = unsafe { *inp };
+    if v.diagnostics.is_null() {
+        return 0;
+    }
+    unsafe { &*v.diagnostics }.items.len() as i64
+}
+
+/// Gets the diagnostic at the given index from a CompileResult, storing it in
+/// out.  Returns false if the index is out of range.  The message stays valid
+/// until free_compileresult is called.
+#[unsafe(no_mangle)]
+pub extern "C" fn compileresult_get_diagnostic(inp: *const CompileResult, index: i64, out: *mut DiagnosticInfo) -> bool {
+    let v = unsafe { *inp };
+    if v.diagnostics.is_null() {
+        return false;
+    }
+    let Some((diagnostic, message)) = usize::try_from(index).ok().and_then(|i| unsafe { &*v.diagnostics }.items.get(i)) else { return false; };
+    unsafe {
+        *out = DiagnosticInfo {
+            message: message.as_ptr(),
+            start: diagnostic.location.start as i64,
+            end: diagnostic.location.end as i64,
+            line: diagnostic.line as i64,
+            column: diagnostic.column as i64,
+            severity: match diagnostic.severity { Severity::Error => 0, Severity::Warning => 1 }
+        };
+    }
+    true
 }
 
 /// Reinitializes the global stack machine registry, deleting all existing VMs
@@ -62,6 +188,7 @@ pub extern "C" fn init() {
 ///  -9: illegal jump address
 /// -10: array index out of bounds
 /// -11: illegal syscall argument
+/// -12: paused by the debugger, before the instruction that would have run
 #[unsafe(no_mangle)]
 pub extern "C" fn run_to_syscall_or_n(id: i64, max_instructions: i32, executed: *mut i32) -> i32 {
     let mut vms = VMS.lock().unwrap();
@@ -78,10 +205,14 @@ pub extern "C" fn run_to_syscall_or_n(id: i64, max_instructions: i32, executed:
             Err(stack_machine::ExecutionException::RaisedException) => return -8,
             Err(stack_machine::ExecutionException::IllegalJumpAddress) => return -9,
             Err(stack_machine::ExecutionException::ArrayIndexOutOfBounds) => return -10,
-            Err(stack_machine::ExecutionException::IllegalSyscallArgument) => return -11
+            Err(stack_machine::ExecutionException::IllegalSyscallArgument) => return -11,
+            Err(stack_machine::ExecutionException::Paused) => {
+                unsafe { *executed -= 1 };
+                return -12
+            }
         }
     }
-    return -1;
+    -1
 }
 
 /// Pushes an integer onto the specified VM's stack.  Returns true on success
@@ -90,7 +221,7 @@ pub extern "C" fn push_int(id: i64, value: i32) -> bool {
     let mut vms = VMS.lock().unwrap();
     let Some(found) = vms.vms.iter_mut().find(|x| x.0 == id) else { return false; };
     found.1.stack.push(StackItem::Int(value));
-    return true;
+    true
 }
 
 /// Pushes a double onto the specified VM's stack.  Returns true on success
@@ -99,7 +230,7 @@ pub extern "C" fn push_double(id: i64, value: f64) -> bool {
     let mut vms = VMS.lock().unwrap();
     let Some(found) = vms.vms.iter_mut().find(|x| x.0 == id) else { return false; };
     found.1.stack.push(StackItem::Double(value));
-    return true;
+    true
 }
 
 /// Pops an int from the specified VM's stack, and puts it in out.  Returns
@@ -150,18 +281,20 @@ fn ptr_to_vec<T : Copy>(inp: *mut T, size: u64) -> Vec<T> {
 }
 
 /// Pushes an integer array to the specified VM's stack.  Returns true on
-/// success.  The caller is responsible for freeing the array.
+/// success, or false if there's no such VM or the array doesn't fit in its heap
+/// limit.  The caller is responsible for freeing the array.
 #[unsafe(no_mangle)]
 pub extern "C" fn push_int_array(id: i64, data: *mut i32, length: u64) -> bool {
     let mut vms = VMS.lock().unwrap();
     let Some(found) = vms.vms.iter_mut().find(|x| x.0 == id) else { return false; };
     let value = ptr_to_vec(data, length);
     //let value = vec![unsafe { length as i32 }, 0];
-    let n = found.1.next_heap_addr;
-    found.1.next_heap_addr += 1;
-    found.1.heap.insert(n, stack_machine::HeapItem { value: value.iter().map(|x| StackItem::Int(*x)).collect(), mark: false, tpe: stack_machine::Tpe::Array(Box::new(stack_machine::Tpe::Int)) });
+    if found.1.reserve(1 + value.len(), 1).is_err() {
+        return false;
+    }
+    let n = found.1.push_heap_item(value.iter().map(|x| StackItem::Int(*x)).collect(), stack_machine::Tpe::Array(Box::new(stack_machine::Tpe::Int)));
     found.1.stack.push(StackItem::HeapAddr(stack_machine::Tpe::Array(Box::new(stack_machine::Tpe::Int)), n));
-    return true;
+    true
 }
 
 /// Frees an int array from pop_int_array
@@ -194,8 +327,14 @@ pub extern "C" fn pop_int_array(id: i64, data: *mut *mut i32, length: *mut u64)
         }
         return false;
     };
-    if let StackItem::HeapAddr(stack_machine::Tpe::Int, ptr) = popped {
-        let items = found.1.heap[&ptr].value.iter().map(|x| if let StackItem::Int(v) = x { *v } else { panic!() }).collect::<Vec<_>>();
+    // anything but an array of ints fails, however the program managed to put it there
+    let items = match popped {
+        StackItem::HeapAddr(stack_machine::Tpe::Int, ptr) => found.1.heap.get(ptr).and_then(|x| x.value.iter()
+            .map(|x| if let StackItem::Int(v) = x { Some(*v) } else { None })
+            .collect::<Option<Vec<_>>>()),
+        _ => None
+    };
+    if let Some(items) = items {
         unsafe { 
             *length = items.len() as u64;
             *data = vec_to_ptr(items);
@@ -217,8 +356,9 @@ pub extern "C" fn pop_int_array(id: i64, data: *mut *mut i32, length: *mut u64)
 /// start and end are set to -1.  ID is set to the VM's ID, and it can be run
 /// using run_to_syscall_or_n.
 ///
-/// On failed compilation, output.error describes the problem, and the error
-/// start and end indices indicate where the error is.  The ID is set to -1.
+/// On failed compilation, output.error describes the first problem, and the
+/// error start and end indices indicate where it is.  The ID is set to -1.
+/// Every problem found can be read with compileresult_get_diagnostic.
 ///
 /// After every invocation, call free_compileresult.
 #[unsafe(no_mangle)]
@@ -228,30 +368,334 @@ pub extern "C" fn compile(program: *const i8, output: *mut CompileResult) {
     res.id = -1;
     res.error_start = -1;
     res.error_end = -1;
+    res.diagnostics = std::ptr::null_mut();
 
-    let parsed = match parser::spellcode::program(&inp) {
+    let parsed = match parser::parse_program(&inp) {
         Ok(v) => v,
         Err(e) => {
-            let error = CString::new(format!("parser error = {e:?}, inp = \"{inp}\" = {:?} = {:?}", inp.chars().collect::<Vec<_>>(), inp.chars().map(|x| format!("{:02x}", u32::from(x))).collect::<Vec<_>>())).unwrap();
-            res.error = error.into_raw();
+            set_errors(res, e.iter().map(|x| Diagnostic::from_parse_error(&inp, x)).collect());
             return;
         }
     };
         
     let mut compiler = Compiler::new();
     if let Err(e) = compiler.compile_program(&parsed) {
-        res.error_start = e.location.start as i64;
-        res.error_end = e.location.end as i64;
-        let error = CString::new(format!("{:?}", e.error)).unwrap();
-        res.error = error.into_raw();
+        set_errors(res, e.iter().map(|x| Diagnostic::from_comp_err(&inp, x)).collect());
         return;
     }
+    let warnings = compiler.warnings.iter().map(|x| {
+        let diagnostic = Diagnostic::from_comp_warning(&inp, x);
+        let message = CString::new(diagnostic.to_string()).unwrap();
+        (diagnostic, message)
+    }).collect();
+    res.diagnostics = Box::into_raw(Box::new(CompileDiagnostics { items: warnings }));
     let error = CString::new("success").unwrap();
     res.error = error.into_raw();
     let mut vms = VMS.lock().unwrap();
     let id = vms.next_id;
     res.id = id;
     vms.next_id += 1;
-    vms.vms.push((id, VM::new(compiler.program)));
+    let source_map = compiler.source_map(&inp);
+    let mut vm = VM::new(compiler.program);
+    vm.source_map = Some(source_map);
+    vms.vms.push((id, vm));
+}
+
+fn set_errors(res: &mut CompileResult, diagnostics: Vec<Diagnostic>) {
+    let first = &diagnostics[0];
+    res.error_start = first.location.start as i64;
+    res.error_end = first.location.end as i64;
+    res.error = CString::new(first.to_string()).unwrap().into_raw();
+
+    let items = diagnostics.into_iter().map(|x| {
+        let message = CString::new(x.to_string()).unwrap();
+        (x, message)
+    }).collect();
+    res.diagnostics = Box::into_raw(Box::new(CompileDiagnostics { items }));
+}
+
+/// Formats the given program, returning the formatted source, or null if it
+/// doesn't parse.  The result must be freed with free_formatted.
+#[unsafe(no_mangle)]
+pub extern "C" fn format_program(program: *const i8) -> *mut i8 {
+    let inp = unsafe { CStr::from_ptr(program) }.to_string_lossy();
+    match formatter::format_program(&inp) {
+        Ok(formatted) => CString::new(formatted).unwrap().into_raw(),
+        Err(_) => std::ptr::null_mut()
+    }
+}
+
+/// Frees a string from format_program
+#[unsafe(no_mangle)]
+pub extern "C" fn free_formatted(formatted: *mut i8) {
+    if !formatted.is_null() {
+        drop(unsafe { CString::from_raw(formatted) });
+    }
+}
+
+/// Splits the given program into tokens for syntax highlighting, storing the
+/// array base pointer into tokens and the length into length.  Punctuation is
+/// left out.  The array must be freed with free_semantic_tokens.
+#[unsafe(no_mangle)]
+pub extern "C" fn semantic_tokens(program: *const i8, tokens: *mut *mut SemanticTokenInfo, length: *mut u64) {
+    let inp = unsafe { CStr::from_ptr(program) }.to_string_lossy();
+    let items = highlighter::semantic_tokens(&inp).into_iter().map(|x| SemanticTokenInfo {
+        kind: x.kind as i64,
+        start: x.location.start as i64,
+        end: x.location.end as i64
+    }).collect::<Vec<_>>();
+    unsafe {
+        *length = items.len() as u64;
+        *tokens = vec_to_ptr(items);
+    }
+}
+
+/// Frees a token array from semantic_tokens
+#[unsafe(no_mangle)]
+pub extern "C" fn free_semantic_tokens(tokens: *mut SemanticTokenInfo, length: u64) {
+    let slice = unsafe { std::slice::from_raw_parts_mut(tokens, length as usize) };
+    unsafe {
+        drop(Box::from_raw(slice));
+    }
 }
 
+/// Serializes the program of the specified VM, so it can be stored and later
+/// loaded with load_bytecode instead of compiling the spell again.  Returns
+/// true on success, and stores the bytes in data and their count in length,
+/// which must be freed with free_bytecode.  On failure, because there's no
+/// such VM or its program is too large to encode, it will set data and
+/// length to hold an empty array, which must still be freed.
+#[unsafe(no_mangle)]
+pub extern "C" fn save_bytecode(id: i64, data: *mut *mut u8, length: *mut u64) -> bool {
+    let vms = VMS.lock().unwrap();
+    let bytes = vms.vms.iter().find(|x| x.0 == id).and_then(|x| bytecode::write_program(&x.1.program).ok());
+    let saved = bytes.is_some();
+    let bytes = bytes.unwrap_or_default();
+    unsafe {
+        *length = bytes.len() as u64;
+        *data = vec_to_ptr(bytes);
+    }
+    saved
+}
+
+/// Frees the bytes from save_bytecode
+#[unsafe(no_mangle)]
+pub extern "C" fn free_bytecode(data: *mut u8, length: u64) {
+    let slice = unsafe { std::slice::from_raw_parts_mut(data, length as usize) };
+    unsafe {
+        drop(Box::from_raw(slice));
+    }
+}
+
+/// Spawns a VM to execute a program from save_bytecode, or either form
+/// written by compiler-bin build.  The VM is not automatically started.
+/// Returns the VM's ID, or a code representing why it couldn't be loaded
+///  -1: not bytecode
+///  -2: written by a different version of the compiler
+///  -3: corrupted, it doesn't match its checksum
+///  -4: malformed
+/// The VM must be freed with free_vm.
+#[unsafe(no_mangle)]
+pub extern "C" fn load_bytecode(data: *const u8, length: u64) -> i64 {
+    let bytes = unsafe { std::slice::from_raw_parts(data, length as usize) };
+    let program = match bytecode::read_program(bytes) {
+        Ok(v) => v,
+        Err(bytecode::BytecodeError::NotBytecode) => return -1,
+        Err(bytecode::BytecodeError::UnsupportedVersion(_)) => return -2,
+        Err(bytecode::BytecodeError::BadChecksum) => return -3,
+        Err(_) => return -4
+    };
+    let mut vms = VMS.lock().unwrap();
+    let id = vms.next_id;
+    vms.next_id += 1;
+    vms.vms.push((id, VM::new(program)));
+    id
+}
+
+/// Deletes a VM, such as one from load_bytecode.  Returns false if there's no
+/// such VM
+#[unsafe(no_mangle)]
+pub extern "C" fn free_vm(id: i64) -> bool {
+    let mut vms = VMS.lock().unwrap();
+    let count = vms.vms.len();
+    vms.vms.retain(|x| x.0 != id);
+    vms.vms.len() != count
+}
+
+/// Finds where in the spell the specified VM is, which after run_to_syscall_or_n
+/// returns an error is the statement that failed.  Returns true on success, and
+/// stores the location in out, which must be freed with free_source_location.
+/// Fails if there's no such VM, or it was loaded from bytecode, or it's in code
+/// the compiler added rather than the spell's, such as the standard library.
+#[unsafe(no_mangle)]
+pub extern "C" fn source_location(id: i64, out: *mut SourceLocation) -> bool {
+    unsafe {
+        *out = SourceLocation { start: -1, end: -1, function: std::ptr::null_mut() };
+    }
+    let vms = VMS.lock().unwrap();
+    let Some(found) = vms.vms.iter().find(|x| x.0 == id) else { return false; };
+    let Some(span) = found.1.current_span() else { return false; };
+    let function = found.1.current_function().map_or(String::new(), |x| x.name.clone());
+    unsafe {
+        *out = SourceLocation {
+            start: span.start as i64,
+            end: span.end as i64,
+            function: CString::new(function).unwrap().into_raw()
+        };
+    }
+    true
+}
+
+/// Frees the function name from a SourceLocation
+#[unsafe(no_mangle)]
+pub extern "C" fn free_source_location(inp: *const SourceLocation) {
+    let v = unsafe { *inp };
+    if !v.function.is_null() {
+        drop(unsafe { CString::from_raw(v.function) });
+    }
+}
+
+/// Gets the function calls in progress in the specified VM, innermost first,
+/// such as after run_to_syscall_or_n returns an error.  Returns true on
+/// success, and stores the frames in frames and their count in length, which
+/// must be freed with free_stack_trace.  On failure, it will set frames and
+/// length to hold an empty array, which must still be freed.
+#[unsafe(no_mangle)]
+pub extern "C" fn stack_trace(id: i64, frames: *mut *mut FrameInfo, length: *mut u64) -> bool {
+    let vms = VMS.lock().unwrap();
+    let found = vms.vms.iter().find(|x| x.0 == id);
+    let items = found.map_or(vec![], |x| x.1.stack_trace()).into_iter().map(|x| {
+        let (start, end) = x.span.map_or((-1, -1), |x| (x.start as i64, x.end as i64));
+        let function = x.function.map_or(String::new(), |x| x.name);
+        FrameInfo { pc: x.pc as i64, start, end, function: CString::new(function).unwrap().into_raw() }
+    }).collect::<Vec<_>>();
+    unsafe {
+        *length = items.len() as u64;
+        *frames = vec_to_ptr(items);
+    }
+    found.is_some()
+}
+
+/// Frees the frames from stack_trace, and their function names
+#[unsafe(no_mangle)]
+pub extern "C" fn free_stack_trace(frames: *mut FrameInfo, length: u64) {
+    let slice = unsafe { std::slice::from_raw_parts_mut(frames, length as usize) };
+    for frame in slice.iter() {
+        drop(unsafe { CString::from_raw(frame.function) });
+    }
+    unsafe {
+        drop(Box::from_raw(slice));
+    }
+}
+
+/// Makes the specified VM pause at the statements on a line of its spell,
+/// counting from 1, when run_to_syscall_or_n reaches them.  Returns false if
+/// there's no such VM, it was loaded from bytecode, or no statement starts on
+/// the line
+#[unsafe(no_mangle)]
+pub extern "C" fn set_breakpoint(id: i64, line: i64) -> bool {
+    let mut vms = VMS.lock().unwrap();
+    let Some(found) = vms.vms.iter_mut().find(|x| x.0 == id) else { return false; };
+    line > 0 && found.1.set_breakpoint(line as usize)
+}
+
+/// Removes the breakpoints from a line.  Returns false if there weren't any
+#[unsafe(no_mangle)]
+pub extern "C" fn clear_breakpoint(id: i64, line: i64) -> bool {
+    let mut vms = VMS.lock().unwrap();
+    let Some(found) = vms.vms.iter_mut().find(|x| x.0 == id) else { return false; };
+    line > 0 && found.1.clear_breakpoint(line as usize)
+}
+
+/// Makes the specified VM pause again once run_to_syscall_or_n has run a step
+/// of the spell, where the step is
+///  0: into, to the next statement, even in a function being called
+///  1: over, to the next statement in the same function or the one it returns to
+///  2: out, to the next statement after the current function returns
+/// Returns false if there's no such VM or no such step
+#[unsafe(no_mangle)]
+pub extern "C" fn debug_step(id: i64, step: i32) -> bool {
+    let step = match step {
+        0 => stack_machine::Step::Into,
+        1 => stack_machine::Step::Over,
+        2 => stack_machine::Step::Out,
+        _ => return false
+    };
+    let mut vms = VMS.lock().unwrap();
+    let Some(found) = vms.vms.iter_mut().find(|x| x.0 == id) else { return false; };
+    found.1.step(step);
+    true
+}
+
+/// Gets the line of the spell, counting from 1, that the specified VM is on,
+/// or -1 if it isn't known
+#[unsafe(no_mangle)]
+pub extern "C" fn current_line(id: i64) -> i64 {
+    let vms = VMS.lock().unwrap();
+    let Some(found) = vms.vms.iter().find(|x| x.0 == id) else { return -1; };
+    found.1.current_line().map_or(-1, |x| x as i64)
+}
+
+/// Gets the variables in scope in a frame of the specified VM's stack trace,
+/// 0 being the innermost, oldest first.  Returns true on success, and stores
+/// them in variables and their count in length, which must be freed with
+/// free_variables.  On failure, it will set variables and length to hold an
+/// empty array, which must still be freed.
+#[unsafe(no_mangle)]
+pub extern "C" fn variables(id: i64, frame: i64, variables: *mut *mut VariableInfo, length: *mut u64) -> bool {
+    let vms = VMS.lock().unwrap();
+    let found = vms.vms.iter().find(|x| x.0 == id).and_then(|x| x.1.variables(usize::try_from(frame).ok()?));
+    let succeeded = found.is_some();
+    let items = found.unwrap_or_default().into_iter().map(|x| VariableInfo {
+        name: CString::new(x.name).unwrap().into_raw(),
+        tpe: CString::new(x.tpe.to_string()).unwrap().into_raw(),
+        // strings and chars come out escaped, so there's never a nul
+        value: CString::new(x.value).unwrap().into_raw()
+    }).collect::<Vec<_>>();
+    unsafe {
+        *length = items.len() as u64;
+        *variables = vec_to_ptr(items);
+    }
+    succeeded
+}
+
+/// Frees the variables from variables, and their strings
+#[unsafe(no_mangle)]
+pub extern "C" fn free_variables(variables: *mut VariableInfo, length: u64) {
+    let slice = unsafe { std::slice::from_raw_parts_mut(variables, length as usize) };
+    for variable in slice.iter() {
+        drop(unsafe { CString::from_raw(variable.name) });
+        drop(unsafe { CString::from_raw(variable.tpe) });
+        drop(unsafe { CString::from_raw(variable.value) });
+    }
+    unsafe {
+        drop(Box::from_raw(slice));
+    }
+}
+
+/// Gets how much of its heap the specified VM is using, and how much it's
+/// allowed.  Garbage counts until it's collected.  Returns true on success
+#[unsafe(no_mangle)]
+pub extern "C" fn heap_usage(id: i64, out: *mut HeapUsage) -> bool {
+    let vms = VMS.lock().unwrap();
+    let Some(found) = vms.vms.iter().find(|x| x.0 == id) else { return false; };
+    let (cells, objects) = found.1.heap_usage();
+    let limit = found.1.heap_limit;
+    unsafe {
+        *out = HeapUsage { cells: cells as i64, objects: objects as i64, max_cells: limit.cells as i64, max_objects: limit.objects as i64 };
+    }
+    true
+}
+
+/// Limits the specified VM's heap.  Allocating past the limit makes
+/// run_to_syscall_or_n return -7, out of memory, if collecting the garbage
+/// doesn't make room.  Lowering it doesn't free anything already allocated.
+/// Returns false if there's no such VM or a limit is negative
+#[unsafe(no_mangle)]
+pub extern "C" fn set_heap_limit(id: i64, max_cells: i64, max_objects: i64) -> bool {
+    let (Ok(cells), Ok(objects)) = (usize::try_from(max_cells), usize::try_from(max_objects)) else { return false; };
+    let mut vms = VMS.lock().unwrap();
+    let Some(found) = vms.vms.iter_mut().find(|x| x.0 == id) else { return false; };
+    found.1.heap_limit = stack_machine::HeapLimit { cells, objects };
+    true
+}
@@ -0,0 +1,551 @@
+//! A language server for spells, speaking LSP over stdin and stdout
+
+#![feature(box_patterns)]
+
+mod stack_machine;
+mod parser;
+mod compiler;
+mod diagnostics;
+#[cfg(test)]
+mod rng;
+
+use std::{collections::HashMap, io::{self, BufRead, Write}, ops::Range};
+
+use serde_json::{json, Value};
+
+use crate::{compiler::{CompType, Compiler, FunctionSignature}, diagnostics::{Diagnostic, Severity}, parser::Statement};
+
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_REQUEST: i64 = -32600;
+const PARSE_ERROR: i64 = -32700;
+
+fn main() -> io::Result<()> {
+    let mut server = Server::default();
+    let code = server.run(&mut io::stdin().lock(), &mut io::stdout().lock())?;
+    std::process::exit(code);
+}
+
+/// An open spell and what the compiler made of it
+struct Document {
+    text: String,
+    analysis: Analysis
+}
+
+struct Analysis {
+    // whatever parsed, broken statements are left out
+    statements: Vec<Statement>,
+    compiler: Compiler,
+    diagnostics: Vec<Diagnostic>
+}
+
+fn analyze(text: &str) -> Analysis {
+    let (statements, parse_errors) = parser::parse_recovering(text);
+    let statements = statements.unwrap_or_default();
+    let mut compiler = Compiler::new();
+    let result = compiler.compile_program(&statements);
+
+    // errors in a half parsed spell are mostly noise, so only the parse errors are shown
+    let diagnostics = if !parse_errors.is_empty() {
+        parse_errors.iter().map(|x| Diagnostic::from_parse_error(text, x)).collect()
+    } else if let Err(errors) = result {
+        errors.iter().map(|x| Diagnostic::from_comp_err(text, x)).collect()
+    } else {
+        compiler.warnings.iter().map(|x| Diagnostic::from_comp_warning(text, x)).collect()
+    };
+    Analysis { statements, compiler, diagnostics }
+}
+
+#[derive(Default)]
+struct Server {
+    documents: HashMap<String, Document>,
+    shut_down: bool
+}
+
+impl Server {
+    /// Answers messages until the client sends `exit`, returning the exit code the spec asks for
+    fn run(&mut self, reader: &mut impl BufRead, writer: &mut impl Write) -> io::Result<i32> {
+        while let Some(body) = read_message(reader)? {
+            let message = match serde_json::from_slice::<Value>(&body) {
+                Ok(v) => v,
+                Err(e) => {
+                    write_message(writer, &error_response(Value::Null, PARSE_ERROR, e.to_string()))?;
+                    continue;
+                }
+            };
+            // responses to requests we never send
+            let Some(method) = message["method"].as_str() else { continue };
+            if method == "exit" {
+                return Ok(if self.shut_down { 0 } else { 1 });
+            }
+            for reply in self.handle(method, &message) {
+                write_message(writer, &reply)?;
+            }
+        }
+        // the client went away without saying goodbye
+        Ok(1)
+    }
+
+    fn handle(&mut self, method: &str, message: &Value) -> Vec<Value> {
+        let params = &message["params"];
+        let Some(id) = message.get("id").cloned() else {
+            return self.notification(method, params);
+        };
+        if self.shut_down {
+            return vec![error_response(id, INVALID_REQUEST, "the server has been shut down".to_owned())];
+        }
+        let result = match method {
+            "initialize" => json!({
+                "capabilities": {
+                    "textDocumentSync": 1,
+                    "hoverProvider": true,
+                    "definitionProvider": true,
+                    "completionProvider": { "triggerCharacters": ["."] },
+                    "signatureHelpProvider": { "triggerCharacters": ["(", ","] }
+                },
+                "serverInfo": { "name": "spellcode-lsp" }
+            }),
+            "shutdown" => {
+                self.shut_down = true;
+                Value::Null
+            }
+            "textDocument/hover" => self.hover(params),
+            "textDocument/definition" => self.definition(params),
+            "textDocument/completion" => self.completion(params),
+            "textDocument/signatureHelp" => self.signature_help(params),
+            _ => return vec![error_response(id, METHOD_NOT_FOUND, format!("unknown method `{method}`"))]
+        };
+        vec![json!({ "jsonrpc": "2.0", "id": id, "result": result })]
+    }
+
+    fn notification(&mut self, method: &str, params: &Value) -> Vec<Value> {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or_default().to_owned();
+        let text = match method {
+            "textDocument/didOpen" => params["textDocument"]["text"].as_str(),
+            // only full syncs are asked for, so the last change has the whole text
+            "textDocument/didChange" => params["contentChanges"].as_array().and_then(|x| x.last()).and_then(|x| x["text"].as_str()),
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                return vec![publish_diagnostics(&uri, vec![])];
+            }
+            _ => None
+        };
+        let Some(text) = text else { return vec![] };
+
+        let analysis = analyze(text);
+        let diagnostics = analysis.diagnostics.iter().map(|x| lsp_diagnostic(&uri, text, x)).collect();
+        self.documents.insert(uri.clone(), Document { text: text.to_owned(), analysis });
+        vec![publish_diagnostics(&uri, diagnostics)]
+    }
+
+    // the document and the byte offset of the position in a request
+    fn position(&self, params: &Value) -> Option<(&Document, usize)> {
+        let document = self.documents.get(params["textDocument"]["uri"].as_str()?)?;
+        let offset = offset_of(&document.text, &params["position"]);
+        Some((document, offset))
+    }
+
+    fn hover(&self, params: &Value) -> Value {
+        let Some((document, offset)) = self.position(params) else { return Value::Null };
+        let info = &document.analysis.compiler.info;
+        let contains = |x: &Range<usize>| x.start <= offset && offset < x.end;
+
+        let tpe = info.types.iter().filter(|x| contains(&x.0)).min_by_key(|x| x.0.len());
+        let call = info.calls.iter().filter(|x| contains(&x.0)).min_by_key(|x| x.0.len());
+        let (range, text) = match (tpe, call) {
+            (Some((range, _)), Some((call_range, signature))) if call_range.len() < range.len() => (call_range, function_label(&document.analysis.compiler, signature)),
+            (Some((range, tpe)), _) => {
+                let name = &document.text[range.clone()];
+                if name.starts_with(|c: char| c.is_ascii_alphabetic()) && name.chars().all(is_identifier) && !matches!(name, "true" | "false") {
+                    (range, format!("{name}: {tpe}"))
+                } else {
+                    (range, tpe.to_string())
+                }
+            }
+            (None, Some((range, signature))) => (range, function_label(&document.analysis.compiler, signature)),
+            (None, None) => return Value::Null
+        };
+        json!({
+            "contents": { "kind": "markdown", "value": format!("```spellcode\n{text}\n```") },
+            "range": range_of(&document.text, range)
+        })
+    }
+
+    fn definition(&self, params: &Value) -> Value {
+        let Some((document, offset)) = self.position(params) else { return Value::Null };
+        let uri = &params["textDocument"]["uri"];
+        let contains = |x: &Range<usize>| x.start <= offset && offset < x.end;
+
+        if let Some((_, signature)) = document.analysis.compiler.info.calls.iter().find(|x| contains(&x.0)) {
+            return match document.analysis.compiler.function_site(signature) {
+                Some(site) => json!({ "uri": uri, "range": range_of(&document.text, &site) }),
+                // the predefined functions and the stdlib aren't in the document
+                None => Value::Null
+            };
+        }
+
+        let word = word_at(&document.text, offset);
+        document.analysis.statements.iter()
+            .find_map(|x| match x {
+                Statement::StructDef { name, .. } if name.item == document.text[word.clone()] => Some(name),
+                _ => None
+            })
+            .map_or(Value::Null, |name| json!({ "uri": uri, "range": range_of(&document.text, &name.loc) }))
+    }
+
+    fn completion(&self, params: &Value) -> Value {
+        let Some((document, offset)) = self.position(params) else { return Value::Null };
+        let text = &document.text;
+        let word_start = text[..offset].trim_end_matches(is_identifier).len();
+
+        if !text[..word_start].ends_with('.') {
+            let compiler = &document.analysis.compiler;
+            return compiler.functions().iter().map(|x| json!({
+                "label": x.name,
+                "kind": 3,
+                "detail": x.to_string()
+            })).collect();
+        }
+
+        // the spell won't parse with the dot in it, so the dot and the field name are blanked out
+        // and the thing before the dot is looked up in that
+        let dot = word_start - 1;
+        let word_end = word_at(text, offset).end;
+        let mut blanked = text.clone();
+        blanked.replace_range(dot..word_end, &" ".repeat(word_end - dot));
+        let analysis = analyze(&blanked);
+        let end = text[..dot].trim_end().len();
+        let Some((_, tpe)) = analysis.compiler.info.types.iter().filter(|x| x.0.end == end).min_by_key(|x| x.0.len()) else {
+            return json!([]);
+        };
+
+        let fields = match tpe {
+            CompType::Struct(v) => v.fields.clone(),
+            CompType::Array(_) | CompType::String => vec![("size".to_owned(), CompType::Int)],
+            _ => vec![]
+        };
+        fields.into_iter().map(|(name, tpe)| json!({
+            "label": name,
+            "kind": 5,
+            "detail": tpe.to_string()
+        })).collect()
+    }
+
+    fn signature_help(&self, params: &Value) -> Value {
+        let Some((document, offset)) = self.position(params) else { return Value::Null };
+        let text = &document.text;
+
+        // walk back to the bracket the cursor is inside of, counting the commas before it
+        let code = parser::unquoted(&text.as_bytes()[..offset]).collect::<Vec<_>>();
+        let mut depth = 0;
+        let mut commas = 0;
+        let mut open = None;
+        for &(i, c) in code.iter().rev() {
+            match c {
+                b')' | b']' | b'}' => depth += 1,
+                b'(' | b'[' | b'{' if depth > 0 => depth -= 1,
+                b'(' => { open = Some(i); break }
+                b'[' | b'{' => break,
+                b',' if depth == 0 => commas += 1,
+                _ => {}
+            }
+        }
+        let Some(open) = open else { return Value::Null };
+        let before = text[..open].trim_end();
+        let name = &before[before.trim_end_matches(is_identifier).len()..];
+
+        let overloads = document.analysis.compiler.functions().iter().filter(|x| x.name == name).collect::<Vec<_>>();
+        if overloads.is_empty() {
+            return Value::Null;
+        }
+        let signatures = overloads.iter().map(|function| {
+            // parameter labels are offsets into the signature's label, in UTF-16 units
+            let mut label = format!("{}(", function.name);
+            let mut parameters = vec![];
+            for (i, (arg, tpe)) in function.args.iter().enumerate() {
+                if i > 0 {
+                    label.push_str(", ");
+                }
+                let start = utf16_len(&label);
+                label.push_str(&format!("{arg}: {tpe}"));
+                parameters.push(json!({ "label": [start, utf16_len(&label)] }));
+            }
+            label.push(')');
+            if let Some(tpe) = &function.return_type {
+                label.push_str(&format!(" -> {tpe}"));
+            }
+            json!({ "label": label, "parameters": parameters })
+        }).collect::<Vec<_>>();
+        let active = overloads.iter().position(|x| x.args.len() > commas).unwrap_or(0);
+        json!({ "signatures": signatures, "activeSignature": active, "activeParameter": commas })
+    }
+}
+
+fn function_label(compiler: &Compiler, signature: &FunctionSignature) -> String {
+    compiler.functions().iter()
+        .find(|x| FunctionSignature::from(*x) == *signature)
+        .map_or_else(|| signature.to_string(), |x| x.to_string())
+}
+
+fn is_identifier(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+// the identifier around the offset, empty if there isn't one
+fn word_at(text: &str, offset: usize) -> Range<usize> {
+    let start = text[..offset].trim_end_matches(is_identifier).len();
+    let end = offset + text[offset..].len() - text[offset..].trim_start_matches(is_identifier).len();
+    start..end
+}
+
+fn utf16_len(text: &str) -> usize {
+    text.chars().map(char::len_utf16).sum()
+}
+
+/// Converts an LSP position, a 0-based line and a column in UTF-16 units, into a byte offset.
+/// Positions past the end of a line or the text are clamped
+fn offset_of(text: &str, position: &Value) -> usize {
+    let line = position["line"].as_u64().unwrap_or(0) as usize;
+    let character = position["character"].as_u64().unwrap_or(0) as usize;
+    let Some(line_start) = std::iter::once(0).chain(text.match_indices('\n').map(|x| x.0 + 1)).nth(line) else {
+        return text.len();
+    };
+    let mut units = 0;
+    for (i, c) in text[line_start..].char_indices() {
+        if units >= character || c == '\n' {
+            return line_start + i;
+        }
+        units += c.len_utf16();
+    }
+    text.len()
+}
+
+fn position_of(text: &str, offset: usize) -> Value {
+    let before = &text[..offset];
+    let line_start = before.rfind('\n').map_or(0, |x| x + 1);
+    json!({ "line": before.matches('\n').count(), "character": utf16_len(&before[line_start..]) })
+}
+
+fn range_of(text: &str, range: &Range<usize>) -> Value {
+    json!({ "start": position_of(text, range.start), "end": position_of(text, range.end) })
+}
+
+fn lsp_diagnostic(uri: &str, text: &str, diagnostic: &Diagnostic) -> Value {
+    let related = diagnostic.notes.iter().map(|x| json!({
+        "location": { "uri": uri, "range": range_of(text, &x.location) },
+        "message": x.message
+    })).collect::<Vec<_>>();
+    json!({
+        "range": range_of(text, &diagnostic.location),
+        "severity": match diagnostic.severity { Severity::Error => 1, Severity::Warning => 2 },
+        "source": "spellcode",
+        "message": diagnostic.message,
+        "relatedInformation": related
+    })
+}
+
+fn publish_diagnostics(uri: &str, diagnostics: Vec<Value>) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "method": "textDocument/publishDiagnostics",
+        "params": { "uri": uri, "diagnostics": diagnostics }
+    })
+}
+
+fn error_response(id: Value, code: i64, message: String) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } })
+}
+
+/// Reads the body of the next message, or None at the end of the input
+fn read_message(reader: &mut impl BufRead) -> io::Result<Option<Vec<u8>>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') && name.eq_ignore_ascii_case("content-length") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+    let Some(length) = length else {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "message without a Content-Length"));
+    };
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    Ok(Some(body))
+}
+
+fn write_message(writer: &mut impl Write, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(writer, "Content-Length: {}\r\n\r\n{body}", body.len())?;
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    const URI: &str = "file:///spell.spc";
+
+    // runs the server over the messages, returning everything it sent back and the exit code
+    fn script(messages: &[Value]) -> (Vec<Value>, i32) {
+        let mut input = vec![];
+        for message in messages {
+            write_message(&mut input, message).unwrap();
+        }
+        let mut output = vec![];
+        let code = Server::default().run(&mut Cursor::new(input), &mut output).unwrap();
+
+        let mut reader = Cursor::new(output);
+        let mut replies = vec![];
+        while let Some(body) = read_message(&mut reader).unwrap() {
+            replies.push(serde_json::from_slice(&body).unwrap());
+        }
+        (replies, code)
+    }
+
+    fn open(text: &str) -> Value {
+        json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": { "textDocument": { "uri": URI, "languageId": "spellcode", "version": 1, "text": text } }
+        })
+    }
+
+    fn request(id: i64, method: &str, line: usize, character: usize) -> Value {
+        json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": method,
+            "params": { "textDocument": { "uri": URI }, "position": { "line": line, "character": character } }
+        })
+    }
+
+    // the result of a single request about the spell
+    fn ask(text: &str, method: &str, line: usize, character: usize) -> Value {
+        let (replies, _) = script(&[open(text), request(1, method, line, character)]);
+        replies.into_iter().find(|x| x["id"] == 1).unwrap()["result"].clone()
+    }
+
+    fn hover_text(text: &str, line: usize, character: usize) -> Option<String> {
+        let result = ask(text, "textDocument/hover", line, character);
+        result["contents"]["value"].as_str().map(|x| x.trim_start_matches("```spellcode\n").trim_end_matches("\n```").to_owned())
+    }
+
+    fn labels(result: &Value) -> Vec<String> {
+        result.as_array().unwrap().iter().map(|x| x["label"].as_str().unwrap().to_owned()).collect()
+    }
+
+    #[test]
+    fn test_lifecycle() {
+        let (replies, code) = script(&[
+            json!({ "jsonrpc": "2.0", "id": 1, "method": "initialize", "params": { "capabilities": {} } }),
+            json!({ "jsonrpc": "2.0", "method": "initialized", "params": {} }),
+            json!({ "jsonrpc": "2.0", "id": 2, "method": "textDocument/rename", "params": {} }),
+            json!({ "jsonrpc": "2.0", "id": 3, "method": "shutdown" }),
+            json!({ "jsonrpc": "2.0", "method": "exit" })
+        ]);
+        assert_eq!(replies.len(), 3);
+        assert_eq!(replies[0]["result"]["capabilities"]["hoverProvider"], true);
+        assert_eq!(replies[1]["error"]["code"], METHOD_NOT_FOUND);
+        assert_eq!(replies[2], json!({ "jsonrpc": "2.0", "id": 3, "result": null }));
+        assert_eq!(code, 0);
+
+        let (_, code) = script(&[json!({ "jsonrpc": "2.0", "method": "exit" })]);
+        assert_eq!(code, 1);
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let (replies, _) = script(&[open("var a = 1\nvar a = 2\nvar b = true + 1")]);
+        let diagnostics = &replies[0]["params"]["diagnostics"];
+        assert_eq!(replies[0]["method"], "textDocument/publishDiagnostics");
+        assert_eq!(diagnostics.as_array().unwrap().len(), 2);
+        assert_eq!(diagnostics[0]["severity"], 1);
+        assert_eq!(diagnostics[0]["range"], json!({ "start": { "line": 1, "character": 4 }, "end": { "line": 1, "character": 5 } }));
+        assert_eq!(diagnostics[0]["relatedInformation"][0]["location"]["range"]["start"], json!({ "line": 0, "character": 4 }));
+
+        // only the parse errors while it doesn't parse
+        let (replies, _) = script(&[open("var a = 1 +* 2\nvar b = true + 1")]);
+        let diagnostics = replies[0]["params"]["diagnostics"].as_array().unwrap();
+        assert_eq!(diagnostics.len(), 1);
+        assert!(diagnostics[0]["message"].as_str().unwrap().starts_with("expected"));
+
+        // warnings, and fixing the spell clears them
+        let (replies, _) = script(&[
+            open("var unused = 1"),
+            json!({
+                "jsonrpc": "2.0",
+                "method": "textDocument/didChange",
+                "params": { "textDocument": { "uri": URI, "version": 2 }, "contentChanges": [{ "text": "println(1)" }] }
+            })
+        ]);
+        assert_eq!(replies[0]["params"]["diagnostics"][0]["severity"], 2);
+        assert_eq!(replies[1]["params"]["diagnostics"], json!([]));
+    }
+
+    #[test]
+    fn test_hover() {
+        // the emoji is two UTF-16 units, columns aren't counted in bytes or characters
+        let src = "fun twice(s: string) -> string {\n    return s\n}\nvar names = new string[2]\n/* 😀 */ var t = twice(names[0])";
+        assert_eq!(hover_text(src, 3, 5).as_deref(), Some("names: string[]"));
+        assert_eq!(hover_text(src, 0, 10).as_deref(), Some("s: string"));
+        assert_eq!(hover_text(src, 4, 13).as_deref(), Some("t: string"));
+        assert_eq!(hover_text(src, 4, 19).as_deref(), Some("twice(s: string) -> string"));
+        assert_eq!(hover_text(src, 4, 25).as_deref(), Some("names: string[]"));
+        assert_eq!(hover_text(src, 4, 29).as_deref(), Some("int"));
+        assert_eq!(hover_text(src, 4, 31).as_deref(), Some("string"));
+        assert_eq!(hover_text(src, 4, 15), None);
+
+        assert_eq!(hover_text("println(spawn_effect(1))", 0, 9).as_deref(), Some("spawn_effect(type: int) -> int"));
+        // the comparisons a match compiles into aren't in the source
+        assert_eq!(hover_text("match 1 {\n    2 => {}\n}", 1, 4).as_deref(), Some("int"));
+    }
+
+    #[test]
+    fn test_definition() {
+        let src = "struct Node { q: int, r: int }\nfun f(n: Node) {}\nfun f(i: int) {}\nf(new Node)\nf(1)\nprintln(1)";
+        let at = |line, character| ask(src, "textDocument/definition", line, character)["range"]["start"].clone();
+        assert_eq!(at(3, 0), json!({ "line": 1, "character": 4 }));
+        assert_eq!(at(4, 0), json!({ "line": 2, "character": 4 }));
+        assert_eq!(at(3, 8), json!({ "line": 0, "character": 7 }));
+        assert_eq!(at(1, 11), json!({ "line": 0, "character": 7 }));
+        assert_eq!(at(5, 2), Value::Null);
+    }
+
+    #[test]
+    fn test_completion() {
+        let functions = ask("var a = 1\n", "textDocument/completion", 1, 0);
+        let functions = labels(&functions);
+        assert!(functions.contains(&"spawn_effect".to_owned()));
+        assert!(functions.contains(&"get_click".to_owned()));
+
+        let src = "struct Node { q: int, r: int }\nvar nodes = new Node[2]\nnodes[0].q = 1\nnodes[1].";
+        assert_eq!(labels(&ask(src, "textDocument/completion", 3, 9)), vec!["q", "r"]);
+        assert_eq!(labels(&ask(src, "textDocument/completion", 2, 9)), vec!["q", "r"]);
+        assert_eq!(labels(&ask(src, "textDocument/completion", 2, 10)), vec!["q", "r"]);
+        assert_eq!(labels(&ask("var s = \"abc\"\nprintln(s.)", "textDocument/completion", 1, 10)), vec!["size"]);
+        assert_eq!(labels(&ask("var a = 1\na.", "textDocument/completion", 1, 2)), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_signature_help() {
+        let src = "fun f(a: int, b: string) {}\nfun f(a: int) {}\nf(1, g(\")\", 2), ";
+        let result = ask(src, "textDocument/signatureHelp", 2, 16);
+        assert_eq!(result["signatures"][0]["label"], "f(a: int, b: string)");
+        assert_eq!(result["signatures"][0]["parameters"][1]["label"], json!([10, 19]));
+        assert_eq!(result["signatures"][1]["label"], "f(a: int)");
+        assert_eq!(result["activeParameter"], 2);
+
+        let result = ask(src, "textDocument/signatureHelp", 2, 2);
+        assert_eq!(result["activeSignature"], 0);
+        assert_eq!(result["activeParameter"], 0);
+        assert_eq!(ask(src, "textDocument/signatureHelp", 2, 0), Value::Null);
+    }
+}
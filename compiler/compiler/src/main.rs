@@ -3,313 +3,618 @@
 mod stack_machine;
 mod parser;
 mod compiler;
+mod diagnostics;
+mod formatter;
+mod bytecode;
+mod assembly;
+mod world;
+mod bench;
+#[cfg(test)]
+mod fuzz;
+#[cfg(test)]
+mod rng;
+
+use std::{io::{BufRead, Write}, process::ExitCode};
+
+use serde_json::{json, Value};
+
+use crate::{compiler::Compiler, diagnostics::{Diagnostic, Severity}, compiler::SourceMap, stack_machine::{Frame, Instruction, Step, VM}, world::{MockWorld, RunError}};
+
+const USAGE: &str = "\
+usage: compiler-bin <command> [options] <file>
+
+commands:
+    check <file>...          report the problems with spells without running them
+    build <file> -o <out>    compile a spell, or assembly, to bytecode
+    run <file>               run a spell, its bytecode or assembly, against a mock world
+    debug <file>             run a spell against a mock world a step at a time, reading
+                             commands from stdin, `help` lists them
+    bench [file]             time a spell against a mock world with the garbage collector, and
+                             with the old one that collected everything every 100 instructions,
+                             or without a spell, time the VM on common mixes of instructions
+    disasm <file>            list the instructions a spell, or its bytecode, compiles to as
+                             assembly, which can be edited and read back from a .spasm file
+    fmt <file>               print a spell formatted
+
+options:
+    -h, --help               print this and exit
+    --json                   write diagnostics as JSON, to stdout for check and stderr otherwise
+    --deny-warnings          treat warnings as errors
+    --text                   (build) write the bytecode as text rather than binary
+    --world <config.json>    (run, debug, bench) read the world from a file, the options below
+                             override it
+    --mana <n>               (run, debug, bench) the player's mana, 1000 by default
+    --player <q>,<r>         (run, debug, bench) where the player stands, 0,0 by default
+    --click <q>,<r>          (run, debug, bench) queue up a click, can be given more than once
+    --radius <n>             (run, debug, bench) how far the map reaches from 0,0, 5 hexes by default
+    --max-steps <n>          (run, debug, bench) stop after running this many instructions, at a
+                             time for debug
+
+exit codes:
+    0    success
+    1    a spell has errors, or doesn't format
+    2    bad usage, or a file can't be read or written
+    3    the spell failed while running";
+
+const DEBUG_HELP: &str = "\
+commands:
+    break <line>       pause at the statements on a line
+    clear <line>       remove the breakpoints from a line
+    step               run to the next statement, going into function calls
+    next               run to the next statement, going over function calls
+    finish             run until the current function returns
+    continue           run to the next breakpoint, or the end
+    locals [frame]     show the variables in scope, in the innermost frame or one from backtrace
+    backtrace          show the calls in progress
+    quit               stop debugging";
+
+const EXIT_ERRORS: u8 = 1;
+const EXIT_USAGE: u8 = 2;
+const EXIT_RUNTIME: u8 = 3;
+
+#[derive(Debug, PartialEq)]
+struct Options {
+    command: String,
+    files: Vec<String>,
+    output: Option<String>,
+    json: bool,
+    deny_warnings: bool,
+    text: bool,
+    world: MockWorld,
+    max_steps: Option<usize>
+}
 
-use std::collections::HashMap;
-
-use crate::{compiler::{CompErr, Compiler}, stack_machine::{ExecutionException, StackItem, Syscall, Tpe, VM}};
+fn main() -> ExitCode {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let options = match parse_args(&args) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("{e}\n\n{USAGE}");
+            return ExitCode::from(EXIT_USAGE);
+        }
+    };
+    match run_command(options) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(code) => ExitCode::from(code)
+    }
+}
 
-#[allow(unused)]
-fn main() {
-    let inp = r#"
-        var start = new Node;
-        start.q = 1;
-        start.r = 2;
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let (command, rest) = match args {
+        // asking for help wins over everything else on the line, even a bad command
+        _ if args.iter().any(|x| x == "--help" || x == "-h") => ("help", &[][..]),
+        [command, rest @ ..] => (command.as_str(), rest),
+        [] => return Err("missing command".to_owned())
+    };
+    let mut options = Options {
+        command: command.to_owned(),
+        files: vec![],
+        output: None,
+        json: false,
+        deny_warnings: false,
+        text: false,
+        world: MockWorld::default(),
+        max_steps: None
+    };
+    // the world has to be read before the options that change it
+    if let Some(i) = rest.iter().position(|x| x == "--world") {
+        let path = rest.get(i + 1).ok_or("missing value for --world")?;
+        let config = std::fs::read_to_string(path).map_err(|e| format!("couldn't read {path}: {e}"))?;
+        options.world = MockWorld::from_config(&config).map_err(|e| format!("{path}: {e}"))?;
+    }
+    options.world.echo = true;
+
+    let mut rest = rest.iter();
+    while let Some(arg) = rest.next() {
+        let mut value = || rest.next().ok_or(format!("missing value for {arg}"));
+        match arg.as_str() {
+            "-o" => options.output = Some(value()?.clone()),
+            "--json" => options.json = true,
+            "--deny-warnings" => options.deny_warnings = true,
+            "--text" => options.text = true,
+            "--mana" => options.world.mana = parse_number(arg, value()?)?,
+            "--player" => options.world.player = parse_location(arg, value()?)?,
+            "--click" => {
+                let click = parse_location(arg, value()?)?;
+                options.world.clicks.push_back(click);
+            }
+            "--radius" => options.world.tiles = world::hexagon(parse_number(arg, value()?)?),
+            "--world" => {
+                value()?;
+            }
+            "--max-steps" => options.max_steps = Some(parse_number(arg, value()?)?),
+            flag if flag.starts_with('-') => return Err(format!("unknown option {flag}")),
+            file => options.files.push(file.to_owned())
+        }
+    }
 
+    match (options.command.as_str(), options.files.len()) {
+        ("help", _) => Ok(options),
+        ("check", 0) => Err("check needs at least one file".to_owned()),
+        ("check", _) => Ok(options),
+        ("bench", 0) => Ok(options),
+        ("build", 1) if options.output.is_none() => Err("build needs an output file, given with -o".to_owned()),
+        ("build" | "run" | "debug" | "bench" | "disasm" | "fmt", 1) => Ok(options),
+        ("build" | "run" | "debug" | "bench" | "disasm" | "fmt", _) => Err(format!("{} takes one file", options.command)),
+        (command, _) => Err(format!("unknown command {command}"))
+    }
+}
 
-        var end = new Node;
-        end.q = 1;
-        end.r = 4;
+fn parse_number<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("{flag} expects a number, not {value}"))
+}
 
-        var path = dijkstra_search(start, end);
+fn parse_location(flag: &str, value: &str) -> Result<(i32, i32), String> {
+    let (q, r) = value.split_once(',').ok_or(format!("{flag} expects a location like 1,2, not {value}"))?;
+    Ok((parse_number(flag, q.trim())?, parse_number(flag, r.trim())?))
+}
 
-        println("path:");
-        for p in path {
-            println(p);
+fn run_command(mut options: Options) -> Result<(), u8> {
+    match options.command.as_str() {
+        "check" => {
+            let mut all = vec![];
+            let mut failed = false;
+            for path in &options.files {
+                let src = read_source(path)?;
+                let (program, diagnostics) = compile(&src);
+                failed |= program.is_none() || (options.deny_warnings && !diagnostics.is_empty());
+                if options.json {
+                    all.extend(diagnostics.iter().map(|x| json_diagnostic(path, x)));
+                } else {
+                    print_diagnostics(path, &diagnostics);
+                }
+            }
+            if options.json {
+                println!("{}", Value::Array(all));
+            }
+            if failed { Err(EXIT_ERRORS) } else { Ok(()) }
         }
-
-        fun print(v: Node) {
-            print(v.q);
-            print(", ");
-            print(v.r);
+        "build" => {
+            let program = load(&options)?.program;
+            let output = options.output.as_deref().unwrap();
+            let bytes = if options.text { bytecode::write_text(&program).map(String::into_bytes) } else { bytecode::write_program(&program) };
+            let bytes = bytes.map_err(|e| {
+                eprintln!("{}: {e}", options.files[0]);
+                EXIT_ERRORS
+            })?;
+            std::fs::write(output, bytes).map_err(|e| {
+                eprintln!("couldn't write {output}: {e}");
+                EXIT_USAGE
+            })
         }
-
-        fun println(v: Node) {
-            print(v);
-            println();
+        "run" => {
+            let Loaded { program, source } = load(&options)?;
+            let mut vm = VM::new(program);
+            let src = source.map(|(src, map)| {
+                vm.source_map = Some(map);
+                src
+            });
+            world::run(&mut vm, &mut options.world, options.max_steps)
+                .map_err(|e| report_failure(&options.files[0], src.as_deref(), &vm, &e))
         }
-
-
-        struct Node {
-            q: int,
-            r: int
+        "debug" => {
+            let Loaded { program, source } = load(&options)?;
+            let Some((src, map)) = source else {
+                eprintln!("{}: only a spell's source can be debugged", options.files[0]);
+                return Err(EXIT_USAGE);
+            };
+            let mut vm = VM::new(program);
+            vm.source_map = Some(map);
+            let mut session = DebugSession { path: &options.files[0], src: &src, world: &mut options.world, max_steps: options.max_steps };
+            session.run(&mut vm, &mut std::io::stdin().lock(), &mut std::io::stdout())
         }
-
-        struct NodeP {
-            node_id: int,
-            cost: int
+        "bench" if options.files.is_empty() => {
+            // enough to take a noticeable fraction of a second
+            const INSTRUCTIONS: usize = 5_000_000;
+            for (name, src) in bench::MIXES {
+                let elapsed = bench::time_mix(src, INSTRUCTIONS);
+                println!("{name}: {:.1} ns per instruction", elapsed.as_nanos() as f64 / INSTRUCTIONS as f64);
+            }
+            Ok(())
+        }
+        "bench" => {
+            let program = load(&options)?.program;
+            options.world.echo = false;
+            for (name, full) in [("collecting everything every 100 instructions", true), ("collecting incrementally", false)] {
+                let mut vm = VM::new(program.clone());
+                let mut world = options.world.clone();
+                let start = std::time::Instant::now();
+                let result = if full {
+                    run_collecting_fully(&mut vm, &mut world, options.max_steps)
+                } else {
+                    world::run(&mut vm, &mut world, options.max_steps)
+                };
+                let elapsed = start.elapsed();
+                if let Err(e) = result {
+                    return Err(report_failure(&options.files[0], None, &vm, &e));
+                }
+                let stats = &vm.gc.stats;
+                println!("{name}: {elapsed:.2?}, {} collections freeing {} objects, {} cells marked, at most {} in one step",
+                    stats.collections, stats.freed, stats.marked, stats.longest_step);
+            }
+            Ok(())
         }
+        "disasm" => {
+            let loaded = load(&options)?;
+            let source = loaded.source.as_ref().map(|(src, map)| (src.as_str(), map));
+            print!("{}", assembly::disassemble(&loaded.program, source));
+            Ok(())
+        }
+        "fmt" => {
+            let src = read_source(&options.files[0])?;
+            match formatter::format_program(&src) {
+                Ok(formatted) => {
+                    print!("{formatted}");
+                    Ok(())
+                }
+                Err(errors) => {
+                    let diagnostics = errors.iter().map(|x| Diagnostic::from_parse_error(&src, x)).collect::<Vec<_>>();
+                    report(&options, &options.files[0], &diagnostics);
+                    Err(EXIT_ERRORS)
+                }
+            }
+        }
+        "help" => {
+            println!("{USAGE}");
+            Ok(())
+        }
+        _ => unreachable!("parse_args only accepts known commands")
+    }
+}
 
-        struct PQueue {
-            items: NodeP[],
-            size: int
+/// Runs a spell the way the VM used to, stopping to collect all the garbage every 100
+/// instructions, to compare the garbage collector against
+fn run_collecting_fully(vm: &mut VM, world: &mut MockWorld, max_steps: Option<usize>) -> Result<(), RunError> {
+    // no incremental collection in between, so only the full ones are measured
+    vm.gc.budget = 0;
+    let mut steps = 0;
+    loop {
+        let chunk = max_steps.map_or(100, |x| x.saturating_sub(steps).min(100));
+        match world::run(vm, world, Some(chunk)) {
+            Err(RunError::OutOfSteps(_)) if max_steps.is_none_or(|x| steps + chunk < x) => {}
+            Err(RunError::OutOfSteps(_)) => return Err(RunError::OutOfSteps(steps + chunk)),
+            result => return result
         }
+        steps += chunk;
+        vm.garbage_collect();
+    }
+}
 
-        fun dijkstra_search(start: Node, end: Node) -> Node[] {
-            var node_db = get_all_nodes(start);
+/// Explains why a spell stopped running, and where.  Gives back the exit code
+fn report_failure(path: &str, src: Option<&str>, vm: &VM, e: &RunError) -> u8 {
+    // the VM stops on the instruction that failed, except for syscalls
+    if !matches!(e, RunError::Exception(..)) {
+        eprintln!("{e}");
+        return EXIT_RUNTIME;
+    }
+    match (src, vm.current_span()) {
+        (Some(src), Some(span)) => {
+            let (line, column) = diagnostics::line_col(src, span.start);
+            eprintln!("{path}: line {line}, column {column}: {e}");
+        }
+        _ => eprintln!("{path}: {e}")
+    }
+    for line in stack_trace(src, &vm.stack_trace()) {
+        eprintln!("    {line}");
+    }
+    EXIT_RUNTIME
+}
 
-            var frontier = pqueue_new();
-            pqueue_push(frontier, nodep_new(find_id(node_db, start), 0));
-            var came_from = new int[node_db.size];
-            for (var i = 0; i < came_from.size; i = i + 1) {
-                came_from[i] = -1;
-            }
+// a spell being run from the terminal under the debugger
+struct DebugSession<'a> {
+    path: &'a str,
+    src: &'a str,
+    world: &'a mut MockWorld,
+    max_steps: Option<usize>
+}
 
-            var placeholder = 100000000;
-            var cost_so_far = new int[node_db.size];
-            for (var i = 0; i < cost_so_far.size; i = i + 1) {
-                cost_so_far[i] = placeholder;
+impl DebugSession<'_> {
+    /// Pauses at the first statement, then follows commands from input until the spell finishes
+    /// or the input runs out.  Output from the debugger goes to output, the spell's to the world
+    fn run(&mut self, vm: &mut VM, input: &mut impl BufRead, output: &mut impl Write) -> Result<(), u8> {
+        vm.step(Step::Into);
+        if self.resume(vm, output)? {
+            return Ok(());
+        }
+        let mut line = String::new();
+        loop {
+            write!(output, "(debug) ").and_then(|_| output.flush()).map_err(terminal_error)?;
+            line.clear();
+            if input.read_line(&mut line).map_err(terminal_error)? == 0 {
+                return Ok(());
             }
-
-            cost_so_far[find_id(node_db, start)] = 0;
-
-            while frontier.size > 0 {
-                var current_id = pqueue_pop(frontier).node_id;
-                var current = node_get(node_db, current_id);
-                if current.q == end.q && current.r == end.r {
-                    var len = 0;
-                    var curr = current_id;
-                    while curr != 0 {
-                        curr = came_from[curr];
-                        len = len + 1;
+            let mut words = line.split_whitespace();
+            let command = words.next().unwrap_or("");
+            let number = words.next().map(|x| x.parse::<usize>().ok());
+            let step = match (command, number) {
+                ("break" | "b", Some(Some(n))) => {
+                    if !vm.set_breakpoint(n) {
+                        writeln!(output, "no statement starts on line {n}").map_err(terminal_error)?;
                     }
-                    var out = new Node[len + 1];
-                    curr = current_id;
-                    while len > 0 {
-                        out[len] = node_get(node_db, curr);
-                        curr = came_from[curr];
-                        len = len - 1;
+                    continue;
+                }
+                ("clear", Some(Some(n))) => {
+                    if !vm.clear_breakpoint(n) {
+                        writeln!(output, "there's no breakpoint on line {n}").map_err(terminal_error)?;
                     }
-                    out[0] = start;
-
-                    return out;
+                    continue;
                 }
-
-                for neighbor in neighbors(current.q, current.r) {
-                    var neighbor_id = find_id(node_db, neighbor[0], neighbor[1]);
-                    var new_cost = cost_so_far[current_id] + neighbor[2];
-                    if new_cost < cost_so_far[neighbor_id] {
-                        cost_so_far[neighbor_id] = new_cost;
-                        pqueue_push(frontier, nodep_new(neighbor_id, new_cost));
-                        came_from[neighbor_id] = current_id;
+                ("locals" | "l", None | Some(Some(_))) => {
+                    let frame = number.flatten().unwrap_or(0);
+                    match vm.variables(frame) {
+                        Some(variables) => for v in variables {
+                            writeln!(output, "{}: {} = {}", v.name, v.tpe, v.value).map_err(terminal_error)?;
+                        },
+                        None => writeln!(output, "there's no frame {frame}").map_err(terminal_error)?
+                    }
+                    continue;
+                }
+                ("backtrace" | "bt", None) => {
+                    for (i, frame) in stack_trace(Some(self.src), &vm.stack_trace()).iter().enumerate() {
+                        writeln!(output, "{i}: {frame}").map_err(terminal_error)?;
                     }
+                    continue;
+                }
+                ("help" | "h", None) => {
+                    writeln!(output, "{DEBUG_HELP}").map_err(terminal_error)?;
+                    continue;
                 }
+                ("quit" | "q", None) => return Ok(()),
+                ("step" | "s", None) => Some(Step::Into),
+                ("next" | "n", None) => Some(Step::Over),
+                ("finish" | "f", None) => Some(Step::Out),
+                ("continue" | "c", None) => None,
+                ("", None) => continue,
+                _ => {
+                    writeln!(output, "unknown command, `help` lists them").map_err(terminal_error)?;
+                    continue;
+                }
+            };
+            if let Some(step) = step {
+                vm.step(step);
             }
-        }
-
-        fun nodep_new(id: int, cost: int) -> NodeP {
-            var out = new NodeP;
-            out.node_id = id;
-            out.cost = cost;
-            return out;
-        }
-
-        fun pqueue_new() -> PQueue {
-            var out = new PQueue;
-            out.items = new NodeP[100];
-            for it in out.items {
-                it.cost = 12345678;
+            if self.resume(vm, output)? {
+                return Ok(());
             }
-            out.size = 0;
-            return out;
         }
+    }
 
-        fun pqueue_push(queue: PQueue, value: NodeP) {
-            for it in queue.items {
-                if it.cost == 12345678 {
-                    it.node_id = value.node_id;
-                    it.cost = value.cost;
-                    queue.size = queue.size + 1;
-                    return;
-                }
+    /// Runs until the spell pauses, showing where, or finishes.  Returns true if it finished
+    fn resume(&mut self, vm: &mut VM, output: &mut impl Write) -> Result<bool, u8> {
+        match world::run(vm, self.world, self.max_steps) {
+            Ok(()) => {
+                writeln!(output, "the spell finished").map_err(terminal_error)?;
+                Ok(true)
             }
-        }
-
-        fun pqueue_pop(queue: PQueue) -> NodeP {
-            var best_idx = -1;
-            var best_value = 12345678;
-            var out = new NodeP;
-            for (var i = 0; i < queue.items.size; i = i + 1) {
-                var it = queue.items[i];
-                if it.cost < best_value {
-                    out = it;
-                    best_idx = i;
-                    best_value = it.cost;
-                }
+            Err(RunError::Paused) => {
+                let line = vm.current_line().unwrap_or(0);
+                let text = self.src.lines().nth(line.wrapping_sub(1)).unwrap_or("").trim();
+                writeln!(output, "line {line}: {text}").map_err(terminal_error)?;
+                Ok(false)
             }
-            queue.size = queue.size - 1;
-            queue.items[best_idx].cost = 12345678;
-            return out;
-        }
-
-        struct NodeDB {
-            nodes: Node[],
-            size: int
+            Err(e) => Err(report_failure(self.path, Some(self.src), vm, &e))
         }
+    }
+}
 
-        fun get_all_nodes(start: Node) -> NodeDB {
-            var out = new NodeDB;
-            out.nodes = new Node[100];
-            out.nodes[0] = start;
-            out.size = 1;
+fn terminal_error(e: std::io::Error) -> u8 {
+    eprintln!("couldn't use the terminal: {e}");
+    EXIT_USAGE
+}
 
-            populate_nodes(out, start.q, start.r);
-            return out;
-        }
+/// Describes each frame of a stack trace, innermost first
+fn stack_trace(src: Option<&str>, trace: &[Frame]) -> Vec<String> {
+    trace.iter().enumerate().map(|(i, frame)| {
+        let function = match (&frame.function, src) {
+            (Some(function), _) => function.to_string(),
+            (None, Some(_)) => "the top level".to_owned(),
+            // loaded from bytecode, so nothing is known about it
+            (None, None) => format!("instruction {}", frame.pc)
+        };
+        let location = match (&frame.span, src) {
+            (Some(span), Some(src)) => format!(", line {}", diagnostics::line_col(src, span.start).0),
+            (None, Some(_)) => ", in the standard library".to_owned(),
+            _ => String::new()
+        };
+        format!("{} {function}{location}", if i == 0 { "in" } else { "called from" })
+    }).collect()
+}
 
+fn read_source(path: &str) -> Result<String, u8> {
+    std::fs::read_to_string(path).map_err(|e| {
+        eprintln!("couldn't read {path}: {e}");
+        EXIT_USAGE
+    })
+}
 
-        fun find_id(db: NodeDB, node: Node) -> int {
-            return find_id(db, node.q, node.r);
+/// Compiles a spell, giving back the program if it has no errors, and every diagnostic either way
+fn compile(src: &str) -> (Option<Compiler>, Vec<Diagnostic>) {
+    let parsed = match parser::parse_program(src) {
+        Ok(v) => v,
+        Err(errors) => return (None, errors.iter().map(|x| Diagnostic::from_parse_error(src, x)).collect())
+    };
+    let mut compiler = Compiler::new();
+    match compiler.compile_program(&parsed) {
+        Ok(()) => {
+            let warnings = compiler.warnings.iter().map(|x| Diagnostic::from_comp_warning(src, x)).collect();
+            (Some(compiler), warnings)
         }
+        Err(errors) => (None, errors.iter().map(|x| Diagnostic::from_comp_err(src, x)).collect())
+    }
+}
 
-        fun find_id(db: NodeDB, q: int, r: int) -> int {
-            for (var i = 0; i < db.size; i = i + 1) {
-                var it = db.nodes[i];
-                if it.q == q && it.r == r {
-                    return i;
-                }
-            }
-            return -1;
-        }
+// a program, with the source it was compiled from if it was
+struct Loaded {
+    program: Vec<Instruction>,
+    source: Option<(String, SourceMap)>
+}
 
-        fun node_get(db: NodeDB, id: int) -> Node {
-            return db.nodes[id];
-        }
+// the program in a spell's source, bytecode or assembly file, with its diagnostics reported
+fn load(options: &Options) -> Result<Loaded, u8> {
+    let path = &options.files[0];
+    let bytes = std::fs::read(path).map_err(|e| {
+        eprintln!("couldn't read {path}: {e}");
+        EXIT_USAGE
+    })?;
+    if bytecode::is_bytecode(&bytes) {
+        let program = bytecode::read_program(&bytes).map_err(|e| {
+            eprintln!("{path}: {e}");
+            EXIT_ERRORS
+        })?;
+        return Ok(Loaded { program, source: None });
+    }
 
-        fun populate_nodes(db: NodeDB, q: int, r: int) {
-            for n in neighbors(q, r) {
-                if find_id(db, n[0], n[1]) == -1 {
-                    db.nodes[db.size] = new Node;
-                    db.nodes[db.size].q = n[0];
-                    db.nodes[db.size].r = n[1];
-                    db.size = db.size + 1;
-                    populate_nodes(db, n[0], n[1]);
-                }
-            }
-        }
-    "#;
-
-    /*
-    
-    let inp = r#"
-        struct Node {
-            q: int,
-            r: int,
-            priority: int
+    let src = String::from_utf8_lossy(&bytes).into_owned();
+    if path.ends_with(".spasm") {
+        let program = assembly::assemble(&src).map_err(|e| {
+            eprintln!("{path}: {e}");
+            EXIT_ERRORS
+        })?;
+        return Ok(Loaded { program, source: None });
+    }
+    let (compiler, diagnostics) = compile(&src);
+    report(options, path, &diagnostics);
+    match compiler {
+        Some(compiler) if !(options.deny_warnings && !diagnostics.is_empty()) => {
+            let map = compiler.source_map(&src);
+            Ok(Loaded { program: compiler.program, source: Some((src, map)) })
         }
+        _ => Err(EXIT_ERRORS)
+    }
+}
 
-        var items = new Node[100]
+fn report(options: &Options, path: &str, diagnostics: &[Diagnostic]) {
+    if options.json {
+        eprintln!("{}", Value::Array(diagnostics.iter().map(|x| json_diagnostic(path, x)).collect()));
+    } else {
+        print_diagnostics(path, diagnostics);
+    }
+}
 
-        items[0] = new Node
-    "#;
-        */
+fn print_diagnostics(path: &str, diagnostics: &[Diagnostic]) {
+    for diagnostic in diagnostics {
+        eprintln!("{path}: {diagnostic}");
+    }
+}
 
-    /*
-    let inp = r#"
-        struct Node {
-            q: int,
-            r: int,
-            priority: int
-        }
+fn json_diagnostic(path: &str, diagnostic: &Diagnostic) -> Value {
+    let notes = diagnostic.notes.iter().map(|x| json!({
+        "message": x.message,
+        "line": x.line,
+        "column": x.column,
+        "start": x.location.start,
+        "end": x.location.end
+    })).collect::<Vec<_>>();
+    json!({
+        "file": path,
+        "severity": match diagnostic.severity { Severity::Error => "error", Severity::Warning => "warning" },
+        "message": diagnostic.message,
+        "line": diagnostic.line,
+        "column": diagnostic.column,
+        "start": diagnostic.location.start,
+        "end": diagnostic.location.end,
+        "notes": notes
+    })
+}
 
-        struct MinHeap {
-            items: Node[],
-            size: int
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        fun heapCreate(capacity: int) -> MinHeap {
-            var out = new MinHeap
-            out.items = new Node[capacity]
-            return out;
-        }
+    fn args(args: &str) -> Result<Options, String> {
+        parse_args(&args.split(' ').map(str::to_owned).collect::<Vec<_>>())
+    }
 
-        var heap = heapCreate(100)
-        var i = heap.size - 1
-        "#;
-        */
+    #[test]
+    fn test_stack_trace() {
+        let src = "fun get(a: int[]) -> int {\n    return a[1]\n}\nvar a = new int[0]\nprintln(get(a))";
+        let compiler = compile(src).0.unwrap();
+        let mut vm = VM::new(compiler.program.clone());
+        vm.source_map = Some(compiler.source_map(src));
+        while vm.tick().is_ok() {}
+        assert_eq!(stack_trace(Some(src), &vm.stack_trace()), vec![
+            "in get(a: int[]) -> int, line 2",
+            "called from the top level, line 5"
+        ]);
+
+        vm.source_map = None;
+        assert_eq!(stack_trace(None, &vm.stack_trace())[1], format!("called from instruction {}", vm.stack_trace()[1].pc));
+    }
 
-    let parsed = parser::spellcode::program(inp).unwrap();
-    let mut compiler = Compiler::new();
-    let v = match compiler.compile_program(&parsed) {
-        Ok(_) => {}
-        Err(CompErr { error, location }) => { panic!("error {error:?} at {location:?}: \"{}\"", &inp[location.clone()]) }
-    };
-    println!("{:?}", compiler.program);
-    let neighbors: HashMap<(i32, i32), Vec<[i32; 3]>> = HashMap::from_iter(vec![
-        ((1, 2), vec![[1, 3, 5]]),
-        ((1, 3), vec![[1, 2, 5], [1, 4, 4]]),
-        ((1, 4), vec![[1, 3, 5]]),
-    ]);
-    let mut vm = VM::new(compiler.program);
-    loop {
-        //println!("stack = {:?}, ins = {:?}", vm.stack, vm.program[vm.program_counter]);
-        let res = vm.tick();
-        match res {
-            Ok(_) => {}
-            Err(ExecutionException::Halt) => {
-                println!("halted");
-                break;
-            }
-            Err(ExecutionException::SyscallException(Syscall::Nop)) => {}
-            Err(ExecutionException::SyscallException(Syscall::Sleep)) => {}
-            Err(ExecutionException::SyscallException(Syscall::GetMana)) => {
-                vm.stack.push(StackItem::Int(1000));
-            }
-            Err(ExecutionException::SyscallException(Syscall::PrintChar)) => {
-                let StackItem::Int(v) = vm.stack.pop().unwrap() else { panic!() };
-                print!("{}", char::try_from(v as u32).unwrap());
-            }
-            Err(ExecutionException::SyscallException(Syscall::PlayerLocation)) => {
-                let id = vm.next_heap_addr;
-                vm.next_heap_addr += 1;
-                vm.heap.insert(id, stack_machine::HeapItem { value: vec![StackItem::Int(3), StackItem::Int(4)], mark: false, tpe: Tpe::Int });
-                vm.stack.push(StackItem::HeapAddr(Tpe::Array(Box::new(Tpe::Int)), id));
-            }
-            Err(ExecutionException::SyscallException(Syscall::ClickLocation)) => {
-                let id = vm.next_heap_addr;
-                vm.next_heap_addr += 1;
-                vm.heap.insert(id, stack_machine::HeapItem { value: vec![StackItem::Int(2), StackItem::Int(4)], mark: false, tpe: Tpe::Int });
-                vm.stack.push(StackItem::HeapAddr(Tpe::Array(Box::new(Tpe::Int)), id));
-            }
-            Err(ExecutionException::SyscallException(Syscall::GetNeighbors)) => {
-                let StackItem::Int(q) = vm.stack.pop().unwrap() else { panic!() };
-                let StackItem::Int(r) = vm.stack.pop().unwrap() else { panic!() };
-                let id = vm.next_heap_addr;
-                vm.next_heap_addr += 1;
-                let mut value = vec![];
-                for _ in 0..6 * 3 {
-                    value.push(StackItem::Int(1234));
-                }
-                if let Some(v) = neighbors.get(&(q, r)) {
-                    for (i, item) in v.iter().enumerate() {
-                        for (j, x) in item.iter().enumerate() {
-                            value[i * 3 + j] = StackItem::Int(*x)
-                        }
-                    }
-                }
-                vm.heap.insert(id, stack_machine::HeapItem { value, mark: false, tpe: Tpe::Int });
-                vm.stack.push(StackItem::HeapAddr(Tpe::Array(Box::new(Tpe::Int)), id));
-            }
+    #[test]
+    fn test_debug() {
+        let src = "fun double(x: int) -> int {\n    var y = x * 2\n    return y\n}\nvar a = 1\nvar b = double(a)\nprint(b)";
+        let compiler = compile(src).0.unwrap();
+        let mut vm = VM::new(compiler.program.clone());
+        vm.source_map = Some(compiler.source_map(src));
+        let mut world = MockWorld::default();
+        let mut session = DebugSession { path: "a.spc", src, world: &mut world, max_steps: None };
+        let mut output = vec![];
+        let commands = "break 9\nbreak 2\nc\nlocals\nbt\nlocals 1\nfinish\nclear 2\nlocals 2\nstep\nnext\n";
+        session.run(&mut vm, &mut commands.as_bytes(), &mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap().split("(debug) ").collect::<Vec<_>>(), vec![
+            "line 5: var a = 1\n",
+            "no statement starts on line 9\n",
+            "",
+            "line 2: var y = x * 2\n",
+            "x: int = 1\n",
+            "0: in double(x: int) -> int, line 2\n1: called from the top level, line 6\n",
+            "a: int = 1\n",
+            "line 7: print(b)\n",
+            "",
+            "there's no frame 2\n",
+            "the spell finished\n"
+        ]);
+        assert_eq!(world.output, "2");
+    }
 
-            Err(e) => {
-                println!("exited with error {e:?}");
-                break;
-            }
-        }
-        //if let Err(e) =  {
-        //    println!("exited with error {e:?}");
-        //    break;
-        //}
+    #[test]
+    fn test_parse_args() {
+        let options = args("run spell.spc --click 1,2 --mana 5 --click -3,4 --max-steps 100").unwrap();
+        assert_eq!(options.files, vec!["spell.spc"]);
+        assert_eq!(options.world.clicks, vec![(1, 2), (-3, 4)]);
+        assert_eq!(options.world.mana, 5);
+        assert_eq!(options.max_steps, Some(100));
+        assert_eq!(args("build a.spc -o a.spbc").unwrap().output.as_deref(), Some("a.spbc"));
+        assert!(args("build a.spc -o a.txt --text").unwrap().text);
+        assert!(args("check a.spc b.spc --json --deny-warnings").unwrap().json);
+
+        assert!(args("build a.spc").is_err());
+        assert!(args("run a.spc b.spc").is_err());
+        assert!(args("run a.spc --click 1").is_err());
+        assert!(args("run a.spc --mana").is_err());
+        assert!(args("lint a.spc").is_err());
+        assert!(args("check --verbose a.spc").is_err());
+        assert_eq!(args("--help").unwrap().command, "help");
+        assert_eq!(args("lint a.spc -h --mana").unwrap().command, "help");
+
+        let options = args("run a.spc --mana 3 --world ../../ExampleSpells/hex_world.json --click 0,0").unwrap();
+        assert_eq!(options.world.mana, 3);
+        assert_eq!(options.world.clicks.back(), Some(&(0, 0)));
+        assert!(options.world.clicks.len() > 1);
+        assert!(args("run a.spc --world missing.json").is_err());
+        assert!(args("debug a.spc --click 1,1").is_ok());
+        assert!(args("bench a.spc --max-steps 10").is_ok());
+        assert!(args("bench").is_ok());
     }
 }
-
-
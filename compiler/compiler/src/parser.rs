@@ -1,22 +1,24 @@
-use peg;
-use std::{ops::{Range, Deref}, fmt::Debug};
+use std::{cmp::Reverse, ops::{Range, Deref}, fmt::Debug};
+
+use peg::{error::ParseError, str::LineCol};
 
 fn math_tag(left: Tag<Expression>, op: Tag<Op>, right: Tag<Expression>) -> Tag<Expression> {
     let range = left.loc.start..right.loc.end;
-    Tag { item: Expression::Math(Box::new(left), op, Box::new(right)), loc: range }
+    Tag::new(Expression::Math(Box::new(left), op, Box::new(right)), range)
 }
 
 peg::parser! {
     pub grammar spellcode() for str {
-        rule _ = ([' ' | '\n' | '\r' | '\u{200b}'] / block_comment())*
+        rule _ = quiet!{([' ' | '\t' | '\n' | '\r' | '\u{200b}'] / block_comment() / line_comment())*}
 
-        rule t<T>(x: rule<T>) -> Tag<T> = l:position!() v:x() r:position!() { Tag { item: v, loc: l..r } }
-        rule t_v<T, V>(x: rule<T>, v: V) -> Tag<V> = l:position!() x() r:position!() { Tag { item: v, loc: l..r } }
+        rule t<T>(x: rule<T>) -> Tag<T> = l:position!() v:x() r:position!() { Tag::new(v, l..r) }
+        rule t_v<T, V>(x: rule<T>, v: V) -> Tag<V> = l:position!() x() r:position!() { Tag::new(v, l..r) }
 
-        rule integer() -> i32
-            = "0x" v:$(['0'..='9' | 'a'..='f' | 'A'..='F']+) {? i32::from_str_radix(v, 16).or(Err("invalid hexadecimal int")) } /
-              "0b" v:$(['0'..='1']+) {? i32::from_str_radix(v, 2).or(Err("invalid binary int")) } /
-              v:$(['0'..='9']+) {? v.parse().or(Err("invalid int")) }
+        // the sign is parsed along with the digits so that -2147483648 fits
+        rule integer(sign: &str) -> i32
+            = "0x" v:$(['0'..='9' | 'a'..='f' | 'A'..='F']+) {? i32::from_str_radix(&format!("{sign}{v}"), 16).or(Err("invalid hexadecimal int")) } /
+              "0b" v:$(['0'..='1']+) {? i32::from_str_radix(&format!("{sign}{v}"), 2).or(Err("invalid binary int")) } /
+              v:$(['0'..='9']+) {? format!("{sign}{v}").parse().or(Err("invalid int")) }
         rule double() -> f64
             = v:$(['0'..='9']+ "." ['0'..='9']+ ("e" ['0'..='9']+)?) {? v.parse().or(Err("invalid float")) } /
               v:$("." ['0'..='9']+ ("e" ['0'..='9']+)?) {? v.parse().or(Err("invalid float")) } /
@@ -25,11 +27,13 @@ peg::parser! {
         rule bool() -> bool
             = "true" { true } / "false" { false }
 
-        rule block_comment() -> () 
-            = "/*" [_]* "*/"
+        // block comments nest, so commenting out code that has comments in it works
+        rule block_comment() -> ()
+            = "/*" (block_comment() / !"*/" [_])* "*/"
 
+        // the newline is left for `_`, so a comment can end the file
         rule line_comment() -> ()
-            = "//" [^'\n']* "\n"
+            = "//" [^'\n']*
 
         rule escape_sequence() -> char
             = r"\\" { '\\' } /
@@ -51,18 +55,21 @@ peg::parser! {
               "'" v:escape_sequence() "'" { v }
 
         rule literal_no_tag() -> Literal
-            = "-" v:double() { Literal::DoubleL(-v) } /
+            = quiet!{
+              "-" v:double() { Literal::DoubleL(-v) } /
               v:double() { Literal::DoubleL(v) } /
-              "-" v:integer() { Literal::IntL(-v) } /
-              v:integer() { Literal::IntL(v) } /
+              "-" v:integer("-") { Literal::IntL(v) } /
+              v:integer("") { Literal::IntL(v) } /
               v:bool() { Literal::BoolL(v) } /
               v:string() { Literal::StringL(v) } /
               v:char_lit() { Literal::CharL(v) }
+              } / expected!("literal")
         rule literal() -> Tag<Literal>
             = t(<literal_no_tag()>)
 
         rule ident() -> Tag<String>
-            = l:position!() v:$(['A'..='Z' | 'a'..='z'] ['A'..='Z' | 'a'..='z' | '0'..='9' | '_']*) r:position!() { Tag::new(v.to_owned(), l..r) }
+            = quiet!{ l:position!() v:$(['A'..='Z' | 'a'..='z'] ['A'..='Z' | 'a'..='z' | '0'..='9' | '_']*) r:position!() { Tag::new(v.to_owned(), l..r) } } /
+              expected!("identifier")
 
         pub rule expression() -> Tag<Expression> = precedence! {
             x:(@) _ op:t_v(<"||">, Op::BoolOr) _ y:@ { math_tag(x, op, y) }
@@ -95,15 +102,16 @@ peg::parser! {
             --
             operation:t_v(<"!">, UnaryOp::BooleanNot) _ value:@ { let loc = operation.loc.start..value.loc.end; Tag::new(Expression::UnaryOperation(operation, Box::new(value)), loc) }
             operation:t_v(<"~">, UnaryOp::BitwiseNot) _ value:@ { let loc = operation.loc.start..value.loc.end; Tag::new(Expression::UnaryOperation(operation, Box::new(value)), loc) }
-            //operation:t_v(<"-">, UnaryOp::UnaryMinus) _ value:@ { let loc = operation.loc.start..value.loc.end; Tag::new(Expression::UnaryOperation(operation, Box::new(value)), loc) }
+            // a minus right before a number is part of the literal instead
+            operation:t_v(<"-" !['0'..='9' | '.']>, UnaryOp::UnaryMinus) _ value:@ { let loc = operation.loc.start..value.loc.end; Tag::new(Expression::UnaryOperation(operation, Box::new(value)), loc) }
             --
-            v:t(<"(" _ v:expression() _ ")" { v }>) { Tag { item: v.item.item, loc: v.loc } }
+            v:t(<"(" _ v:expression() _ ")" { v }>) { Tag::new(v.item.item, v.loc) }
             --
-            l:position!() name:ident() "(" _ args:expression() ** (_ "," _) _ ")" r:position!() { Tag { item: Expression::FunctionCall { name, args }, loc: l..r } }
+            l:position!() name:ident() "(" _ args:expression() ** (_ "," _) _ ")" r:position!() { Tag::new(Expression::FunctionCall { name, args }, l..r) }
             --
-            x:(@) "." name:ident() r:position!() { let loc = x.loc.start..r; Tag { item: Expression::PropertyAccess(Box::new(x), name), loc } }
+            x:(@) "." name:ident() r:position!() { let loc = x.loc.start..r; Tag::new(Expression::PropertyAccess(Box::new(x), name), loc) }
             --
-            x:(@) "[" _ index:expression() _ "]" r:position!() { let loc = x.loc.start..r; Tag { item: Expression::ArrayAccess { array: Box::new(x), index: Box::new(index) }, loc } }
+            x:(@) "[" _ index:expression() _ "]" r:position!() { let loc = x.loc.start..r; Tag::new(Expression::ArrayAccess { array: Box::new(x), index: Box::new(index) }, loc) }
             --
             v:t(<"new" _ tpe:tpe() _ "[" _ length:expression() _ "]" { Expression::NewArray(tpe, Box::new(length)) }>) { v }
             v:t(<"new" _ name:ident() { Expression::NewStruct(name) }>) { v }
@@ -117,6 +125,10 @@ peg::parser! {
         rule block() -> Vec<Statement>
             = _ "{" _ v:statement() ** (_ ";"? _) ";"? _ "}" _ { v }
 
+        // a block that also keeps where it ends
+        rule function_block() -> (Vec<Statement>, Tag<()>)
+            = _ "{" _ v:statement() ** (_ ";"? _) ";"? _ end:t(<"}">) _ { (v, end) }
+
         rule tpe_name_basic() -> Tag<TypeName>
             = l:position!() "int" r:position!() { Tag::new(TypeName::Int, l..r) } /
               l:position!() "char" r:position!() { Tag::new(TypeName::Char, l..r) } /
@@ -134,24 +146,418 @@ peg::parser! {
         rule func_arg() -> (Tag<String>, Tag<TypeName>)
             = name:ident() _ ":" _ tpe:tpe() { (name, tpe) }
 
+        rule loop_label() -> Tag<String>
+            = l:ident() _ ":" _ { l }
+
+        // break and continue only take a label on the same line, otherwise the next statement
+        // would be mistaken for one
+        rule exit_label() -> Tag<String>
+            = [' ' | '\t']+ l:ident() { l }
+
+        rule word_end() = !['A'..='Z' | 'a'..='z' | '0'..='9' | '_']
+
+        // `else if` is an else block holding just the next if
+        rule if_statement() -> Statement
+            = "if" _ condition:expression() _ block:block() _ "else" _ else_if:if_statement() { Statement::If { condition, block, else_block: Some(vec![else_if]) } } /
+              "if" _ condition:expression() _ block:block() _ "else" _ else_block:block() { Statement::If { condition, block, else_block: Some(else_block) } } /
+              "if" _ condition:expression() _ block:block() { Statement::If { condition, block, else_block: None } }
+
+        rule match_arm() -> (Vec<Tag<Literal>>, Vec<Statement>)
+            = patterns:literal() ++ (_ "," _) _ "=>" _ block:block() { (patterns, block) }
+
+        rule default_arm() -> (Tag<()>, Vec<Statement>)
+            = underscore:t(<"_">) _ "=>" _ block:block() { (underscore, block) }
+
         rule statement() -> Statement
             = "var" _ name:ident() _ "=" _ value:expression() { Statement::VariableDecl(name, value) } /
-              "if" _ condition:expression() _ block:block() _ "else" _ else_block:block() { Statement::If { condition, block, else_block: Some(else_block) } } /
-              "if" _ condition:expression() _ block:block() { Statement::If { condition, block, else_block: None } } /
-              "for" _ "(" _ init:statement()? _ ";" _ condition:expression() _ ";" _ increment:statement()? _ ")" _ block:block() { Statement::CFor { init: Box::new(init), condition, increment: Box::new(increment), block } } /
-              "for" _ variable:ident() _ "in" _ array:expression() _ block:block() { Statement::ForEach { variable, array, block } } /
+              if_statement() /
+              keyword:t(<"match" word_end()>) _ value:expression() _ "{" _ arms:match_arm() ** (_ ","? _) _ ","? _ default:default_arm()? _ ","? _ "}" { Statement::Match { keyword, value, arms, default } } /
+              label:loop_label()? "for" _ "(" _ init:statement()? _ ";" _ condition:expression() _ ";" _ increment:statement()? _ ")" _ block:block() { Statement::CFor { label, init: Box::new(init), condition, increment: Box::new(increment), block } } /
+              label:loop_label()? "for" _ variable:ident() _ "in" _ array:expression() _ block:block() { Statement::ForEach { label, variable, array, block } } /
+              keyword:t(<"break" word_end()>) label:exit_label()? { Statement::Break { keyword, label } } /
+              keyword:t(<"continue" word_end()>) label:exit_label()? { Statement::Continue { keyword, label } } /
               left:expression() _ "=" _ value:expression() { Statement::Assignment { left, value } } /
-              "fun" _ name:ident() _ "(" _ arguments:func_arg() ** (_ "," _) _ ")" _ "->" _ return_type:tpe() _ block:block() { Statement::FunctionDef { name, arguments, return_type: Some(return_type), block } } /
-              "fun" _ name:ident() _ "(" _ arguments:func_arg() ** (_ "," _) _ ")"  _ block:block() { Statement::FunctionDef { name, arguments, return_type: None, block } } /
-              "while" _ condition:expression() _ block:block() { Statement::While { condition, block } } /
-              keyword:t(<"return" { () }>) _ expr:expression()? { Statement::Return { keyword, expr  } } /
+              "fun" _ name:ident() _ "(" _ arguments:func_arg() ** (_ "," _) _ ")" _ "->" _ return_type:tpe() _ block:function_block() { Statement::FunctionDef { name, arguments, return_type: Some(return_type), block: block.0, end: block.1 } } /
+              "fun" _ name:ident() _ "(" _ arguments:func_arg() ** (_ "," _) _ ")"  _ block:function_block() { Statement::FunctionDef { name, arguments, return_type: None, block: block.0, end: block.1 } } /
+              label:loop_label()? "while" _ condition:expression() _ block:block() { Statement::While { label, condition, block } } /
+              keyword:t(<"return">) _ expr:expression()? { Statement::Return { keyword, expr  } } /
               "struct" _ name:ident() _ "{" _ fields:func_arg() ** (_ "," _) _ "}" { Statement::StructDef { name, fields } } /
               v:expression() { Statement::ExprS(v) }
 
         pub rule program() -> Vec<Statement> = _ v:statement() ** (_ ";"? _) _ ";"? _ { v }
+
+        // every comment in the source, in order.  String and char literals are skipped so a `//`
+        // in one isn't mistaken for a comment
+        pub rule comments() -> Vec<Tag<String>>
+            = v:(c:t(<c:$(block_comment() / line_comment()) { c.to_owned() }>) { Some(c) } / string() { None } / char_lit() { None } / [_] { None })* { v.into_iter().flatten().collect() }
+
+        // the source cut into tokens by the same rules the grammar reads them with, for
+        // highlighting.  Never fails, a character that can't start a token is `Unknown`
+        pub rule tokens() -> Vec<Tag<Lexeme>>
+            = v:([' ' | '\t' | '\n' | '\r' | '\u{200b}']+ { None } / v:t(<lexeme()>) { Some(v) })* { v.into_iter().flatten().collect() }
+
+        rule lexeme() -> Lexeme
+            = (block_comment() / line_comment()) { Lexeme::Comment } /
+              // out of range ints are still numbers, the parser reports them
+              (double() / integer("") / ['0'..='9']+ / string() / char_lit()) { Lexeme::Literal } /
+              ['A'..='Z' | 'a'..='z'] ['A'..='Z' | 'a'..='z' | '0'..='9' | '_']* { Lexeme::Word } /
+              ("->" / "=>" / ">>>" / "<<" / ">>" / "<=" / ">=" / "==" / "!=" / "&&" / "||" / ['+' | '-' | '*' | '/' | '%' | '<' | '>' | '=' | '!' | '~' | '&' | '|' | '^']) { Lexeme::Operator } /
+              ['(' | ')' | '{' | '}' | '[' | ']' | ',' | ';' | ':' | '.'] { Lexeme::Punctuation } /
+              [_] { Lexeme::Unknown }
+    }
+}
+
+const STATEMENT_KEYWORDS: [&str; 10] = ["var", "if", "match", "for", "while", "return", "break", "continue", "fun", "struct"];
+
+/// Parses a program, continuing past syntax errors so they can all be reported at once.
+///
+/// After each error, the broken statement is blanked out (keeping every byte offset the same)
+/// and the program is parsed again, until it either parses or nothing more can be skipped.
+pub fn parse_program(src: &str) -> Result<Vec<Statement>, Vec<ParseError<LineCol>>> {
+    match parse_recovering(src) {
+        (Some(v), errors) if errors.is_empty() => Ok(v),
+        (_, errors) => Err(errors)
+    }
+}
+
+/// Like `parse_program`, but also gives back what was left of the program once the broken
+/// statements were blanked out, if that parses.  Offsets in it match the original source
+#[allow(unused)]
+pub fn parse_recovering(src: &str) -> (Option<Vec<Statement>>, Vec<ParseError<LineCol>>) {
+    let mut text = src.to_owned();
+    let mut errors: Vec<ParseError<LineCol>> = vec![];
+    loop {
+        let e = match spellcode::program(&text) {
+            Ok(v) => return (Some(v), errors),
+            Err(e) => e
+        };
+        let at = e.location.offset;
+        if errors.last().is_none_or(|x| x.location.offset != at) {
+            errors.push(e);
+        }
+        if at >= text.len() {
+            // the last statement runs into the end, so drop it and see if the rest parses.  It
+            // can span lines, so take the fewest lines that get the rest parsing
+            let (start, _) = resync_region(&text, at);
+            if text[start..].trim().is_empty() {
+                return (None, errors);
+            }
+            let line_starts = text[start..].match_indices('\n').map(|x| start + x.0 + 1);
+            text = line_starts.rev()
+                .filter(|&line| !text[line..].trim().is_empty())
+                .map(|line| blanked(&text, line..text.len()))
+                .find(|t| spellcode::program(t).is_ok())
+                .unwrap_or_else(|| blanked(&text, start..text.len()));
+            continue;
+        }
+
+        let (start, ends) = resync_region(&text, at);
+        if ends.iter().all(|&end| text[start..end].trim().is_empty()) {
+            // nothing left to skip around the error, so skip the offending character itself
+            text = blanked(&text, at..at + text[at..].chars().next().map_or(0, char::len_utf8));
+            continue;
+        }
+        // take the shortest skip that gets the parser past it, a statement can span lines
+        let (last, candidates) = ends.split_last().unwrap();
+        text = candidates.iter()
+            .map(|&end| (end, blanked(&text, start..end)))
+            .find(|(end, t)| match spellcode::program(t) {
+                Ok(_) => true,
+                Err(e) => e.location.offset >= *end
+            })
+            .map(|x| x.1)
+            .unwrap_or_else(|| blanked(&text, start..*last));
+    }
+}
+
+/// Like `parse_program`, but with each comment attached to a node as trivia, for the formatter.
+/// A comment leads the next node, unless there's a `}` before it, then it trails the node before
+/// instead.  The comments in a program with no nodes at all are given back on their own
+#[allow(unused)]
+pub fn parse_with_comments(src: &str) -> Result<(Vec<Statement>, Comments), Vec<ParseError<LineCol>>> {
+    let mut program = parse_program(src)?;
+    // can't fail, anything that isn't a comment is skipped over
+    let comments = spellcode::comments(src).unwrap();
+    let mut nodes = vec![];
+    for statement in &mut program {
+        statement_nodes(statement, &mut nodes);
+    }
+    // nodes starting at the same place are outermost first, the sort keeps the walk's order
+    // for ones with the same span too
+    nodes.sort_by_key(|(loc, _)| (loc.start, Reverse(loc.end)));
+    let mut loose = vec![];
+    for comment in comments {
+        let next = nodes.partition_point(|(loc, _)| loc.start < comment.loc.end);
+        let gap_end = nodes.get(next).map_or(src.len(), |x| x.0.start);
+        let closes_block = unquoted(&src.as_bytes()[comment.loc.end..gap_end]).any(|(_, c)| c == b'}');
+        if next < nodes.len() && !closes_block {
+            nodes[next].1.leading.push(comment);
+            continue;
+        }
+        let before = nodes.iter_mut()
+            .filter(|(loc, _)| loc.end <= comment.loc.start)
+            .min_by_key(|(loc, _)| (Reverse(loc.end), loc.start));
+        match before {
+            Some((_, trivia)) => trivia.trailing.push(comment),
+            None => loose.push(comment)
+        }
+    }
+    Ok((program, loose))
+}
+
+pub type Comments = Vec<Tag<String>>;
+
+type Nodes<'a> = Vec<(Range<usize>, &'a mut Trivia)>;
+
+// adds a node to the list and gives back what's inside it
+fn node<'a, T>(tag: &'a mut Tag<T>, out: &mut Nodes<'a>) -> &'a mut T {
+    let Tag { item, loc, trivia } = tag;
+    out.push((loc.clone(), trivia));
+    item
+}
+
+fn statement_nodes<'a>(statement: &'a mut Statement, out: &mut Nodes<'a>) {
+    fn block_nodes<'a>(block: &'a mut [Statement], out: &mut Nodes<'a>) {
+        for statement in block {
+            statement_nodes(statement, out);
+        }
+    }
+
+    match statement {
+        Statement::ExprS(expr) => expression_nodes(expr, out),
+        Statement::VariableDecl(name, value) => {
+            node(name, out);
+            expression_nodes(value, out);
+        }
+        Statement::Assignment { left, value } => {
+            expression_nodes(left, out);
+            expression_nodes(value, out);
+        }
+        Statement::If { condition, block, else_block } => {
+            expression_nodes(condition, out);
+            block_nodes(block, out);
+            if let Some(else_block) = else_block {
+                block_nodes(else_block, out);
+            }
+        }
+        Statement::Match { keyword, value, arms, default } => {
+            node(keyword, out);
+            expression_nodes(value, out);
+            for (patterns, block) in arms {
+                for pattern in patterns {
+                    node(pattern, out);
+                }
+                block_nodes(block, out);
+            }
+            if let Some((underscore, block)) = default {
+                node(underscore, out);
+                block_nodes(block, out);
+            }
+        }
+        Statement::CFor { label, init, condition, increment, block } => {
+            if let Some(label) = label {
+                node(label, out);
+            }
+            if let Some(init) = init.as_mut() {
+                statement_nodes(init, out);
+            }
+            expression_nodes(condition, out);
+            if let Some(increment) = increment.as_mut() {
+                statement_nodes(increment, out);
+            }
+            block_nodes(block, out);
+        }
+        Statement::ForEach { label, variable, array, block } => {
+            if let Some(label) = label {
+                node(label, out);
+            }
+            node(variable, out);
+            expression_nodes(array, out);
+            block_nodes(block, out);
+        }
+        Statement::While { label, condition, block } => {
+            if let Some(label) = label {
+                node(label, out);
+            }
+            expression_nodes(condition, out);
+            block_nodes(block, out);
+        }
+        Statement::Return { keyword, expr } => {
+            node(keyword, out);
+            if let Some(expr) = expr {
+                expression_nodes(expr, out);
+            }
+        }
+        Statement::Break { keyword, label } | Statement::Continue { keyword, label } => {
+            node(keyword, out);
+            if let Some(label) = label {
+                node(label, out);
+            }
+        }
+        Statement::FunctionDef { name, arguments, return_type, block, end } => {
+            node(name, out);
+            for (name, tpe) in arguments {
+                node(name, out);
+                type_nodes(tpe, out);
+            }
+            if let Some(return_type) = return_type {
+                type_nodes(return_type, out);
+            }
+            block_nodes(block, out);
+            node(end, out);
+        }
+        Statement::StructDef { name, fields } => {
+            node(name, out);
+            for (name, tpe) in fields {
+                node(name, out);
+                type_nodes(tpe, out);
+            }
+        }
+    }
+}
+
+fn expression_nodes<'a>(expr: &'a mut Tag<Expression>, out: &mut Nodes<'a>) {
+    match node(expr, out) {
+        Expression::Lit(lit) => {
+            node(lit, out);
+        }
+        Expression::Math(left, op, right) => {
+            expression_nodes(left, out);
+            node(op, out);
+            expression_nodes(right, out);
+        }
+        Expression::FunctionCall { name, args } => {
+            node(name, out);
+            for arg in args {
+                expression_nodes(arg, out);
+            }
+        }
+        Expression::PropertyAccess(x, name) => {
+            expression_nodes(x, out);
+            node(name, out);
+        }
+        Expression::Ternary { condition, if_true, if_false } => {
+            expression_nodes(condition, out);
+            expression_nodes(if_true, out);
+            expression_nodes(if_false, out);
+        }
+        Expression::ArrayAccess { array, index } => {
+            expression_nodes(array, out);
+            expression_nodes(index, out);
+        }
+        Expression::VarAccess(name) | Expression::NewStruct(name) => {
+            node(name, out);
+        }
+        Expression::NewArray(tpe, length) => {
+            type_nodes(tpe, out);
+            expression_nodes(length, out);
+        }
+        Expression::UnaryOperation(op, value) => {
+            node(op, out);
+            expression_nodes(value, out);
+        }
     }
 }
 
+fn type_nodes<'a>(tpe: &'a mut Tag<TypeName>, out: &mut Nodes<'a>) {
+    match node(tpe, out) {
+        TypeName::Array(inner) => type_nodes(inner, out),
+        TypeName::Struct(name) => {
+            node(name, out);
+        }
+        _ => {}
+    }
+}
+
+fn blanked(text: &str, region: Range<usize>) -> String {
+    let blank = text[region.clone()].chars()
+        .map(|c| if c == '\n' { "\n".to_owned() } else { " ".repeat(c.len_utf8()) })
+        .collect::<String>();
+    let mut out = text.to_owned();
+    out.replace_range(region, &blank);
+    out
+}
+
+// Finds the extent of the statement containing the error at `at`.  Statements end at `;` and
+// `}`, start after `;`, `{` and `}`, and a line beginning with a keyword always starts a new one.
+// Any other newline might end the statement too, so those are returned as earlier candidates
+// for the end, with the definite end last.
+fn resync_region(text: &str, at: usize) -> (usize, Vec<usize>) {
+    let starts_statement = |i: usize| {
+        let line = text[i..].trim_start_matches([' ', '\t', '\r']);
+        STATEMENT_KEYWORDS.iter().any(|kw| line.starts_with(kw)
+            && !line[kw.len()..].starts_with(|c: char| c.is_ascii_alphanumeric() || c == '_'))
+    };
+
+    let mut start = 0;
+    let mut ends = vec![];
+    let mut depth = 0;
+    for (i, c) in unquoted(text.as_bytes()) {
+        if i < at {
+            match c {
+                b';' | b'{' | b'}' => start = i + 1,
+                b'\n' if starts_statement(i + 1) => start = i + 1,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            b'{' => depth += 1,
+            b'}' if depth == 0 => return (start, with_end(ends, i)),
+            b'}' => depth -= 1,
+            b';' if depth == 0 => return (start, with_end(ends, i + 1)),
+            b'\n' if depth == 0 && starts_statement(i + 1) => return (start, with_end(ends, i)),
+            b'\n' if depth == 0 => ends.push(i),
+            _ => {}
+        }
+    }
+
+    (start, with_end(ends, text.len()))
+}
+
+fn with_end(mut ends: Vec<usize>, end: usize) -> Vec<usize> {
+    ends.push(end);
+    ends
+}
+
+// the bytes of the text that aren't inside a string or char literal or a comment
+pub fn unquoted(bytes: &[u8]) -> impl Iterator<Item = (usize, u8)> {
+    let mut quote = None;
+    let mut escaped = false;
+    let mut line_comment = false;
+    let mut block_depth = 0;
+    // the second byte of a comment delimiter
+    let mut skip = false;
+    bytes.iter().copied().enumerate().filter(move |&(i, c)| {
+        let next = bytes.get(i + 1).copied();
+        if skip {
+            skip = false;
+        } else if line_comment {
+            // the newline still ends the statement
+            line_comment = c != b'\n';
+            return c == b'\n';
+        } else if block_depth > 0 {
+            match (c, next) {
+                (b'/', Some(b'*')) => { block_depth += 1; skip = true }
+                (b'*', Some(b'/')) => { block_depth -= 1; skip = true }
+                _ => {}
+            }
+        } else {
+            match quote {
+                Some(_) if escaped => escaped = false,
+                Some(_) if c == b'\\' => escaped = true,
+                Some(q) if c == q => quote = None,
+                Some(_) => {}
+                None if c == b'/' && next == Some(b'/') => line_comment = true,
+                None if c == b'/' && next == Some(b'*') => { block_depth = 1; skip = true }
+                None if c == b'"' || c == b'\'' => quote = Some(c),
+                None => return true
+            }
+        }
+        false
+    })
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Literal {
     IntL(i32),
@@ -161,6 +567,16 @@ pub enum Literal {
     CharL(char),
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lexeme {
+    Word,
+    Literal,
+    Operator,
+    Punctuation,
+    Comment,
+    Unknown
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Op {
     Plus, Minus, Times, Divide, Mod,
@@ -175,6 +591,26 @@ pub enum UnaryOp {
     UnaryMinus, BitwiseNot, BooleanNot
 }
 
+impl Op {
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            Op::Plus => "+", Op::Minus => "-", Op::Times => "*", Op::Divide => "/", Op::Mod => "%",
+            Op::Shl => "<<", Op::Shr => ">>", Op::Shrl => ">>>",
+            Op::Lt => "<", Op::Le => "<=", Op::Eq => "==", Op::Ne => "!=", Op::Ge => ">=", Op::Gt => ">",
+            Op::BoolAnd => "&&", Op::BoolOr => "||",
+            Op::And => "&", Op::Or => "|", Op::Xor => "^"
+        }
+    }
+}
+
+impl UnaryOp {
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            UnaryOp::UnaryMinus => "-", UnaryOp::BitwiseNot => "~", UnaryOp::BooleanNot => "!"
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Expression {
     Lit(Tag<Literal>),
@@ -200,19 +636,69 @@ pub enum Statement {
     VariableDecl(Tag<String>, Tag<Expression>),
     Assignment { left: Tag<Expression>, value: Tag<Expression> },
     If { condition: Tag<Expression>, block: Vec<Statement>, else_block: Option<Vec<Statement>> },
-    CFor { init: Box<Option<Statement>>, condition: Tag<Expression>, increment: Box<Option<Statement>>, block: Vec<Statement> },
-    ForEach { variable: Tag<String>, array: Tag<Expression>, block: Vec<Statement> },
-    While { condition: Tag<Expression>, block: Vec<Statement> },
+    // each arm runs if the value equals any of its literals, the default arm is the `_` one
+    Match { keyword: Tag<()>, value: Tag<Expression>, arms: Vec<(Vec<Tag<Literal>>, Vec<Statement>)>, default: Option<(Tag<()>, Vec<Statement>)> },
+    CFor { label: Option<Tag<String>>, init: Box<Option<Statement>>, condition: Tag<Expression>, increment: Box<Option<Statement>>, block: Vec<Statement> },
+    ForEach { label: Option<Tag<String>>, variable: Tag<String>, array: Tag<Expression>, block: Vec<Statement> },
+    While { label: Option<Tag<String>>, condition: Tag<Expression>, block: Vec<Statement> },
     Return { keyword: Tag<()>, expr: Option<Tag<Expression>> },
-    FunctionDef { name: Tag<String>, arguments: Vec<(Tag<String>, Tag<TypeName>)>, return_type: Option<Tag<TypeName>>, block: Vec<Statement> },
+    // the label picks which loop to leave when they're nested, otherwise it's the innermost one
+    Break { keyword: Tag<()>, label: Option<Tag<String>> },
+    Continue { keyword: Tag<()>, label: Option<Tag<String>> },
+    FunctionDef { name: Tag<String>, arguments: Vec<(Tag<String>, Tag<TypeName>)>, return_type: Option<Tag<TypeName>>, block: Vec<Statement>, end: Tag<()> },
     StructDef { name: Tag<String>, fields: Vec<(Tag<String>, Tag<TypeName>)> }
 }
 
+impl Statement {
+    /// The span from the first to the last tagged part of the statement.  Keywords and
+    /// closing braces that aren't tagged are left out
+    pub fn loc(&self) -> Range<usize> {
+        fn block_end(block: &[Statement], default: usize) -> usize {
+            block.last().map_or(default, |x| x.loc().end)
+        }
+
+        match self {
+            Statement::ExprS(expr) => expr.loc.clone(),
+            Statement::VariableDecl(name, expr) => name.loc.start..expr.loc.end,
+            Statement::Assignment { left, value } => left.loc.start..value.loc.end,
+            Statement::If { condition, block, else_block } => {
+                let end = block_end(block, condition.loc.end);
+                condition.loc.start..else_block.as_ref().map_or(end, |x| block_end(x, end))
+            }
+            Statement::Match { keyword, value, arms, default } => {
+                let end = arms.last().map_or(value.loc.end, |x| block_end(&x.1, x.0.last().unwrap().loc.end));
+                keyword.loc.start..default.as_ref().map_or(end, |x| block_end(&x.1, x.0.loc.end))
+            }
+            Statement::CFor { label, init, condition, increment, block } => {
+                let start = init.as_ref().as_ref().map_or(condition.loc.start, |x| x.loc().start);
+                let end = increment.as_ref().as_ref().map_or(condition.loc.end, |x| x.loc().end);
+                label.as_ref().map_or(start, |x| x.loc.start)..block_end(block, end)
+            }
+            Statement::ForEach { label, variable, array, block } => label.as_ref().unwrap_or(variable).loc.start..block_end(block, array.loc.end),
+            Statement::While { label, condition, block } => label.as_ref().map_or(condition.loc.start, |x| x.loc.start)..block_end(block, condition.loc.end),
+            Statement::Return { keyword, expr } => keyword.loc.start..expr.as_ref().map_or(keyword.loc.end, |x| x.loc.end),
+            Statement::Break { keyword, label } | Statement::Continue { keyword, label } => keyword.loc.start..label.as_ref().map_or(keyword.loc.end, |x| x.loc.end),
+            Statement::FunctionDef { name, end, .. } => name.loc.start..end.loc.end,
+            Statement::StructDef { name, fields } => name.loc.start..fields.last().map_or(name.loc.end, |x| x.1.loc.end)
+        }
+    }
+}
+
 // Tag class and methods from https://github.com/blahblahbloopster/calculator-3
 #[derive(Clone)]
 pub struct Tag<T> {
     pub item: T,
-    pub loc: Range<usize>
+    pub loc: Range<usize>,
+    // only filled in by `parse_with_comments`, equality and Debug leave it out
+    pub trivia: Trivia
+}
+
+/// The comments next to a tagged node.  Leading ones come right before it, trailing ones come
+/// after it with a `}` before the next node, so they end the block it's the last thing in
+#[derive(Clone, Default)]
+pub struct Trivia {
+    pub leading: Vec<Tag<String>>,
+    pub trailing: Vec<Tag<String>>
 }
 
 impl<T> Deref for Tag<T> {
@@ -230,8 +716,8 @@ impl<T : Debug> Debug for Tag<T> {
 }
 
 impl<T> Tag<T> {
-    fn new(item: T, loc: Range<usize>) -> Tag<T> {
-        Tag { item, loc }
+    pub fn new(item: T, loc: Range<usize>) -> Tag<T> {
+        Tag { item, loc, trivia: Trivia::default() }
     }
 }
 
@@ -311,5 +797,122 @@ mod tests {
         assert!(matches!(spellcode::expression("1 + 2 * 3"), Ok(t!(Expression::Math(t!(bil 1), t!(Op::Plus), box t!(Expression::Math(t!(bil 2), t!(Op::Times), t!(bil 3))))))));
         assert!(matches!(spellcode::expression("(1 + 2) * 3"), Ok(t!(Expression::Math(box t!(Expression::Math(t!(bil 1), t!(Op::Plus), t!(bil 2))), t!(Op::Times), t!(bil 3))))));
     }
-}
 
+    #[test]
+    fn test_unary_minus() {
+        assert!(matches!(spellcode::expression("-x"), Ok(t!(Expression::UnaryOperation(t!(UnaryOp::UnaryMinus), box t!(Expression::VarAccess(_)))))));
+        assert!(matches!(spellcode::expression("-(1 + 2)"), Ok(t!(Expression::UnaryOperation(t!(UnaryOp::UnaryMinus), box t!(Expression::Math(..)))))));
+        assert!(matches!(spellcode::expression("- 3"), Ok(t!(Expression::UnaryOperation(t!(UnaryOp::UnaryMinus), t!(bil 3))))));
+        assert!(matches!(spellcode::expression("--3"), Ok(t!(Expression::UnaryOperation(t!(UnaryOp::UnaryMinus), t!(bil -3))))));
+        assert!(matches!(spellcode::expression("-a.size"), Ok(t!(Expression::UnaryOperation(_, box t!(Expression::PropertyAccess(..)))))));
+
+        // negative literals and binary minus are unchanged
+        assert!(matches!(spellcode::expression("-3"), Ok(t!(eil -3))));
+        assert!(matches!(spellcode::expression("-2147483648"), Ok(t!(eil -2147483648))));
+        assert!(matches!(spellcode::expression("-0x80000000"), Ok(t!(eil -2147483648))));
+        assert!(matches!(spellcode::expression("-.5"), Ok(t!(edl -0.5))));
+        assert!(matches!(spellcode::expression("a - 3"), Ok(t!(Expression::Math(_, t!(Op::Minus), t!(bil 3))))));
+        assert!(matches!(spellcode::expression("a -x"), Ok(t!(Expression::Math(_, t!(Op::Minus), box t!(Expression::VarAccess(_)))))));
+        assert!(matches!(spellcode::expression("a - -x"), Ok(t!(Expression::Math(_, t!(Op::Minus), box t!(Expression::UnaryOperation(..)))))));
+        assert!(matches!(spellcode::expression("2 * -x"), Ok(t!(Expression::Math(_, t!(Op::Times), box t!(Expression::UnaryOperation(..)))))));
+    }
+
+    #[test]
+    fn test_break_continue() {
+        let parsed = parse_program("outer: while true {\n    for x in a { break outer }\n    continue\n}").unwrap();
+        let [Statement::While { label: Some(t!(label)), block, .. }] = parsed.as_slice() else { panic!("{parsed:?}") };
+        assert_eq!(label, "outer");
+        assert!(matches!(block.as_slice(), [Statement::ForEach { label: None, .. }, Statement::Continue { label: None, .. }]));
+        let Statement::ForEach { block, .. } = &block[0] else { unreachable!() };
+        assert!(matches!(block.as_slice(), [Statement::Break { label: Some(_), .. }]));
+
+        // a label has to be on the same line, and keywords have to be whole words
+        assert!(matches!(parse_program("while true {\n    break\n    f()\n}").unwrap().as_slice(),
+            [Statement::While { block, .. }] if matches!(block.as_slice(), [Statement::Break { label: None, .. }, Statement::ExprS(_)])));
+        assert!(matches!(parse_program("breakfast = 1").unwrap().as_slice(), [Statement::Assignment { .. }]));
+        assert!(matches!(parse_program("a: for (var i = 0; i < 1; i = i + 1) { }").unwrap().as_slice(), [Statement::CFor { label: Some(_), .. }]));
+    }
+
+    #[test]
+    fn test_else_if() {
+        let parsed = parse_program("if a { } else if b { f() } else { }").unwrap();
+        let [Statement::If { else_block: Some(else_block), .. }] = parsed.as_slice() else { panic!("{parsed:?}") };
+        assert!(matches!(else_block.as_slice(), [Statement::If { block, else_block: Some(_), .. }] if block.len() == 1));
+        assert!(matches!(parse_program("if a { } else if b { }").unwrap().as_slice(), [Statement::If { else_block: Some(x), .. }] if matches!(x.as_slice(), [Statement::If { else_block: None, .. }])));
+    }
+
+    #[test]
+    fn test_match() {
+        let parsed = parse_program("match x {\n    1, 2 => { f() }\n    'a' => { }\n    _ => { g() }\n}").unwrap();
+        let [Statement::Match { arms, default: Some(_), .. }] = parsed.as_slice() else { panic!("{parsed:?}") };
+        assert_eq!(arms.iter().map(|x| x.0.len()).collect::<Vec<_>>(), vec![2, 1]);
+        assert!(matches!(parse_program("match x { 1 => { }, _ => { } }").unwrap().as_slice(), [Statement::Match { .. }]));
+        assert!(matches!(parse_program("match x { }").unwrap().as_slice(), [Statement::Match { arms, default: None, .. }] if arms.is_empty()));
+        assert!(matches!(parse_program("matches = 1").unwrap().as_slice(), [Statement::Assignment { .. }]));
+        // the default arm has to come last
+        assert!(parse_program("match x { _ => { } 1 => { } }").is_err());
+    }
+
+    #[test]
+    fn test_comments() {
+        let parsed = parse_program("// a spell\nvar x = 1 // one\n\tvar y = x / 2 /* two */ var z = 3\n// the end").unwrap();
+        assert_eq!(parsed.len(), 3);
+        assert!(matches!(parse_program("/* outer /* inner */ still outer */ f()").unwrap().as_slice(), [Statement::ExprS(_)]));
+        assert!(matches!(parse_program("f(/* a */ 1, // b\n 2)").unwrap().as_slice(), [Statement::ExprS(_)]));
+        assert!(parse_program("/* unclosed /* */ f()").is_err());
+        let parsed = parse_program("var s = \"// not /* a comment\"").unwrap();
+        let [Statement::VariableDecl(_, t!(Expression::Lit(t!(Literal::StringL(s)))))] = parsed.as_slice() else { panic!("{parsed:?}") };
+        assert_eq!(s, "// not /* a comment");
+        assert!(parse_program("while true {\n\tbreak // done\n}").is_ok());
+    }
+
+    #[test]
+    fn test_comment_trivia() {
+        let text = |comments: &[Tag<String>]| comments.iter().map(|x| x.item.clone()).collect::<Vec<_>>();
+        let (parsed, loose) = parse_with_comments("// x\nvar a = 1 /* b */\nif a {\n    f(/* c */ 2) // d\n} // e").unwrap();
+        assert!(loose.is_empty());
+        let [Statement::VariableDecl(name, _), Statement::If { condition, block, .. }] = parsed.as_slice() else { panic!("{parsed:?}") };
+        assert_eq!(text(&name.trivia.leading), ["// x"]);
+        assert_eq!(text(&condition.trivia.leading), ["/* b */"]);
+        let [Statement::ExprS(call @ t!(Expression::FunctionCall { args, .. }))] = block.as_slice() else { panic!("{block:?}") };
+        assert_eq!(text(&args[0].trivia.leading), ["/* c */"]);
+        // both end a block, `e` the one the if is in
+        assert_eq!(text(&call.trivia.trailing), ["// d", "// e"]);
+
+        let (parsed, loose) = parse_with_comments("/* only */ // comments").unwrap();
+        assert!(parsed.is_empty());
+        assert_eq!(text(&loose), ["/* only */", "// comments"]);
+    }
+
+    fn error_offsets(src: &str) -> Vec<usize> {
+        parse_program(src).expect_err("should not parse").iter().map(|x| x.location.offset).collect()
+    }
+
+    #[test]
+    fn test_error_recovery() {
+        assert!(parse_program("var x = 1; x = x + 2").is_ok());
+        assert_eq!(error_offsets("var x = 1 +* 2"), vec![11]);
+        assert_eq!(error_offsets("var x = 1 +* 2; var y = (3; y = 4"), vec![11, 26]);
+        assert_eq!(error_offsets("var x = 1 +* 2\nvar y = 3\nvar z = / 4\n"), vec![11, 33]);
+        assert_eq!(error_offsets("fun f() {\n    var a = *\n    a = ]\n}\nf(,)"), vec![22, 32, 38]);
+        assert_eq!(error_offsets("while true { var s = \"};\" +* 1 }; var t = %"), vec![27, 42]);
+        // quotes and braces in comments don't confuse finding where statements end
+        assert_eq!(error_offsets("var x = 1 +* 2 // don't }\nvar y = ]\n/* it's { */ var z = 3"), vec![11, 34]);
+    }
+
+    #[test]
+    fn test_error_recovery_unclosed() {
+        assert_eq!(error_offsets("fun f() {\n    var a = 1\n"), vec![24]);
+        assert_eq!(error_offsets("var a = 1 }\nvar b = ]"), vec![10, 20]);
+    }
+
+    #[test]
+    fn test_parse_recovering() {
+        // what's left after the broken statements keeps its offsets
+        let (parsed, errors) = parse_recovering("var x = 1 +* 2\nvar y = 3\ny.");
+        assert_eq!(errors.len(), 2);
+        assert!(matches!(parsed.as_deref(), Some([Statement::VariableDecl(Tag { loc: Range { start: 19, .. }, .. }, _)])));
+        let (parsed, _) = parse_recovering("fun f() {\n    var a = 1\n");
+        assert!(parsed.is_none());
+    }
+}
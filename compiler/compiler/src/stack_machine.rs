@@ -1,4 +1,6 @@
-use std::{char, collections::HashMap};
+use std::{char, collections::HashSet, ops::Range};
+
+use crate::compiler::{CompType, DeclaredFunction, SourceMap};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Syscall {
@@ -16,7 +18,16 @@ pub enum Syscall {
     GetNeighbors = 11
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+impl Syscall {
+    // every syscall, in the order of their numbers
+    #[allow(unused)]
+    pub const ALL: [Syscall; 12] = [
+        Syscall::Nop, Syscall::GetMana, Syscall::EnvironmentID, Syscall::SpawnEffect, Syscall::PlayerLocation, Syscall::ClickLocation,
+        Syscall::Sleep, Syscall::PrintChar, Syscall::Halt, Syscall::Exception, Syscall::MoveEffect, Syscall::GetNeighbors
+    ];
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Tpe {
     Int, Double, Array(Box<Tpe>), Struct(Vec<Tpe>)
 }
@@ -106,16 +117,188 @@ impl From<f64> for StackItem {
 pub struct HeapItem {
     pub value: Vec<StackItem>,
     pub mark: bool,
+    #[allow(unused)]
     pub tpe: Tpe
 }
 
+/// The objects on a VM's heap.  A heap address is the index of the object's slot, and slots
+/// freed by the garbage collector are reused, so addresses stay small however long it runs
+#[derive(Default)]
+pub struct Heap {
+    slots: Vec<Option<HeapItem>>,
+    // the empty slots, the next to be reused last
+    free: Vec<usize>,
+    len: usize
+}
+
+impl Heap {
+    pub fn get(&self, id: usize) -> Option<&HeapItem> {
+        self.slots.get(id)?.as_ref()
+    }
+
+    pub fn get_mut(&mut self, id: usize) -> Option<&mut HeapItem> {
+        self.slots.get_mut(id)?.as_mut()
+    }
+
+    /// Adds an object, giving back its address
+    pub fn insert(&mut self, item: HeapItem) -> usize {
+        self.len += 1;
+        match self.free.pop() {
+            Some(id) => {
+                self.slots[id] = Some(item);
+                id
+            }
+            None => {
+                self.slots.push(Some(item));
+                self.slots.len() - 1
+            }
+        }
+    }
+
+    /// The number of objects
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[allow(unused)]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Each object with its address, in order of address
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &HeapItem)> {
+        self.slots.iter().enumerate().filter_map(|(id, x)| Some((id, x.as_ref()?)))
+    }
+
+    /// Frees every object that keep returns false for
+    pub fn retain(&mut self, mut keep: impl FnMut(&HeapItem) -> bool) {
+        for (id, slot) in self.slots.iter_mut().enumerate() {
+            if slot.as_ref().is_some_and(|x| !keep(x)) {
+                *slot = None;
+                self.free.push(id);
+                self.len -= 1;
+            }
+        }
+        // the lowest addresses get reused first
+        self.free.sort_by(|a, b| b.cmp(a));
+    }
+}
+
+// the most items the stack can hold, so runaway recursion fails rather than taking all the
+// host's memory
+pub const MAX_STACK: usize = 1 << 20;
+// the smallest the heap is allowed to grow to, in cells, before it's collected
+const MIN_GC_THRESHOLD: usize = 4096;
+// how many cells each instruction marks during a collection, by default
+const GC_BUDGET: usize = 256;
+
+/// The most a VM's heap can hold.  Allocating past it collects all the garbage, then fails with
+/// OutOfMemory if that didn't free enough
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapLimit {
+    // counting each object and each value in one as a cell, like the garbage collector
+    pub cells: usize,
+    pub objects: usize
+}
+
+impl Default for HeapLimit {
+    fn default() -> Self {
+        HeapLimit { cells: 1 << 20, objects: 1 << 16 }
+    }
+}
+
+/// Frees unreachable heap objects a little at a time, so no one instruction takes long.  A
+/// collection starts once the heap has doubled since the last one finished.  Each instruction
+/// after that marks a bounded number of cells, starting from the stack, and once everything
+/// reachable is marked the rest is freed in one go
+#[derive(Debug)]
+pub struct GarbageCollector {
+    // objects found reachable that haven't been looked inside yet, None between collections
+    gray: Option<Vec<usize>>,
+    // the value of HeapItem::mark that means reachable.  Flipping it when a collection starts
+    // unmarks everything at once
+    marked: bool,
+    // the size of the heap, counting each object and each value in one as a cell
+    cells: usize,
+    // the size the heap can grow to before the next collection starts
+    threshold: usize,
+    // how many cells to mark per instruction, with none only garbage_collect collects
+    pub budget: usize,
+    pub stats: GcStats
+}
+
+/// What the garbage collector has done, for measuring it
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GcStats {
+    pub collections: usize,
+    // cells marked or looked at on the stack, in total and the most in any one step
+    pub marked: usize,
+    pub longest_step: usize,
+    // objects freed
+    pub freed: usize
+}
+
+impl Default for GarbageCollector {
+    fn default() -> Self {
+        GarbageCollector { gray: None, marked: true, cells: 0, threshold: MIN_GC_THRESHOLD, budget: GC_BUDGET, stats: GcStats::default() }
+    }
+}
+
+/// A function call in progress, as found on the stack
+#[derive(Debug, Clone)]
+#[allow(unused)]
+pub struct Frame {
+    // the instruction being run, which is a call in all but the innermost frame
+    pub pc: usize,
+    // None for the top level code, or if there's no source map
+    pub function: Option<DeclaredFunction>,
+    // the statement being run, if it's in the user's code
+    pub span: Option<Range<usize>>
+}
+
+/// How far to run a paused program before pausing it again
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(unused)]
+pub enum Step {
+    // to the next statement, even if it's in a function being called
+    Into,
+    // to the next statement in the same function, or the one it returns to
+    Over,
+    // to the next statement after the current function returns
+    Out
+}
+
+/// Where to pause a program being debugged.  It only ever pauses at the start of a statement in
+/// the user's code, so it needs a source map
+#[derive(Debug, Default)]
+pub struct Debugger {
+    // the first instructions of the statements with breakpoints on them
+    breakpoints: HashSet<usize>,
+    // the step asked for, and how many calls deep the program was at the time
+    step: Option<(Step, usize)>,
+    // where it last paused, which it shouldn't pause at again straight away when resumed
+    paused_at: Option<usize>
+}
+
+/// A variable in scope in a paused program
+#[derive(Debug, Clone, PartialEq)]
+pub struct Variable {
+    pub name: String,
+    pub tpe: CompType,
+    pub value: String
+}
+
 pub struct VM {
     pub stack: Vec<StackItem>,
     pub program: Vec<Instruction>,
     pub program_counter: usize,
-    pub heap: HashMap<usize, HeapItem>,
-    pub next_heap_addr: usize,
-    executed: usize
+    pub heap: Heap,
+    pub gc: GarbageCollector,
+    pub heap_limit: HeapLimit,
+    // where each instruction came from, if the program was compiled here rather than loaded
+    pub source_map: Option<SourceMap>,
+    // None unless the program is being debugged
+    pub debugger: Option<Debugger>
 }
 
 // Only present in this debugging runtime, not in the real one
@@ -130,21 +313,56 @@ pub enum ExecutionException {
     ArrayIndexOutOfBounds,
     OutOfMemory,
     RaisedException,
-    SyscallException(Syscall)
+    SyscallException(Syscall),
+    // the debugger stopped it before the next instruction, running it again carries on
+    Paused
 }
 
 impl VM {
+    #[allow(unused)]
     pub fn new(program: Vec<Instruction>) -> VM {
         VM {
             stack: vec![],
             program,
             program_counter: 0,
-            heap: HashMap::new(),
-            next_heap_addr: 0,
-            executed: 0
+            heap: Heap::default(),
+            gc: GarbageCollector::default(),
+            heap_limit: HeapLimit::default(),
+            source_map: None,
+            debugger: None
         }
     }
 
+    /// Where in the source the next instruction came from.  After an exception, other than a
+    /// syscall, that's the statement that raised it
+    #[allow(unused)]
+    pub fn current_span(&self) -> Option<Range<usize>> {
+        self.source_map.as_ref()?.span(self.program_counter)
+    }
+
+    /// The function the next instruction is in, or None for the top level code or if there's no
+    /// source map
+    #[allow(unused)]
+    pub fn current_function(&self) -> Option<&DeclaredFunction> {
+        self.source_map.as_ref()?.function(self.program_counter)
+    }
+
+    /// The calls in progress, innermost first.  Each return address on the stack comes right
+    /// after the call that pushed it, so this works without the program's cooperation, though
+    /// only the source map can say which functions and statements they were
+    #[allow(unused)]
+    pub fn stack_trace(&self) -> Vec<Frame> {
+        let calls = self.stack.iter().rev().filter_map(|x| match x {
+            StackItem::ReturnAddr(addr) => addr.checked_sub(1),
+            _ => None
+        });
+        [self.program_counter].into_iter().chain(calls).map(|pc| Frame {
+            pc,
+            function: self.source_map.as_ref().and_then(|x| x.function(pc)).cloned(),
+            span: self.source_map.as_ref().and_then(|x| x.span(pc))
+        }).collect()
+    }
+
     fn pop(&mut self) -> Result<StackItem, ExecutionException> {
         self.stack.pop().ok_or(ExecutionException::EmptyStack)
     }
@@ -172,13 +390,17 @@ impl VM {
     }
 
     pub fn tick_nohandle(&mut self) -> Result<(), ExecutionException> {
+        if self.debugger.is_some() && self.should_pause() {
+            return Err(ExecutionException::Paused)
+        }
         let ins = self.program.get(self.program_counter)
             .ok_or(ExecutionException::IllegalJumpAddress)?.clone();
-        let mut next_addr = self.program_counter + 1;
-        self.executed += 1;
-        if self.executed % 100 == 0 {
-            self.garbage_collect();
+        // no instruction pushes more than one item
+        if self.stack.len() >= MAX_STACK {
+            return Err(ExecutionException::OutOfMemory)
         }
+        let mut next_addr = self.program_counter + 1;
+        self.collect_garbage_step();
 
         match &ins {
             Instruction::ImmediateInt(v) => self.stack.push(StackItem::Int(*v)),
@@ -274,13 +496,14 @@ impl VM {
                 if size > 16384 {
                     return Err(ExecutionException::OutOfMemory)
                 }
-                let id = self.next_heap_addr;
-                self.next_heap_addr += 1;
+                let (cells, objects) = Self::footprint(tpe);
+                let count = size.max(0) as usize;
+                self.reserve(count.saturating_mul(1 + cells).saturating_add(1), count.saturating_mul(objects).saturating_add(1))?;
                 let mut item = vec![];
                 for _ in 0..size {
-                    item.push(self.alloc(&tpe));
+                    item.push(self.alloc(tpe));
                 }
-                self.heap.insert(id, HeapItem { value: item, mark: false, tpe: Tpe::Array(Box::new(t.clone())) });
+                let id = self.push_heap_item(item, Tpe::Array(Box::new(t.clone())));
                 self.stack.push(StackItem::HeapAddr(Tpe::Array(Box::new(t)), id))
             }
             Instruction::GetA => {
@@ -288,7 +511,7 @@ impl VM {
                 let idx: i32 = self.pop()?.try_into()?;
                 match arr {
                     StackItem::HeapAddr(Tpe::Array(_), id) => {
-                        let v = &self.heap[&id];
+                        let v = self.heap_item(id)?;
                         self.stack.push(v.value.get(idx as usize).ok_or(ExecutionException::ArrayIndexOutOfBounds)?.clone())
                     }
                     _ => return Err(ExecutionException::WrongType)
@@ -303,10 +526,9 @@ impl VM {
                 match arr {
                     StackItem::HeapAddr(Tpe::Array(box tpe), id) => {
                         //println!("inner tpe = {tpe:?}");
-                        // there's no way for an illegal heap address to get on the stack
-                        let v = self.heap.get_mut(&id).unwrap();
+                        self.write_barrier(&item);
+                        let v = self.heap.get_mut(id).ok_or(ExecutionException::WrongType)?;
                         if tpe != item.tpe() {
-                            println!("expected {:?}, found {:?}", tpe, item.tpe());
                             return Err(ExecutionException::WrongType)
                         }
                         *(v.value.get_mut(idx as usize).ok_or(ExecutionException::ArrayIndexOutOfBounds)?) = item;
@@ -318,7 +540,7 @@ impl VM {
                 let arr = self.pop()?;
                 match arr {
                     StackItem::HeapAddr(_, id) => {
-                        let v = &self.heap[&id];
+                        let v = self.heap_item(id)?;
                         self.stack.push((v.value.len() as i32).into())
                     }
                     _ => return Err(ExecutionException::WrongType)
@@ -328,24 +550,27 @@ impl VM {
                 let Tpe::Struct(_) = tpe else {
                     return Err(ExecutionException::WrongType)
                 };
-                let item = self.alloc(&tpe);
+                let (cells, objects) = Self::footprint(tpe);
+                self.reserve(cells, objects)?;
+                let item = self.alloc(tpe);
                 self.stack.push(item);
             }
             Instruction::GetS(idx) => {
-                let StackItem::HeapAddr(Tpe::Struct(inner), id) = self.stack.pop().unwrap() else {
-                    println!("");
+                let StackItem::HeapAddr(Tpe::Struct(_), id) = self.pop()? else {
                     return Err(ExecutionException::WrongType)
                 };
-                let item = self.heap[&id].value[*idx].clone();
+                // a field the struct doesn't have means the program was compiled for another type
+                let item = self.heap_item(id)?.value.get(*idx).ok_or(ExecutionException::WrongType)?.clone();
                 self.stack.push(item);
             }
             Instruction::SetS(idx) => {
-                let StackItem::HeapAddr(Tpe::Struct(inner), id) = self.stack.pop().unwrap() else {
+                let StackItem::HeapAddr(Tpe::Struct(_), id) = self.pop()? else {
                     return Err(ExecutionException::WrongType)
                 };
-                let value = self.pop().unwrap();
-                let item = self.heap.get_mut(&id).unwrap();
-                item.value[*idx] = value;
+                let value = self.pop()?;
+                self.write_barrier(&value);
+                let item = self.heap.get_mut(id).ok_or(ExecutionException::WrongType)?;
+                *item.value.get_mut(*idx).ok_or(ExecutionException::WrongType)? = value;
             }
         }
 
@@ -353,11 +578,48 @@ impl VM {
         Ok(())
     }
 
-    fn push_heap_item(&mut self, item: HeapItem) -> usize {
-        let id = self.next_heap_addr;
-        self.next_heap_addr += 1;
-        self.heap.insert(id, item);
-        id
+    // only the VM makes heap addresses, and the garbage collector only frees objects nothing
+    // points to, so every address on the stack or heap should be valid.  This is in case one
+    // isn't, since a panic would take the game down with it
+    fn heap_item(&self, id: usize) -> Result<&HeapItem, ExecutionException> {
+        self.heap.get(id).ok_or(ExecutionException::WrongType)
+    }
+
+    /// The size of the heap, in cells and objects
+    #[allow(unused)]
+    pub fn heap_usage(&self) -> (usize, usize) {
+        (self.gc.cells, self.heap.len())
+    }
+
+    /// Makes sure there's room on the heap for the given number of cells and objects, collecting
+    /// all the garbage if there isn't
+    pub fn reserve(&mut self, cells: usize, objects: usize) -> Result<(), ExecutionException> {
+        let fits = |vm: &VM| vm.gc.cells.saturating_add(cells) <= vm.heap_limit.cells
+            && vm.heap.len().saturating_add(objects) <= vm.heap_limit.objects;
+        if fits(self) {
+            return Ok(())
+        }
+        self.garbage_collect();
+        if fits(self) { Ok(()) } else { Err(ExecutionException::OutOfMemory) }
+    }
+
+    // the cells and objects alloc puts on the heap for a value of a type
+    fn footprint(tpe: &Tpe) -> (usize, usize) {
+        match tpe {
+            Tpe::Int | Tpe::Double => (0, 0),
+            Tpe::Array(_) => (1, 1),
+            Tpe::Struct(tpes) => tpes.iter().map(Self::footprint)
+                .fold((1 + tpes.len(), 1), |(cells, objects), (c, o)| (cells + c, objects + o))
+        }
+    }
+
+    /// Puts a new object on the heap, giving back its address.  It's up to the caller to reserve
+    /// room for it first
+    pub fn push_heap_item(&mut self, value: Vec<StackItem>, tpe: Tpe) -> usize {
+        self.gc.cells += 1 + value.len();
+        // new objects are reachable, so a collection in progress doesn't need to look at them,
+        // and the next one unmarks them along with everything else
+        self.heap.insert(HeapItem { value, mark: self.gc.marked, tpe })
     }
 
     fn alloc(&mut self, tpe: &Tpe) -> StackItem {
@@ -365,7 +627,7 @@ impl VM {
             Tpe::Int => StackItem::Int(0),
             Tpe::Double => StackItem::Double(0.0),
             Tpe::Array(inner) => {
-                let id = self.push_heap_item(HeapItem { value: vec![], mark: false, tpe: *inner.clone() });
+                let id = self.push_heap_item(vec![], *inner.clone());
                 StackItem::HeapAddr(*inner.clone(), id)
             }
             Tpe::Struct(tpes) => {
@@ -373,41 +635,251 @@ impl VM {
                 for t in tpes {
                     value.push(self.alloc(t))
                 }
-                let id = self.push_heap_item(HeapItem { value, mark: false, tpe: tpe.clone() });
+                let id = self.push_heap_item(value, tpe.clone());
                 StackItem::HeapAddr(tpe.clone(), id)
             }
         }
     }
 
-    fn garbage_collect(&mut self) {
-        for obj in self.heap.iter_mut() {
-            obj.1.mark = false;
+    /// Collects all the garbage now, finishing any collection in progress
+    #[allow(unused)]
+    pub fn garbage_collect(&mut self) {
+        if self.gc.gray.is_none() {
+            self.start_collection();
         }
+        self.mark(usize::MAX);
+        self.sweep();
+    }
 
-        let mut items_to_mark = self.stack.clone();
-        while !items_to_mark.is_empty() {
-            let old = items_to_mark.clone();
-            items_to_mark.clear();
-            for item in old {
-                if let StackItem::HeapAddr(_, id) = &item {
-                    let heap_item = self.heap.get_mut(id).unwrap();
-                    heap_item.mark = true;
-                    for it in &heap_item.value {
-                        items_to_mark.push(it.clone());
-                    }
-                }
+    // runs before every instruction
+    fn collect_garbage_step(&mut self) {
+        if self.gc.gray.is_none() {
+            if self.gc.budget == 0 || self.gc.cells < self.gc.threshold {
+                return
             }
+            self.start_collection();
+        }
+        if self.mark(self.gc.budget) {
+            self.sweep();
         }
+    }
+
+    fn start_collection(&mut self) {
+        self.gc.marked = !self.gc.marked;
+        self.gc.gray = Some(self.stack_roots());
+    }
+
+    fn stack_roots(&self) -> Vec<usize> {
+        self.stack.iter().filter_map(|x| match x {
+            StackItem::HeapAddr(_, id) => Some(*id),
+            _ => None
+        }).collect()
+    }
 
-        let mut to_free = vec![];
-        for obj in &self.heap {
-            if obj.1.mark == false {
-                to_free.push(*obj.0);
+    /// Marks objects until roughly budget cells have been looked at.  Returns true once
+    /// everything reachable is marked
+    fn mark(&mut self, budget: usize) -> bool {
+        let mut gray = self.gc.gray.take().unwrap_or_default();
+        let mut work = 0;
+        let done = loop {
+            if work >= budget {
+                break false
+            }
+            let Some(id) = gray.pop() else {
+                // the stack isn't watched while marking, so anything it picked up since the
+                // start is only found now.  Marking carries on until it has nothing new, and
+                // looking through the stack counts against the budget like any other cells
+                gray = self.stack_roots();
+                work += self.stack.len();
+                gray.retain(|x| self.heap.get(*x).is_some_and(|x| x.mark != self.gc.marked));
+                if gray.is_empty() {
+                    break true
+                }
+                continue
+            };
+            let Some(item) = self.heap.get_mut(id) else { continue };
+            work += 1;
+            if item.mark == self.gc.marked {
+                continue
             }
+            item.mark = self.gc.marked;
+            work += item.value.len();
+            gray.extend(item.value.iter().filter_map(|x| match x {
+                StackItem::HeapAddr(_, id) => Some(*id),
+                _ => None
+            }));
+        };
+        self.gc.stats.marked += work;
+        self.gc.stats.longest_step = self.gc.stats.longest_step.max(work);
+        self.gc.gray = Some(gray);
+        done
+    }
+
+    /// Stores to the heap during a collection go through here, so that an object put inside
+    /// one that's already been looked at still gets marked
+    fn write_barrier(&mut self, item: &StackItem) {
+        if let (Some(gray), StackItem::HeapAddr(_, id)) = (&mut self.gc.gray, item) {
+            gray.push(*id);
         }
+    }
 
-        for item in to_free {
-            self.heap.remove(&item);
+    fn sweep(&mut self) {
+        let before = self.heap.len();
+        let marked = self.gc.marked;
+        self.heap.retain(|x| x.mark == marked);
+        self.gc.cells = self.heap.iter().map(|(_, x)| 1 + x.value.len()).sum();
+        self.gc.threshold = (self.gc.cells * 2).max(MIN_GC_THRESHOLD);
+        self.gc.gray = None;
+        self.gc.stats.collections += 1;
+        self.gc.stats.freed += before - self.heap.len();
+    }
+}
+
+// the debugger
+impl VM {
+    /// Pauses at every statement that starts on a line, counting from 1.  Returns false if there
+    /// aren't any, or there's no source map to find them with
+    #[allow(unused)]
+    pub fn set_breakpoint(&mut self, line: usize) -> bool {
+        let statements = self.statements_on(line);
+        if statements.is_empty() {
+            return false
+        }
+        self.debugger.get_or_insert_default().breakpoints.extend(statements);
+        true
+    }
+
+    /// Removes the breakpoints from a line.  Returns false if there weren't any
+    #[allow(unused)]
+    pub fn clear_breakpoint(&mut self, line: usize) -> bool {
+        let statements = self.statements_on(line);
+        let Some(debugger) = &mut self.debugger else { return false };
+        let before = debugger.breakpoints.len();
+        debugger.breakpoints.retain(|x| !statements.contains(x));
+        debugger.breakpoints.len() != before
+    }
+
+    /// The lines with breakpoints on them, in order
+    #[allow(unused)]
+    pub fn breakpoints(&self) -> Vec<usize> {
+        let (Some(map), Some(debugger)) = (&self.source_map, &self.debugger) else { return vec![] };
+        let mut lines = debugger.breakpoints.iter().filter_map(|x| map.line(*x)).collect::<Vec<_>>();
+        lines.sort();
+        lines.dedup();
+        lines
+    }
+
+    fn statements_on(&self, line: usize) -> Vec<usize> {
+        let Some(map) = &self.source_map else { return vec![] };
+        map.statements.iter().copied().filter(|x| map.line(*x) == Some(line)).collect()
+    }
+
+    /// Makes the program pause again after running the given step.  It carries on across syscalls
+    /// until then, and a breakpoint reached first cancels it
+    #[allow(unused)]
+    pub fn step(&mut self, step: Step) {
+        let depth = self.call_depth();
+        self.debugger.get_or_insert_default().step = Some((step, depth));
+    }
+
+    /// How many function calls are in progress
+    pub fn call_depth(&self) -> usize {
+        self.stack.iter().filter(|x| matches!(x, StackItem::ReturnAddr(_))).count()
+    }
+
+    /// The line, counting from 1, of the statement the next instruction is part of
+    #[allow(unused)]
+    pub fn current_line(&self) -> Option<usize> {
+        self.source_map.as_ref()?.line(self.program_counter)
+    }
+
+    fn should_pause(&mut self) -> bool {
+        let pc = self.program_counter;
+        let Some(map) = &self.source_map else { return false };
+        if map.statements.binary_search(&pc).is_err() {
+            return false
+        }
+        let depth = self.call_depth();
+        let Some(debugger) = &mut self.debugger else { return false };
+        if debugger.paused_at.take() == Some(pc) {
+            return false
+        }
+        let stepped = match debugger.step {
+            Some((Step::Into, _)) => true,
+            Some((Step::Over, start)) => depth <= start,
+            Some((Step::Out, start)) => depth < start,
+            None => false
+        };
+        if stepped || debugger.breakpoints.contains(&pc) {
+            debugger.step = None;
+            debugger.paused_at = Some(pc);
+            return true
+        }
+        false
+    }
+
+    /// The variables in scope in a frame of the stack trace, 0 being the innermost, with their
+    /// values.  None if there's no such frame or no source map
+    #[allow(unused)]
+    pub fn variables(&self, frame: usize) -> Option<Vec<Variable>> {
+        let map = self.source_map.as_ref()?;
+        let trace = self.stack_trace();
+        let pc = trace.get(frame)?.pc;
+        // each function's frame ends with the return address its call pushed, the innermost
+        // function's being the highest on the stack.  The top level's starts at the bottom
+        let return_addrs = self.stack.iter().enumerate().rev()
+            .filter(|(_, x)| matches!(x, StackItem::ReturnAddr(_)))
+            .map(|(i, _)| i);
+        let base = match (map.function(pc), return_addrs.clone().nth(frame)) {
+            (Some(function), Some(addr)) => addr.checked_sub(function.args.len() + function.return_type.iter().count())?,
+            (None, None) if frame == trace.len() - 1 => 0,
+            _ => return None
+        };
+        // the standard library's variables are none of the user's business, and its built in
+        // functions aren't mapped at all
+        let locals = if map.span(pc).is_some() { map.variables(pc) } else { &[] };
+        Some(locals.iter().filter_map(|local| Some(Variable {
+            name: local.name.clone(),
+            tpe: local.tpe.clone(),
+            value: self.format_value(self.stack.get(base + local.slot)?, &local.tpe, 0)
+        })).collect())
+    }
+
+    /// Writes a value out the way it would be written in a spell, as far as that's possible
+    fn format_value(&self, item: &StackItem, tpe: &CompType, depth: usize) -> String {
+        // the heap can have cycles, and long arrays aren't readable anyway
+        const MAX_DEPTH: usize = 3;
+        const MAX_ELEMENTS: usize = 20;
+        let values = |id: &usize| self.heap.get(*id).map_or(&[][..], |x| &x.value[..]);
+        match (item, tpe) {
+            (StackItem::Int(v), CompType::Bool) => (*v != 0).to_string(),
+            (StackItem::Int(v), CompType::Char) => format!("{:?}", char::from_u32(*v as u32).unwrap_or(char::REPLACEMENT_CHARACTER)),
+            (StackItem::Int(v), _) => v.to_string(),
+            (StackItem::Double(v), _) => format!("{v:?}"),
+            (StackItem::HeapAddr(_, id), CompType::String) => {
+                let chars = values(id).iter().map(|x| match x {
+                    StackItem::Int(c) => char::from_u32(*c as u32).unwrap_or(char::REPLACEMENT_CHARACTER),
+                    _ => char::REPLACEMENT_CHARACTER
+                }).collect::<String>();
+                format!("{chars:?}")
+            }
+            (StackItem::HeapAddr(..), CompType::Array(_)) if depth >= MAX_DEPTH => "[...]".to_owned(),
+            (StackItem::HeapAddr(_, id), CompType::Array(inner)) => {
+                let values = values(id);
+                let mut items = values.iter().take(MAX_ELEMENTS).map(|x| self.format_value(x, inner, depth + 1)).collect::<Vec<_>>();
+                if values.len() > MAX_ELEMENTS {
+                    items.push(format!("... {} more", values.len() - MAX_ELEMENTS));
+                }
+                format!("[{}]", items.join(", "))
+            }
+            (StackItem::HeapAddr(..), CompType::Struct(s)) if depth >= MAX_DEPTH => format!("{} {{ ... }}", s.name),
+            (StackItem::HeapAddr(_, id), CompType::Struct(s)) => {
+                let fields = s.fields.iter().zip(values(id))
+                    .map(|((name, tpe), x)| format!("{name}: {}", self.format_value(x, tpe, depth + 1)))
+                    .collect::<Vec<_>>();
+                format!("{} {{ {} }}", s.name, fields.join(", "))
+            }
+            (item, _) => format!("{item:?}")
         }
     }
 }
@@ -760,8 +1232,136 @@ mod tests {
         ImmediateInt(0), Call(3), Syscall(Syscall::Exception), ImmediateInt(1), Set(2), Return => Int(1) => RaisedException;
     }
 
+    #[test]
+    fn test_stack_trace() {
+        let mut vm = VM::new(vec![Call(2), Syscall(Syscall::Halt), ImmediateInt(7), Call(5), Return, Copy(5)]);
+        while vm.tick().is_ok() {}
+        assert_eq!(vm.stack_trace().iter().map(|x| x.pc).collect::<Vec<_>>(), vec![5, 3, 0]);
+        assert!(vm.stack_trace().iter().all(|x| x.function.is_none() && x.span.is_none()));
+    }
+
+    #[test]
+    fn test_incremental_gc() {
+        let tpe = Tpe::Struct(vec![Tpe::Struct(vec![])]);
+        let addr = |id| HeapAddr(tpe.clone(), id);
+        let mut vm = VM::new(vec![]);
+        let x = vm.push_heap_item(vec![], tpe.clone());
+        let y = vm.push_heap_item(vec![], tpe.clone());
+        let garbage = vm.push_heap_item(vec![addr(x)], tpe.clone());
+        let b = vm.push_heap_item(vec![addr(x), addr(y)], tpe.clone());
+        let a = vm.push_heap_item(vec![Int(0)], tpe.clone());
+        vm.stack = vec![addr(b), addr(a)];
+        vm.gc.threshold = 0;
+        vm.gc.budget = 1;
+
+        // a is marked first, then x is moved into it and y onto the stack before b is looked at
+        vm.collect_garbage_step();
+        assert!(vm.gc.gray.is_some());
+        vm.write_barrier(&addr(x));
+        vm.heap.get_mut(a).unwrap().value[0] = addr(x);
+        vm.stack.push(addr(y));
+        vm.heap.get_mut(b).unwrap().value = vec![];
+        while vm.gc.gray.is_some() {
+            vm.collect_garbage_step();
+        }
+        // the longest step is the one that looks through the stack again
+        assert_eq!(vm.gc.stats.longest_step, 3);
+        let live = vm.heap.iter().map(|x| x.0).collect::<Vec<_>>();
+        assert_eq!(live, vec![x, y, b, a]);
+        assert_eq!(vm.gc.stats.freed, 1);
+        assert!(vm.heap.get(garbage).is_none());
+
+        vm.stack.clear();
+        vm.garbage_collect();
+        assert!(vm.heap.is_empty());
+        assert_eq!(vm.gc.stats.collections, 2);
+
+        vm.push_heap_item(vec![], tpe.clone());
+        vm.gc.threshold = 0;
+        vm.gc.budget = 0;
+        vm.collect_garbage_step();
+        assert!(vm.gc.gray.is_none());
+        assert_eq!(vm.heap.len(), 1);
+    }
+
+    #[test]
+    fn test_heap_reuses_addresses() {
+        let item = |n| HeapItem { value: vec![Int(n)], mark: false, tpe: Tpe::Int };
+        let mut heap = Heap::default();
+        for n in 0..4 {
+            assert_eq!(heap.insert(item(n)), n as usize);
+        }
+        heap.retain(|x| x.value[0] == Int(0) || x.value[0] == Int(3));
+        assert_eq!(heap.len(), 2);
+        assert!(heap.get(1).is_none());
+        assert_eq!(heap.insert(item(4)), 1);
+        assert_eq!(heap.insert(item(5)), 2);
+        assert_eq!(heap.insert(item(6)), 4);
+        assert_eq!(heap.iter().map(|(id, x)| (id, x.value[0].clone())).collect::<Vec<_>>(), vec![(0, Int(0)), (1, Int(4)), (2, Int(5)), (3, Int(3)), (4, Int(6))]);
+
+        // a program that keeps allocating garbage stays within a few collections' worth
+        let mut vm = VM::new(vec![ImmediateInt(8), AllocA(Tpe::Struct(vec![Tpe::Int])), Pop(1), Jmp(0)]);
+        for _ in 0..100000 {
+            vm.tick().unwrap();
+        }
+        assert!(vm.heap.iter().all(|(id, _)| id < 2 * MIN_GC_THRESHOLD));
+    }
+
+    #[test]
+    fn test_heap_limit() {
+        let run = |program: Vec<Instruction>| {
+            let mut vm = VM::new(program);
+            vm.heap_limit = HeapLimit { cells: 100, objects: 10 };
+            for _ in 0..1000 {
+                if let Err(e) = vm.tick() {
+                    return (Some(e), vm);
+                }
+            }
+            (None, vm)
+        };
+
+        // garbage is collected to make room
+        let (result, vm) = run(vec![ImmediateInt(10), AllocA(Tpe::Int), Pop(1), Jmp(0)]);
+        assert_eq!(result, None);
+        assert!(vm.heap_usage().0 <= 100);
+
+        let (result, vm) = run(vec![ImmediateInt(10), AllocA(Tpe::Int), Jmp(0)]);
+        assert_eq!(result, Some(OutOfMemory));
+        assert_eq!(vm.stack.len(), 9);
+        assert_eq!(vm.heap_usage(), (99, 9));
+
+        // each element is an object too
+        let (result, vm) = run(vec![ImmediateInt(4), AllocA(Tpe::Struct(vec![Tpe::Int])), Jmp(0)]);
+        assert_eq!(result, Some(OutOfMemory));
+        assert_eq!(vm.heap_usage().1, 10);
+        let (result, _) = run(vec![AllocS(Tpe::Struct(vec![Tpe::Struct(vec![]), Tpe::Array(Box::new(Tpe::Int))])), Jmp(0)]);
+        assert_eq!(result, Some(OutOfMemory));
+    }
+
+    #[test]
+    fn test_malformed_programs() {
+        let run = |program: Vec<Instruction>| {
+            let mut vm = VM::new(program);
+            loop {
+                if let Err(e) = vm.tick() {
+                    return e;
+                }
+            }
+        };
+        let pair = || AllocS(Tpe::Struct(vec![Tpe::Int, Tpe::Int]));
+        assert_eq!(run(vec![GetS(0)]), EmptyStack);
+        assert_eq!(run(vec![pair(), SetS(0)]), EmptyStack);
+        assert_eq!(run(vec![pair(), GetS(2)]), WrongType);
+        assert_eq!(run(vec![ImmediateInt(1), pair(), SetS(usize::MAX)]), WrongType);
+        assert_eq!(run(vec![ImmediateInt(0), GetS(0)]), WrongType);
+        assert_eq!(run(vec![Copy(usize::MAX)]), EmptyStack);
+        assert_eq!(run(vec![ImmediateInt(1), Set(usize::MAX)]), EmptyStack);
+        // runaway recursion
+        assert_eq!(run(vec![Call(0)]), OutOfMemory);
+    }
+
     test! { test_array:
-        ImmediateInt(5), AllocA(Tpe::Int) => HeapAddr(Tpe::Int, 0);
+        ImmediateInt(5), AllocA(Tpe::Int) => HeapAddr(Tpe::Array(Box::new(Tpe::Int)), 0);
         // TODO: test actual operations
     }
 }
@@ -0,0 +1,390 @@
+use std::{collections::{HashMap, HashSet, VecDeque}, fmt::Display, io::Write};
+
+use serde_json::Value;
+
+use crate::stack_machine::{ExecutionException, StackItem, Syscall, Tpe, VM};
+
+// the axial offsets of a hex's six neighbours, in the order the game lists them
+pub const NEIGHBOR_DIRS: [(i32, i32); 6] = [(1, 0), (1, -1), (0, -1), (-1, 0), (-1, 1), (0, 1)];
+// what get_neighbors gives for a neighbour that isn't on the map
+const NO_NEIGHBOR: i32 = 1234;
+
+pub type Hex = (i32, i32);
+
+/// The game, as far as a running spell can tell.  `run` turns each syscall the spell makes into
+/// a call on this, and does the stack shuffling around it
+pub trait World {
+    fn mana(&mut self) -> i32;
+    fn environment(&mut self) -> i32;
+    /// The new effect's ID, or -1 if it couldn't be spawned
+    fn spawn_effect(&mut self, tpe: i32) -> i32;
+    fn move_effect(&mut self, id: i32, q: i32, r: i32);
+    fn player_location(&mut self) -> (i32, i32);
+    /// The next click, or None if the spell would wait forever
+    fn click_location(&mut self) -> Option<(i32, i32)>;
+    /// The neighbours of a hex that are on the map, with the cost of moving to each
+    fn neighbors(&mut self, q: i32, r: i32) -> Vec<(i32, i32, i32)>;
+    fn print(&mut self, c: char);
+    /// The spell has finished its turn
+    fn sleep(&mut self) {}
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RunError {
+    // the VM failed, at the given instruction
+    Exception(ExecutionException, usize),
+    // the spell raised an exception itself
+    Raised,
+    OutOfSteps(usize),
+    NoClick,
+    // a syscall found the wrong thing on the stack
+    BadArgument(Syscall),
+    // the debugger paused it, running it again carries on
+    Paused
+}
+
+impl Display for RunError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RunError::Exception(e, pc) => write!(f, "{e:?} at instruction {pc}"),
+            RunError::Raised => write!(f, "the spell raised an exception"),
+            RunError::OutOfSteps(steps) => write!(f, "stopped after {steps} instructions"),
+            RunError::NoClick => write!(f, "the spell waited for a click, but there are no more"),
+            RunError::BadArgument(syscall) => write!(f, "wrong arguments on the stack for {syscall:?}"),
+            RunError::Paused => write!(f, "paused by the debugger")
+        }
+    }
+}
+
+/// Runs a spell against the world until it halts, or for at most `max_steps` instructions
+pub fn run(vm: &mut VM, world: &mut impl World, max_steps: Option<usize>) -> Result<(), RunError> {
+    let mut steps = 0;
+    loop {
+        if max_steps.is_some_and(|x| steps >= x) {
+            return Err(RunError::OutOfSteps(steps));
+        }
+        steps += 1;
+        let syscall = match vm.tick_nohandle() {
+            Ok(()) => continue,
+            Err(ExecutionException::SyscallException(syscall)) => syscall,
+            Err(ExecutionException::Paused) => return Err(RunError::Paused),
+            Err(e) => return Err(RunError::Exception(e, vm.program_counter))
+        };
+        let bad_argument = |_| RunError::BadArgument(syscall);
+        match syscall {
+            Syscall::Nop => {}
+            Syscall::Halt => return Ok(()),
+            Syscall::Exception => return Err(RunError::Raised),
+            Syscall::Sleep => world.sleep(),
+            Syscall::GetMana => vm.stack.push(StackItem::Int(world.mana())),
+            Syscall::EnvironmentID => vm.stack.push(StackItem::Int(world.environment())),
+            Syscall::PrintChar => {
+                let c = pop_int(vm).map_err(bad_argument)?;
+                world.print(char::from_u32(c as u32).unwrap_or(char::REPLACEMENT_CHARACTER));
+            }
+            Syscall::SpawnEffect => {
+                let tpe = pop_int(vm).map_err(bad_argument)?;
+                vm.stack.push(StackItem::Int(world.spawn_effect(tpe)));
+            }
+            Syscall::MoveEffect => {
+                let q = pop_int(vm).map_err(bad_argument)?;
+                let r = pop_int(vm).map_err(bad_argument)?;
+                let id = pop_int(vm).map_err(bad_argument)?;
+                world.move_effect(id, q, r);
+            }
+            Syscall::PlayerLocation => {
+                let (q, r) = world.player_location();
+                push_int_array(vm, vec![q, r]).map_err(|e| RunError::Exception(e, vm.program_counter))?;
+            }
+            Syscall::ClickLocation => {
+                let (q, r) = world.click_location().ok_or(RunError::NoClick)?;
+                push_int_array(vm, vec![q, r]).map_err(|e| RunError::Exception(e, vm.program_counter))?;
+            }
+            Syscall::GetNeighbors => {
+                let q = pop_int(vm).map_err(bad_argument)?;
+                let r = pop_int(vm).map_err(bad_argument)?;
+                let neighbors = world.neighbors(q, r);
+                // six slots of q, r and cost, in the order of the directions, as the game lays
+                // them out
+                let mut value = vec![NO_NEIGHBOR; NEIGHBOR_DIRS.len() * 3];
+                for (nq, nr, cost) in neighbors {
+                    if let Some(i) = NEIGHBOR_DIRS.iter().position(|&(dq, dr)| (q.wrapping_add(dq), r.wrapping_add(dr)) == (nq, nr)) {
+                        value[i * 3..i * 3 + 3].copy_from_slice(&[nq, nr, cost]);
+                    }
+                }
+                push_int_array(vm, value).map_err(|e| RunError::Exception(e, vm.program_counter))?;
+            }
+        }
+    }
+}
+
+fn pop_int(vm: &mut VM) -> Result<i32, ExecutionException> {
+    vm.stack.pop().ok_or(ExecutionException::EmptyStack)?.try_into()
+}
+
+fn push_int_array(vm: &mut VM, values: Vec<i32>) -> Result<(), ExecutionException> {
+    vm.reserve(1 + values.len(), 1)?;
+    let tpe = Tpe::Array(Box::new(Tpe::Int));
+    let id = vm.push_heap_item(values.into_iter().map(StackItem::Int).collect(), tpe.clone());
+    vm.stack.push(StackItem::HeapAddr(tpe, id));
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Effect {
+    pub tpe: i32,
+    // where it was spawned, then everywhere it was moved to
+    pub path: Vec<(i32, i32)>
+}
+
+/// A world for running spells headlessly, read from a JSON config such as
+///
+/// ```json
+/// {
+///     "mana": 100,
+///     "environment": 0,
+///     "player": [0, 0],
+///     "clicks": [[2, -1], [0, 3]],
+///     "map": { "radius": 3 },
+///     "costs": [{ "from": [0, 0], "to": [1, 0], "cost": 5 }],
+///     "effects": [10, 25]
+/// }
+/// ```
+///
+/// Every field is optional.  The map is either every hex within `radius` of 0,0 or a list of
+/// `"tiles"`, moving between neighbours costs 1 unless listed in `costs` (either way round), and
+/// `effects` is the mana each effect type costs, if it's missing any type can be spawned for free
+#[derive(Debug, Clone, PartialEq)]
+pub struct MockWorld {
+    pub mana: i32,
+    pub environment: i32,
+    pub player: (i32, i32),
+    pub clicks: VecDeque<(i32, i32)>,
+    pub tiles: HashSet<(i32, i32)>,
+    pub costs: HashMap<(Hex, Hex), i32>,
+    pub effect_costs: Option<Vec<i32>>,
+    // indexed by ID - 1
+    pub effects: Vec<Effect>,
+    pub output: String,
+    pub turns: usize,
+    // also write what happens to stdout and stderr as it happens
+    pub echo: bool
+}
+
+impl Default for MockWorld {
+    fn default() -> Self {
+        MockWorld {
+            mana: 1000,
+            environment: 0,
+            player: (0, 0),
+            clicks: VecDeque::new(),
+            tiles: hexagon(5),
+            costs: HashMap::new(),
+            effect_costs: None,
+            effects: vec![],
+            output: String::new(),
+            turns: 0,
+            echo: false
+        }
+    }
+}
+
+/// Every hex within `radius` steps of 0,0
+pub fn hexagon(radius: i32) -> HashSet<(i32, i32)> {
+    let mut out = HashSet::new();
+    for q in -radius..=radius {
+        for r in (-radius).max(-q - radius)..=radius.min(-q + radius) {
+            out.insert((q, r));
+        }
+    }
+    out
+}
+
+impl MockWorld {
+    pub fn from_config(config: &str) -> Result<MockWorld, String> {
+        let config: Value = serde_json::from_str(config).map_err(|e| e.to_string())?;
+        let config = config.as_object().ok_or("the config should be an object")?;
+        let mut world = MockWorld::default();
+        for (key, value) in config {
+            match key.as_str() {
+                "mana" => world.mana = int(key, value)?,
+                "environment" => world.environment = int(key, value)?,
+                "player" => world.player = location(key, value)?,
+                "clicks" => world.clicks = list(key, value)?.iter().map(|x| location(key, x)).collect::<Result<_, _>>()?,
+                "map" => {
+                    world.tiles = match (value.get("radius"), value.get("tiles")) {
+                        (Some(radius), None) => hexagon(int("radius", radius)?),
+                        (None, Some(tiles)) => list("tiles", tiles)?.iter().map(|x| location("tiles", x)).collect::<Result<_, _>>()?,
+                        _ => return Err("map should have either a radius or a list of tiles".to_owned())
+                    };
+                }
+                "costs" => {
+                    for cost in list(key, value)? {
+                        let from = location("from", &cost["from"])?;
+                        let to = location("to", &cost["to"])?;
+                        world.costs.insert((from, to), int(key, &cost["cost"])?);
+                    }
+                }
+                "effects" => world.effect_costs = Some(list(key, value)?.iter().map(|x| int(key, x)).collect::<Result<_, _>>()?),
+                _ => return Err(format!("unknown setting `{key}`"))
+            }
+        }
+        Ok(world)
+    }
+
+    fn cost(&self, from: (i32, i32), to: (i32, i32)) -> i32 {
+        self.costs.get(&(from, to)).or(self.costs.get(&(to, from))).copied().unwrap_or(1)
+    }
+}
+
+fn int(key: &str, value: &Value) -> Result<i32, String> {
+    value.as_i64().and_then(|x| i32::try_from(x).ok()).ok_or(format!("`{key}` should be an int, not {value}"))
+}
+
+fn list<'a>(key: &str, value: &'a Value) -> Result<&'a Vec<Value>, String> {
+    value.as_array().ok_or(format!("`{key}` should be a list, not {value}"))
+}
+
+fn location(key: &str, value: &Value) -> Result<(i32, i32), String> {
+    match list(key, value)?.as_slice() {
+        [q, r] => Ok((int(key, q)?, int(key, r)?)),
+        _ => Err(format!("`{key}` should be a location like [1, 2], not {value}"))
+    }
+}
+
+impl World for MockWorld {
+    fn mana(&mut self) -> i32 {
+        self.mana
+    }
+
+    fn environment(&mut self) -> i32 {
+        self.environment
+    }
+
+    fn spawn_effect(&mut self, tpe: i32) -> i32 {
+        let cost = match &self.effect_costs {
+            Some(costs) => match usize::try_from(tpe).ok().and_then(|x| costs.get(x)) {
+                Some(cost) => *cost,
+                None => return -1
+            },
+            None => 0
+        };
+        if cost > self.mana {
+            return -1;
+        }
+        self.mana -= cost;
+        self.effects.push(Effect { tpe, path: vec![self.player] });
+        let id = self.effects.len() as i32;
+        if self.echo {
+            eprintln!("spawned effect {id} of type {tpe} at {},{}", self.player.0, self.player.1);
+        }
+        id
+    }
+
+    fn move_effect(&mut self, id: i32, q: i32, r: i32) {
+        // the game ignores IDs it doesn't know about
+        let Some(effect) = id.checked_sub(1).and_then(|x| usize::try_from(x).ok()).and_then(|x| self.effects.get_mut(x)) else { return };
+        effect.path.push((q, r));
+        if self.echo {
+            eprintln!("moved effect {id} to {q},{r}");
+        }
+    }
+
+    fn player_location(&mut self) -> (i32, i32) {
+        self.player
+    }
+
+    fn click_location(&mut self) -> Option<(i32, i32)> {
+        self.clicks.pop_front()
+    }
+
+    fn neighbors(&mut self, q: i32, r: i32) -> Vec<(i32, i32, i32)> {
+        if !self.tiles.contains(&(q, r)) {
+            return vec![];
+        }
+        NEIGHBOR_DIRS.iter()
+            .map(|(dq, dr)| (q.wrapping_add(*dq), r.wrapping_add(*dr)))
+            .filter(|x| self.tiles.contains(x))
+            .map(|x| (x.0, x.1, self.cost((q, r), x)))
+            .collect()
+    }
+
+    fn print(&mut self, c: char) {
+        self.output.push(c);
+        if self.echo {
+            print!("{c}");
+            // so the output comes out as it happens, it doesn't matter if it can't be written
+            let _ = std::io::stdout().flush();
+        }
+    }
+
+    fn sleep(&mut self) {
+        self.turns += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compiler::Compiler, parser};
+
+    fn run_spell(src: &str, world: &mut MockWorld) -> Result<(), RunError> {
+        let mut compiler = Compiler::new();
+        compiler.compile_program(&parser::parse_program(src).unwrap()).unwrap();
+        run(&mut VM::new(compiler.program), world, Some(1_000_000))
+    }
+
+    #[test]
+    fn test_config() {
+        let world = MockWorld::from_config(r#"{
+            "mana": 50,
+            "player": [1, -1],
+            "clicks": [[2, 0], [0, 0]],
+            "map": { "tiles": [[0, 0], [1, 0], [1, -1]] },
+            "costs": [{ "from": [0, 0], "to": [1, 0], "cost": 7 }],
+            "effects": [10]
+        }"#).unwrap();
+        assert_eq!(world.mana, 50);
+        assert_eq!(world.player, (1, -1));
+        assert_eq!(world.clicks, [(2, 0), (0, 0)]);
+        assert_eq!(world.tiles.len(), 3);
+        assert_eq!(world.effect_costs, Some(vec![10]));
+        assert_eq!(world.cost((1, 0), (0, 0)), 7);
+
+        assert_eq!(MockWorld::from_config("{}").unwrap(), MockWorld::default());
+        assert_eq!(hexagon(1).len(), 7);
+        assert_eq!(hexagon(5).len(), 91);
+        assert!(MockWorld::from_config(r#"{ "player": [1] }"#).unwrap_err().contains("player"));
+        assert!(MockWorld::from_config(r#"{ "map": { } }"#).is_err());
+        assert!(MockWorld::from_config(r#"{ "manna": 1 }"#).unwrap_err().contains("manna"));
+    }
+
+    #[test]
+    fn test_effects() {
+        let mut world = MockWorld::from_config(r#"{ "mana": 30, "player": [1, 1], "clicks": [[2, -1]], "effects": [10, 25] }"#).unwrap();
+        run_spell("var fire = spawn_effect(0)\nvar click = get_click()\nmove_effect(click[0], click[1], fire)\nprintln(spawn_effect(1))\nprintln(spawn_effect(5))\nprintln(spawn_effect(0))", &mut world).unwrap();
+        assert_eq!(world.output, "-1\n-1\n2\n");
+        assert_eq!(world.mana, 10);
+        assert_eq!(world.effects, vec![
+            Effect { tpe: 0, path: vec![(1, 1), (2, -1)] },
+            Effect { tpe: 0, path: vec![(1, 1)] }
+        ]);
+        assert_eq!(run_spell("get_click()", &mut world), Err(RunError::NoClick));
+        assert_eq!(run_spell("while true { }", &mut world), Err(RunError::OutOfSteps(1_000_000)));
+    }
+
+    #[test]
+    fn test_neighbors() {
+        let mut world = MockWorld::from_config(r#"{
+            "map": { "radius": 1 },
+            "costs": [{ "from": [1, 0], "to": [0, 0], "cost": 4 }]
+        }"#).unwrap();
+        run_spell("for n in neighbors(0, 0) { print(n[0]); print(','); print(n[1]); print(','); println(n[2]) }", &mut world).unwrap();
+        assert_eq!(world.output, "1,0,4\n1,-1,1\n0,-1,1\n-1,0,1\n-1,1,1\n0,1,1\n");
+
+        // a corner of the map only has three neighbours, and there are none off the map
+        world.output.clear();
+        run_spell("println(neighbors(1, 0).size)\nprintln(neighbors(5, 5).size)", &mut world).unwrap();
+        assert_eq!(world.output, "3\n0\n");
+    }
+}
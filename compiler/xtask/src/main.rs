@@ -72,12 +72,12 @@ fn dist_all() -> Result<(), DynError> {
 }
 
 fn clean() -> Result<(), DynError> {
-    let _ = fs::remove_dir_all(&dist_dir());
-    fs::create_dir_all(&dist_dir())?;
-    fs::create_dir_all(&dist_dir().join("linux"))?;
-    fs::create_dir_all(&dist_dir().join("windows"))?;
-    fs::create_dir_all(&dist_dir().join("web"))?;
-    fs::create_dir_all(&dist_dir().join("macos"))?;
+    let _ = fs::remove_dir_all(dist_dir());
+    fs::create_dir_all(dist_dir())?;
+    fs::create_dir_all(dist_dir().join("linux"))?;
+    fs::create_dir_all(dist_dir().join("windows"))?;
+    fs::create_dir_all(dist_dir().join("web"))?;
+    fs::create_dir_all(dist_dir().join("macos"))?;
     Ok(())
 }
 